@@ -0,0 +1,77 @@
+//! Criterion benchmarks for the image watermark pipeline.
+//!
+//! Run with `cargo bench`; reports and comparisons with the previous run are written to
+//! `target/criterion`.
+
+#[allow(dead_code)]
+#[path = "../src/main.rs"]
+mod worker;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::{imageops, DynamicImage, GenericImageView, Rgb, RgbImage, RgbaImage};
+use std::hint::black_box;
+
+/// Representative 800px preview with enough detail that compositing isn't trivial
+fn preview_image() -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(800, 533, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x ^ y) % 256) as u8])
+    }))
+}
+
+/// Full-resolution original as it comes out of a camera
+fn original_image() -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(4000, 2667, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    }))
+}
+
+fn watermark_image(c: &mut Criterion) {
+    let branding = worker::Branding::from_env();
+    let preview = preview_image();
+
+    c.bench_function("watermark_image/800px", |b| {
+        b.iter(|| worker::watermark_image(black_box(preview.clone()), &branding))
+    });
+}
+
+fn draw_logo(c: &mut Criterion) {
+    let logo = image::open(worker::BUNDLED_LOGO_PATH)
+        .expect("bench runs from the crate root")
+        .resize(32, 32, imageops::FilterType::Lanczos3)
+        .to_rgba8();
+    let canvas: RgbaImage = preview_image().to_rgba8();
+
+    c.bench_function("draw_logo/32px_x10", |b| {
+        b.iter(|| {
+            let mut canvas = canvas.clone();
+            for i in 0..10 {
+                worker::draw_logo(&mut canvas, black_box(&logo), 40 + i * 60, 200, 0.42);
+            }
+            canvas
+        })
+    });
+}
+
+fn resize(c: &mut Criterion) {
+    let original = original_image();
+    let (width, height) = original.dimensions();
+    let (new_width, new_height) = (800, 800 * height / width);
+
+    let mut group = c.benchmark_group("resize");
+    // Each iteration resamples a 4000px (10MP) original to 800px, so the default 100
+    // samples take minutes
+    group.sample_size(10);
+    for (name, filter) in [
+        ("Nearest", imageops::FilterType::Nearest),
+        ("Triangle", imageops::FilterType::Triangle),
+        ("Lanczos3", imageops::FilterType::Lanczos3),
+    ] {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &filter, |b, &filter| {
+            b.iter(|| black_box(&original).resize_exact(new_width, new_height, filter))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, watermark_image, draw_logo, resize);
+criterion_main!(benches);
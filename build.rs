@@ -0,0 +1,21 @@
+use std::process::Command;
+use std::time::SystemTime;
+
+// Embeds the git commit and build time for the `/version` endpoint
+fn main() {
+    let commit = std::env::var("GIT_COMMIT").ok().filter(|commit| !commit.is_empty()).or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    let built_at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    println!("cargo:rustc-env=REFLEXU_GIT_COMMIT={}", commit.unwrap_or_else(|| "unknown".to_string()));
+    println!("cargo:rustc-env=REFLEXU_BUILD_TIMESTAMP={}", built_at);
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -1,532 +1,4437 @@
 use aws_sdk_s3::{Client, config::Region, types::ObjectCannedAcl};
 use aws_sdk_s3::config::Credentials;
-use std::{env, path::PathBuf, process::Command, io::Cursor, time::Instant};
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::get_object::GetObjectOutput;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Object};
+use std::{borrow::Cow, env, path::{Path, PathBuf}, io::Cursor, time::Instant};
+use clap::{Args, Parser, Subcommand};
 use dotenv::dotenv;
-use image::{DynamicImage, GenericImageView, Rgba, RgbaImage, imageops};
-use imageproc::drawing::draw_text_mut;
+use image::{AnimationDecoder, DynamicImage, Frame, GenericImageView, ImageDecoder, Rgba, RgbaImage, imageops};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut, Blend};
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use imageproc::rect::Rect;
 use rusttype::{Font, Scale};
 use tempfile::NamedTempFile;
 use tokio::fs;
+#[cfg(feature = "video")]
+use tokio::process::Command;
+#[cfg(feature = "video")]
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+#[cfg(feature = "video")]
+use std::process::Stdio;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::SystemTime;
+use aws_sdk_s3::primitives::{ByteStream, DateTime, DateTimeFormat};
+#[cfg(feature = "video")]
+use aws_sdk_s3::primitives::Length;
+use aws_smithy_types::Document;
+use aws_smithy_types::body::SdkBody;
+use aws_smithy_runtime_api::client::http::{HttpConnectorFuture, HttpConnectorSettings, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::HttpResponse;
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+use regex::Regex;
 use aws_config::BehaviorVersion;
 use tokio::time::{sleep, Duration};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response, body::Incoming as IncomingBody};
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use aws_smithy_json::serialize::JsonObjectWriter;
+use aws_smithy_types::Number;
 use tokio::net::TcpListener;
+use futures_util::stream::{self, StreamExt};
+
+/// `println!` for log lines, without the leading emoji when `LOG_NO_EMOJI=true`
+macro_rules! log {
+    ($($arg:tt)*) => { println!("{}", log_line(&format!($($arg)*))) };
+}
+
+/// `eprintln!` counterpart of `log!`
+macro_rules! log_error {
+    ($($arg:tt)*) => { eprintln!("{}", log_line(&format!($($arg)*))) };
+}
+
+/// Plain-text logs for backends that mangle or don't want emoji (`LOG_NO_EMOJI`)
+static LOG_NO_EMOJI: OnceLock<bool> = OnceLock::new();
+
+fn log_line(line: &str) -> Cow<'_, str> {
+    if *LOG_NO_EMOJI.get_or_init(|| env::var("LOG_NO_EMOJI").unwrap_or_default() == "true") {
+        Cow::Owned(strip_leading_emoji(line))
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+/// Drops the emoji (with variation selectors and joiners) and the spacing after it from
+/// the start of a line, keeping any indentation before it
+fn strip_leading_emoji(line: &str) -> String {
+    let indent_len = line.len() - line.trim_start().len();
+    let (indent, rest) = line.split_at(indent_len);
+    let is_emoji = |c: char| matches!(c, '\u{2100}'..='\u{2BFF}' | '\u{FE0F}' | '\u{200D}' | '\u{1F000}'..);
+    let text = rest.trim_start_matches(is_emoji);
+    if text.len() == rest.len() {
+        return line.to_string();
+    }
+    format!("{}{}", indent, text.trim_start())
+}
+
+const DEFAULT_BUCKET: &str = "reflexu";
+
+/// Command line parsed by clap. Options fall back to the environment variable shown in
+/// `--help`, so a flag wins over both the environment and `.env`.
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Watermarks originals in S3-compatible storage and uploads the previews",
+    after_help = "Without a command, TEST_LOCAL=true selects `local`, PROCESS_UUID (or --uuid) processes that one \
+user once, RUN_ONCE=true selects `once`, otherwise `run`. `once --uuid <UUID>` also limits the run to that user."
+)]
+struct Cli {
+    /// `None` when no subcommand was given and the mode comes from the environment
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+    /// Process only this user's directory once and exit
+    #[arg(long, env = "PROCESS_UUID", global = true)]
+    uuid: Option<String>,
+    #[command(flatten)]
+    settings: CliSettings,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+enum CliCommand {
+    /// Process continuously every INTERVAL_MINUTES with a health server (default)
+    Run,
+    /// Process all users once and exit (same as RUN_ONCE=true)
+    Once,
+    /// Watermark the files in assets/ locally (same as TEST_LOCAL=true)
+    Local,
+    /// Watermark a single original
+    Single {
+        /// Key of the original, e.g. users/u/events/e/originals/photo.jpg
+        #[arg(long)]
+        key: String,
+    },
+    /// One user's directory, selected by `--uuid` / `PROCESS_UUID`
+    #[command(skip)]
+    User { uuid: String },
+}
+
+/// Settings that can also be given as flags; `Config::from_env` reads them from here
+/// instead of the environment. Values stay raw strings so that, like every other
+/// variable, a blank one means unset and a malformed one is reported with the rest of
+/// the configuration problems.
+#[derive(Args, Debug, Default)]
+struct CliSettings {
+    /// Minutes between processing cycles
+    #[arg(long, env = "INTERVAL_MINUTES", global = true)]
+    interval_minutes: Option<String>,
+    /// Stop a cycle after this many originals needed work
+    #[arg(long, env = "MAX_FILES_PER_CYCLE", global = true)]
+    max_files_per_cycle: Option<String>,
+    /// Comma-separated preview max dimensions, e.g. 400,800,1600
+    #[arg(long, env = "PREVIEW_SIZES", global = true)]
+    preview_sizes: Option<String>,
+    /// Skip images larger than this many bytes
+    #[arg(long, env = "MAX_IMAGE_BYTES", global = true)]
+    max_image_bytes: Option<String>,
+    /// Max FFmpeg time per video in seconds
+    #[arg(long, env = "VIDEO_TIMEOUT_SECONDS", global = true)]
+    video_timeout_seconds: Option<String>,
+    /// Skip videos larger than this many MB
+    #[arg(long, env = "VIDEO_MAX_MB", global = true)]
+    video_max_mb: Option<String>,
+    /// libx264, h264_nvenc or h264_vaapi
+    #[arg(long, env = "VIDEO_ENCODER", global = true)]
+    video_encoder: Option<String>,
+    /// mp4 or webm
+    #[arg(long, env = "VIDEO_OUTPUT_FORMAT", global = true)]
+    video_output_format: Option<String>,
+    /// Directory for temp files
+    #[arg(long, env = "TEMP_DIR", global = true)]
+    temp_dir: Option<PathBuf>,
+    /// Root prefix containing user directories
+    #[arg(long, env = "USER_PREFIX", global = true)]
+    user_prefix: Option<String>,
+    /// Regex user directory names must match
+    #[arg(long, env = "USER_ID_PATTERN", global = true)]
+    user_id_pattern: Option<String>,
+    /// Sample folder read by local mode
+    #[arg(long, env = "LOCAL_INPUT_DIR", global = true)]
+    local_input_dir: Option<PathBuf>,
+    /// Folder local mode writes previews to
+    #[arg(long, env = "LOCAL_OUTPUT_DIR", global = true)]
+    local_output_dir: Option<PathBuf>,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
-    // Check if we should run in local test mode (only if explicitly set)
-    if env::var("TEST_LOCAL").unwrap_or_default() == "true" {
-        println!("🧪 Running in local test mode with assets folder");
-        return test_local_files().await;
+    let cli = Cli::parse();
+
+    let command = match (cli.command, cli.uuid.filter(|uuid| !uuid.is_empty())) {
+        (Some(CliCommand::Once), Some(uuid)) => CliCommand::User { uuid },
+        (Some(command), _) => command,
+        (None, process_uuid) => {
+            let run_once_env = env::var("RUN_ONCE").unwrap_or_default();
+            log!("🔧 RUN_ONCE environment variable: '{}' (parsed as: {})", run_once_env, run_once_env == "true");
+
+            // Local test mode only if explicitly set
+            if env::var("TEST_LOCAL").unwrap_or_default() == "true" {
+                CliCommand::Local
+            } else if let Some(uuid) = process_uuid {
+                CliCommand::User { uuid }
+            } else if run_once_env == "true" {
+                CliCommand::Once
+            } else {
+                CliCommand::Run
+            }
+        }
+    };
+
+    // Fail fast, before the loop or health server, listing everything that needs fixing
+    #[cfg_attr(not(feature = "video"), allow(unused_mut))]
+    let mut config = match Config::from_env(command != CliCommand::Local, &cli.settings) {
+        Ok(config) => config,
+        Err(e) => {
+            log_error!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    #[cfg(feature = "video")]
+    {
+        log!("🎬 Video limits: timeout {}s, max size {}MB", config.video.timeout.as_secs(), config.video.max_mb);
+        config.video.encoder = resolve_video_encoder(config.video.encoder, config.video_output_format).await;
+        init_drawtext_probe().await;
+    }
+    #[cfg(not(feature = "video"))]
+    log!("🎬 Video support not compiled in, videos will be skipped");
+    init_watermark_font();
+    fallback_fonts();
+    if command != CliCommand::Local {
+        log!(
+            "🪣 Storage provider: {} ({} addressing), bucket '{}'",
+            config.provider.name(),
+            if config.force_path_style { "path-style" } else { "virtual-hosted-style" },
+            config.bucket
+        );
+        if let Some(endpoint) = &config.endpoint {
+            log!("🔗 Endpoint: {}", endpoint);
+        }
+        if config.accept_invalid_certs {
+            log_error!("{}", "!".repeat(60));
+            log_error!("⚠️  S3_ACCEPT_INVALID_CERTS=true: TLS certificates of the storage endpoint are NOT verified");
+            log_error!("⚠️  Anyone on the network path can impersonate the endpoint; use only for on-prem/testing setups");
+            log_error!("{}", "!".repeat(60));
+        }
+    }
+    log!("🎞️  Media types: {}", config.media_types.describe());
+    if config.disable_resize {
+        log!("📐 Resize disabled (DISABLE_RESIZE=true), previews keep the original resolution");
+    }
+    if config.flat_layout {
+        log!("📂 Flat layout: originals '{}', previews '{}' at the bucket root", config.originals_folder, config.watermarks_folder);
+    } else {
+        log!("📂 Event folders: originals '{}', previews '{}'", config.originals_folder, config.watermarks_folder);
+    }
+
+    let status = SharedStatus::default();
+
+    match command {
+        CliCommand::Local => {
+            log!("🧪 Running in local test mode with assets folder");
+            return test_local_files(&config).await;
+        }
+        CliCommand::Once => {
+            log!("▶️  Running in one-time mode");
+            process_files(&config, &status).await?;
+        }
+        CliCommand::Single { key } => {
+            log!("▶️  Processing single object: {}", key);
+            process_single_key(&config, &key, &status).await?;
+        }
+        CliCommand::User { uuid } => {
+            log!("▶️  Processing single user: {}", uuid);
+            process_single_user(&config, &uuid, &status).await?;
+        }
+        CliCommand::Run => {
+            // Run continuously with configurable interval
+            let interval_minutes = config.interval_minutes;
+
+            log!("🔄 Starting continuous worker (interval: {} minutes)", interval_minutes);
+
+            // Start health check server; without it the orchestrator can't see the worker,
+            // so a port conflict is fatal rather than a silently dead task
+            let listener = bind_health_server(HEALTH_ADDR).await?;
+            tokio::spawn(serve_health(listener, status.clone()));
+
+            let (min_retry, max_retry) = (config.min_retry_seconds, config.max_retry_seconds);
+            let jitter = config.interval_jitter_seconds;
+            let mut processing = false;
+            let mut consecutive_failures: u32 = 0;
+
+            loop {
+                if processing {
+                    log!("⏭️  Skipping cycle - previous processing still in progress");
+                } else {
+                    #[allow(unused_assignments)]
+                    {
+                        processing = true;
+                    }
+                    status.write().unwrap().start_cycle();
+                    let result = process_files(&config, &status).await;
+                    status.write().unwrap().finish_cycle(result.is_ok());
+                    match result {
+                        Ok(_) => {
+                            log!("✅ Processing cycle completed");
+                            consecutive_failures = 0;
+                        }
+                        Err(e) => {
+                            log_error!("❌ Processing cycle failed: {}", e);
+                            consecutive_failures += 1;
+                        }
+                    }
+                    processing = false;
+                }
+
+                let offset = if jitter > 0 { fastrand::u64(0..=jitter) } else { 0 };
+                if consecutive_failures > 0 {
+                    let delay = retry_delay_seconds(consecutive_failures, min_retry, max_retry) + offset;
+                    log!("🔁 Retrying in {}s (consecutive failures: {}, jitter: {}s)", delay, consecutive_failures, offset);
+                    sleep(Duration::from_secs(delay)).await;
+                } else {
+                    let delay = interval_minutes * 60 + offset;
+                    log!("⏳ Waiting {}s until next cycle ({} minutes + {}s jitter)...", delay, interval_minutes, offset);
+                    sleep(Duration::from_secs(delay)).await;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Watermarks one original given its full key, writing next to it in the sibling
+/// watermarks folder
+async fn process_single_key(config: &Config, key: &str, status: &SharedStatus) -> Result<(), Box<dyn std::error::Error>> {
+    let originals = &config.originals_folder;
+    let (event_prefix, _) = key
+        .rsplit_once(&format!("/{}", originals))
+        .ok_or_else(|| format!("Key '{}' is not inside an {} folder", key, originals))?;
+    let watermarks_prefix = format!("{}/{}", event_prefix, config.watermarks_folder);
+
+    let client = build_s3_client(config).await?;
+    let branding = match user_id_from_key(config, key) {
+        Some(user_id) => load_user_branding(config, &client, &user_id, &config.branding).await?,
+        None => config.branding.clone(),
+    };
+    // No listing here, so the ETag for REPROCESS_CHANGED comes from a HEAD
+    let etag = match config.reprocess_changed {
+        true => client.head_object().bucket(&config.bucket).key(key).send().await?.e_tag().map(str::to_string),
+        false => None,
+    };
+    process_one_object(config, &client, key, etag.as_deref(), &watermarks_prefix, &branding, status).await
+}
+
+/// Runs one full processing cycle and reports any failures to `NOTIFY_WEBHOOK_URL`
+async fn process_files(config: &Config, status: &SharedStatus) -> Result<(), Box<dyn std::error::Error>> {
+    let cycle_start = Instant::now();
+    let result = run_locked_cycle(config, status).await;
+
+    let summary = {
+        let mut status = status.write().unwrap();
+        let summary = CycleSummary {
+            files_processed: status.files_processed,
+            total_seconds: cycle_start.elapsed().as_secs_f64(),
+            processing_seconds: status.processing_seconds,
+            bytes_downloaded: status.bytes_downloaded,
+            bytes_uploaded: status.bytes_uploaded,
+        };
+        status.last_cycle = Some(summary);
+        summary
+    };
+    summary.log();
+
+    if let Some(limit) = config.max_files_per_cycle {
+        log!("📊 Cycle file limit: used {} of {}", status.read().unwrap().files_attempted, limit);
+    }
+    if let Err(e) = &result {
+        status.write().unwrap().record_failure("cycle", e.to_string());
+    }
+
+    let raw_skipped = std::mem::take(&mut status.write().unwrap().raw_skipped);
+    if !raw_skipped.is_empty() {
+        log!(
+            "📷 Skipped {} camera RAW original(s) this cycle (not supported, upload JPEG/PNG): {}",
+            raw_skipped.len(),
+            raw_skipped.iter().take(MAX_NOTIFIED_FAILURES).cloned().collect::<Vec<_>>().join(", ")
+        );
+    }
+    let empty_skipped = std::mem::take(&mut status.write().unwrap().empty_skipped);
+    if !empty_skipped.is_empty() {
+        log!(
+            "⏭️  Skipped {} empty original(s) this cycle (0 bytes, possibly interrupted uploads): {}",
+            empty_skipped.len(),
+            empty_skipped.iter().take(MAX_NOTIFIED_FAILURES).cloned().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let failures = std::mem::take(&mut status.write().unwrap().failures);
+    notify_failures(config, &failures).await;
+
+    result
+}
+
+/// Processes every user, holding the bucket-wide cycle lock when `ENABLE_LOCK=true`.
+/// A replica that finds the lock held by someone else skips the cycle.
+async fn run_locked_cycle(config: &Config, status: &SharedStatus) -> Result<(), Box<dyn std::error::Error>> {
+    let client = build_s3_client(config).await?;
+    if !config.enable_lock {
+        return process_all_users(config, &client, status).await;
+    }
+
+    if !acquire_cycle_lock(config, &client).await? {
+        return Ok(());
+    }
+    let result = process_all_users(config, &client, status).await;
+    release_cycle_lock(config, &client).await;
+    result
+}
+
+async fn process_all_users(config: &Config, client: &Client, status: &SharedStatus) -> Result<(), Box<dyn std::error::Error>> {
+    if config.flat_layout {
+        return process_flat_layout(config, client, status).await;
+    }
+
+    // Discover all user IDs under users/, unless an explicit list was given
+    let user_ids = match &config.user_list_file {
+        Some(path) => {
+            let user_ids = parse_user_list(config, &fs::read_to_string(path).await.map_err(|e| format!("could not read UUID_LIST_FILE {}: {}", path.display(), e))?);
+            log!("📋 Read {} user ids from {}", user_ids.len(), path.display());
+            if user_ids.is_empty() {
+                return Ok(());
+            }
+            user_ids
+        }
+        None => discover_user_ids(config, client).await?,
+    };
+
+    if user_ids.is_empty() {
+        log!("ℹ️  No user directories found in users/");
+        return Ok(());
+    }
+
+    let discovered = user_ids.len();
+    let user_ids = filter_user_ids(user_ids, config.user_allowlist.as_deref(), &config.user_denylist);
+    if user_ids.len() != discovered {
+        log!("🎯 UUID_ALLOWLIST/UUID_DENYLIST kept {} of {} user directories", user_ids.len(), discovered);
+    }
+
+    log!("👥 Found {} user directories to process", user_ids.len());
+
+    for user_id in user_ids {
+        if cycle_limit_reached(config, status) {
+            log!("⏸️  Reached MAX_FILES_PER_CYCLE, remaining files wait for the next cycle");
+            break;
+        }
+        process_user(config, client, &user_id, status).await?;
+    }
+
+    Ok(())
+}
+
+/// Processes `FLAT_LAYOUT` buckets: everything under `ORIGINALS_PREFIX` at the bucket root
+/// into `WATERMARKS_PREFIX`, with the default branding
+async fn process_flat_layout(config: &Config, client: &Client, status: &SharedStatus) -> Result<(), Box<dyn std::error::Error>> {
+    let originals_prefix = &config.originals_folder;
+    let watermarks_prefix = &config.watermarks_folder;
+    log!("📂 Flat layout: processing {} into {}", originals_prefix, watermarks_prefix);
+    process_files_in_paths(config, client, originals_prefix, watermarks_prefix, &config.branding, status).await?;
+    log!("✅ Completed processing {}", originals_prefix);
+    Ok(())
+}
+
+/// Processes every event of one user
+async fn process_user(config: &Config, client: &Client, user_id: &str, status: &SharedStatus) -> Result<(), Box<dyn std::error::Error>> {
+    log!("👤 Processing user: {}", user_id);
+
+    // Discover all events for this user
+    let event_ids = discover_event_ids(config, client, user_id).await?;
+
+    if event_ids.is_empty() {
+        log!("   ℹ️  No events found for user {}", user_id);
+        return Ok(());
+    }
+
+    log!("   📅 Found {} events for user {}", event_ids.len(), user_id);
+
+    // Without the user's own branding their previews would be made with the defaults and
+    // then never redone, so a failed read skips the user until the next cycle
+    let branding = match load_user_branding(config, client, user_id, &config.branding).await {
+        Ok(branding) => branding,
+        Err(e) => {
+            log_error!("   ❌ Failed to load branding for user {}: {}, skipping the user this cycle", user_id, e);
+            status.write().unwrap().record_failure(&format!("{}{}/", config.user_prefix, user_id), format!("failed to load branding: {}", e));
+            return Ok(());
+        }
+    };
+
+    for event_id in event_ids {
+        if cycle_limit_reached(config, status) {
+            break;
+        }
+        log!("   🎯 Processing event: {}", event_id);
+        let originals_prefix = format!("{}{}/events/{}/{}", config.user_prefix, user_id, event_id, config.originals_folder);
+        let watermarks_prefix = format!("{}{}/events/{}/{}", config.user_prefix, user_id, event_id, config.watermarks_folder);
+
+        match process_files_in_paths(config, client, &originals_prefix, &watermarks_prefix, &branding, status).await {
+            Ok(_) => log!("   ✅ Completed processing event {} for user {}", event_id, user_id),
+            Err(e) => {
+                log_error!("   ❌ Failed to process event {} for user {}: {}", event_id, user_id, e);
+                status.write().unwrap().record_failure(&originals_prefix, e.to_string());
+                // Continue processing other events
+                continue;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Processes one user's directory without discovering the others (`PROCESS_UUID` /
+/// `--uuid`). The id must be a single directory name matching `USER_ID_PATTERN`.
+async fn process_single_user(config: &Config, user_id: &str, status: &SharedStatus) -> Result<(), Box<dyn std::error::Error>> {
+    if user_id.is_empty() || user_id.contains('/') || !is_valid_user_id(config, user_id) {
+        return Err(format!("'{}' is not a valid user id (see USER_ID_PATTERN)", user_id).into());
+    }
+    let client = build_s3_client(config).await?;
+    process_user(config, &client, user_id, status).await
+}
+
+/// Keeps allowlisted (when an allowlist is set) and non-denylisted user ids
+fn filter_user_ids(user_ids: Vec<String>, allowlist: Option<&[String]>, denylist: &[String]) -> Vec<String> {
+    user_ids
+        .into_iter()
+        .filter(|id| allowlist.is_none_or(|allowed| allowed.contains(id)))
+        .filter(|id| !denylist.contains(id))
+        .collect()
+}
+
+/// Splits a comma-separated list, dropping blanks
+fn parse_list(raw: &str) -> Vec<String> {
+    raw.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::to_string).collect()
+}
+
+/// User ids from a `UUID_LIST_FILE`, one per line. Blank lines and `#` comments are
+/// ignored; ids that fail `USER_ID_PATTERN` are logged and dropped, as are duplicates.
+fn parse_user_list(config: &Config, contents: &str) -> Vec<String> {
+    let mut user_ids: Vec<String> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let user_id = line.trim();
+        if user_id.is_empty() || user_id.starts_with('#') {
+            continue;
+        }
+        if user_id.contains('/') || !is_valid_user_id(config, user_id) {
+            log_error!("⚠️  UUID_LIST_FILE line {}: '{}' is not a valid user id, skipping", index + 1, user_id);
+            continue;
+        }
+        if !user_ids.iter().any(|existing| existing == user_id) {
+            user_ids.push(user_id.to_string());
+        }
+    }
+    user_ids
+}
+
+/// Key of the cross-replica cycle lock, next to the user directories
+fn lock_key(config: &Config) -> String {
+    format!("{}.reflexu-lock", config.user_prefix)
+}
+
+/// Identifies this replica in the lock: `HOSTNAME` (the pod name on Kubernetes), the
+/// process id and a random suffix
+fn lock_owner() -> &'static str {
+    static OWNER: OnceLock<String> = OnceLock::new();
+    OWNER.get_or_init(|| {
+        let host = env::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string());
+        format!("{}-{}-{:08x}", host, std::process::id(), fastrand::u32(..))
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Lock object body: `{"owner": ..., "expires_at": <unix seconds>}`
+fn lock_payload(owner: &str, expires_at: u64) -> String {
+    let mut out = String::new();
+    let mut object = JsonObjectWriter::new(&mut out);
+    object.key("owner").string(owner);
+    object.key("expires_at").number(Number::PosInt(expires_at));
+    object.finish();
+    out
+}
+
+/// (owner, expires_at) from a lock object; `None` if it can't be parsed
+fn parse_lock(body: &[u8]) -> Option<(String, u64)> {
+    let mut tokens = aws_smithy_json::deserialize::json_token_iter(body).peekable();
+    let Document::Object(mut fields) = aws_smithy_json::deserialize::token::expect_document(&mut tokens).ok()? else {
+        return None;
+    };
+    let Some(Document::String(owner)) = fields.remove("owner") else { return None };
+    let Some(Document::Number(expires_at)) = fields.remove("expires_at") else { return None };
+    Some((owner, expires_at.to_f64_lossy() as u64))
+}
+
+/// Current lock holder as (owner, expires_at, etag), or `None` when there is no lock
+async fn read_cycle_lock(config: &Config, client: &Client) -> Result<Option<(String, u64, Option<String>)>, Box<dyn std::error::Error>> {
+    let object = match client.get_object().bucket(&config.bucket).key(lock_key(config)).send().await {
+        Ok(object) => object,
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let etag = object.e_tag().map(str::to_string);
+    let body = object.body.collect().await?.into_bytes();
+    // An unreadable lock is treated as expired so a corrupt object can't block every replica
+    Ok(Some(parse_lock(&body).map_or((String::new(), 0, etag.clone()), |(owner, expires_at)| (owner, expires_at, etag))))
+}
+
+/// Takes the cycle lock for `LOCK_TTL_SECONDS` unless another replica holds an unexpired
+/// one. Writes are conditional (`If-None-Match`/`If-Match`) and read back, so on providers
+/// that ignore the conditions a lost race is still detected.
+async fn acquire_cycle_lock(config: &Config, client: &Client) -> Result<bool, Box<dyn std::error::Error>> {
+    let ttl_seconds = config.lock_ttl_seconds;
+    let now = unix_now();
+    let mut put = client
+        .put_object()
+        .bucket(&config.bucket)
+        .key(lock_key(config))
+        .content_type("application/json")
+        .body(lock_payload(lock_owner(), now + ttl_seconds).into_bytes().into());
+
+    match read_cycle_lock(config, client).await? {
+        Some((owner, expires_at, _)) if owner != lock_owner() && expires_at > now => {
+            log!("🔒 Cycle lock held by {} for another {}s, skipping this cycle", owner, expires_at - now);
+            return Ok(false);
+        }
+        Some((_, _, Some(etag))) => put = put.if_match(etag),
+        Some((_, _, None)) => {}
+        None => put = put.if_none_match("*"),
+    }
+
+    if let Err(e) = put.send().await {
+        // 412/409: another replica wrote the lock between our read and write
+        if matches!(e.raw_response().map(|r| r.status().as_u16()), Some(409 | 412)) {
+            log!("🔒 Another replica took the cycle lock first, skipping this cycle");
+            return Ok(false);
+        }
+        return Err(e.into());
+    }
+
+    match read_cycle_lock(config, client).await? {
+        Some((owner, _, _)) if owner == lock_owner() => {
+            log!("🔒 Acquired cycle lock as {} (ttl {}s)", owner, ttl_seconds);
+            Ok(true)
+        }
+        _ => {
+            log!("🔒 Another replica took the cycle lock first, skipping this cycle");
+            Ok(false)
+        }
+    }
+}
+
+/// Deletes the cycle lock if this replica still owns it. Failures are logged; the lock
+/// expires on its own after the TTL.
+async fn release_cycle_lock(config: &Config, client: &Client) {
+    match read_cycle_lock(config, client).await {
+        Ok(Some((owner, _, _))) if owner == lock_owner() => {
+            match client.delete_object().bucket(&config.bucket).key(lock_key(config)).send().await {
+                Ok(_) => log!("🔓 Released cycle lock"),
+                Err(e) => log_error!("⚠️  Failed to release cycle lock: {}", e),
+            }
+        }
+        Ok(_) => log_error!("⚠️  Cycle lock was taken over by another replica before release"),
+        Err(e) => log_error!("⚠️  Failed to read cycle lock before release: {}", e),
+    }
+}
+
+/// Whether this cycle has already worked on `MAX_FILES_PER_CYCLE` originals
+fn cycle_limit_reached(config: &Config, status: &SharedStatus) -> bool {
+    config
+        .max_files_per_cycle
+        .is_some_and(|limit| status.read().unwrap().files_attempted >= limit)
+}
+
+/// Folder name from `var` with exactly one trailing slash, or `default` when unset
+fn event_folder(var: &str, default: &str) -> String {
+    let folder = env::var(var).unwrap_or_default();
+    let folder = folder.trim_matches('/');
+    if folder.is_empty() {
+        default.to_string()
+    } else {
+        format!("{}/", folder)
+    }
+}
+
+/// User directory a key belongs to, e.g. `abc` for `users/abc/events/e/originals/a.jpg`
+fn user_id_from_key(config: &Config, key: &str) -> Option<String> {
+    key.strip_prefix(&config.user_prefix)?
+        .split('/')
+        .next()
+        .filter(|user_id| !user_id.is_empty())
+        .map(str::to_string)
+}
+
+/// Compiles `USER_ID_PATTERN` at startup so a malformed regex fails fast. The pattern
+/// must match the whole directory name.
+fn user_id_pattern(pattern: Option<&str>, user_prefix: &str) -> Result<Option<Regex>, Box<dyn std::error::Error>> {
+    match pattern {
+        Some(pattern) if !pattern.is_empty() => {
+            let regex = Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| format!("USER_ID_PATTERN '{}' is not a valid regex: {}", pattern, e))?;
+            log!("🔎 User directories under '{}' must match: {}", user_prefix, pattern);
+            Ok(Some(regex))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn is_valid_user_id(config: &Config, user_id: &str) -> bool {
+    config.user_id_pattern.as_ref().is_none_or(|regex| regex.is_match(user_id))
+}
+
+/// Worker state shared between the processing loop and the `/status` endpoint
+#[derive(Debug, Default)]
+struct WorkerStatus {
+    last_success: Option<SystemTime>,
+    cycle_in_progress: bool,
+    /// Originals watermarked in the current (or last finished) cycle
+    files_processed: u64,
+    /// Originals that needed work this cycle, successful or not; counts toward
+    /// `MAX_FILES_PER_CYCLE`
+    files_attempted: u64,
+    /// Failures of the running cycle as (key or prefix, error), reported at cycle end
+    failures: Vec<(String, String)>,
+    /// Camera RAW originals skipped this cycle, reported separately from failures
+    raw_skipped: Vec<String>,
+    /// Zero-byte originals (usually interrupted uploads) skipped this cycle, reported
+    /// separately from failures
+    empty_skipped: Vec<String>,
+    /// Seconds spent on originals that needed work this cycle, summed over files
+    processing_seconds: f64,
+    bytes_downloaded: u64,
+    bytes_uploaded: u64,
+    /// Throughput of the last finished cycle
+    last_cycle: Option<CycleSummary>,
+}
+
+/// End-of-cycle throughput, logged like local mode's performance summary and reported
+/// by `/status`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CycleSummary {
+    files_processed: u64,
+    total_seconds: f64,
+    /// Summed per-file time; with `MAX_CONCURRENT_FILES` above 1 it can exceed the total
+    processing_seconds: f64,
+    bytes_downloaded: u64,
+    bytes_uploaded: u64,
+}
+
+impl CycleSummary {
+    fn average_seconds(&self) -> f64 {
+        if self.files_processed > 0 { self.processing_seconds / self.files_processed as f64 } else { 0.0 }
+    }
+
+    /// Listing, HEAD checks and locking: wall time not spent inside a file
+    fn overhead_seconds(&self) -> f64 {
+        (self.total_seconds - self.processing_seconds).max(0.0)
+    }
+
+    fn log(&self) {
+        let mb = |bytes: u64| bytes as f64 / 1024.0 / 1024.0;
+        log!("{}", "=".repeat(60));
+        log!("📊 CYCLE SUMMARY");
+        log!("{}", "=".repeat(60));
+        log!("📁 Files processed: {}", self.files_processed);
+        log!("⏱️  Total cycle time: {:.2}s", self.total_seconds);
+        log!("⚡ Average time per file: {:.2}s", self.average_seconds());
+        log!("🔄 Processing time only: {:.2}s", self.processing_seconds);
+        log!("🔧 Overhead time: {:.2}s", self.overhead_seconds());
+        log!(
+            "📦 Downloaded {:.1}MB, uploaded {:.1}MB ({:.2}MB/s downloaded)",
+            mb(self.bytes_downloaded),
+            mb(self.bytes_uploaded),
+            mb(self.bytes_downloaded) / self.total_seconds.max(0.001)
+        );
+        log!("{}", "=".repeat(60));
+    }
+
+    fn to_json(self) -> String {
+        let mut out = String::new();
+        let mut object = JsonObjectWriter::new(&mut out);
+        object.key("files_processed").number(Number::PosInt(self.files_processed));
+        object.key("total_seconds").number(Number::Float(self.total_seconds));
+        object.key("average_seconds_per_file").number(Number::Float(self.average_seconds()));
+        object.key("processing_seconds").number(Number::Float(self.processing_seconds));
+        object.key("overhead_seconds").number(Number::Float(self.overhead_seconds()));
+        object.key("bytes_downloaded").number(Number::PosInt(self.bytes_downloaded));
+        object.key("bytes_uploaded").number(Number::PosInt(self.bytes_uploaded));
+        object.finish();
+        out
+    }
+}
+
+/// Adds the time until it's dropped to the cycle's processing time, whichever way the
+/// file's processing ends
+struct FileTimer<'a> {
+    status: &'a SharedStatus,
+    start: Instant,
+}
+
+impl Drop for FileTimer<'_> {
+    fn drop(&mut self) {
+        self.status.write().unwrap().processing_seconds += self.start.elapsed().as_secs_f64();
+    }
+}
+
+type SharedStatus = Arc<RwLock<WorkerStatus>>;
+
+impl WorkerStatus {
+    fn start_cycle(&mut self) {
+        self.cycle_in_progress = true;
+        self.files_processed = 0;
+        self.files_attempted = 0;
+        self.raw_skipped.clear();
+        self.empty_skipped.clear();
+        self.processing_seconds = 0.0;
+        self.bytes_downloaded = 0;
+        self.bytes_uploaded = 0;
+    }
+
+    fn record_failure(&mut self, subject: &str, error: impl Into<String>) {
+        self.failures.push((subject.to_string(), error.into()));
+    }
+
+    fn finish_cycle(&mut self, success: bool) {
+        self.cycle_in_progress = false;
+        if success {
+            self.last_success = Some(SystemTime::now());
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let last_success = match self.last_success {
+            Some(time) => DateTime::from(time)
+                .fmt(DateTimeFormat::DateTime)
+                .map(|formatted| format!("\"{}\"", formatted))
+                .unwrap_or_else(|_| "null".to_string()),
+            None => "null".to_string(),
+        };
+        let last_cycle = self.last_cycle.map_or_else(|| "null".to_string(), |summary| summary.to_json());
+        format!(
+            "{{\"last_success\":{},\"cycle_in_progress\":{},\"files_processed\":{},\"last_cycle\":{}}}",
+            last_success, self.cycle_in_progress, self.files_processed, last_cycle
+        )
+    }
+}
+
+/// Numeric settings that must parse when set; otherwise a typo would silently fall back to
+/// the default
+const INTEGER_ENV_VARS: &[&str] = &[
+    "MAX_CONCURRENT_FILES",
+    "LOCK_TTL_SECONDS",
+    "FULLSIZE_MAX_PIXELS",
+    "FULLSIZE_JPEG_QUALITY",
+    "INTERVAL_JITTER_SECONDS",
+    "MIN_RETRY_SECONDS",
+    "MAX_RETRY_SECONDS",
+    "MULTIPART_THRESHOLD_MB",
+    "DOWNLOAD_TIMEOUT_SECONDS",
+    "DOWNLOAD_RETRIES",
+    "MAX_CONCURRENT_FFMPEG",
+    "MAX_CONCURRENT_UPLOADS",
+    "ZIP_MAX_ENTRIES",
+    "ZIP_MAX_TOTAL_MB",
+    "THROTTLE_RETRIES",
+    "FFMPEG_RETRIES",
+    "GIF_MAX_FRAMES",
+    "GIF_MAX_TOTAL_PIXELS",
+    "PIXELATE_BLOCK_SIZE",
+    "WATERMARK_OUTLINE_WIDTH",
+    "WATERMARK_TEXT_BORDER_WIDTH",
+];
+const DECIMAL_ENV_VARS: &[&str] = &[
+    "VIDEO_POSTER_TIME",
+    "VIDEO_WEBP_SECONDS",
+    "VIDEO_WEBP_FPS",
+    "VIDEO_WEBP_WIDTH",
+    "VIDEO_WEBP_MAX_KB",
+    "VIDEO_DISK_HEADROOM",
+    "WATERMARK_OPACITY",
+    "WATERMARK_COVERAGE",
+    "WATERMARK_BAND_OPACITY",
+    "BLUR_SIGMA",
+    "CORNER_SIZE",
+    "CORNER_OPACITY",
+    "TILE_SIZE",
+    "TILE_SPACING",
+    "TILE_OPACITY",
+    "WATERMARK_ANGLE",
+];
+
+/// Settings read once at startup by `Config::from_env` and passed down as `&Config`
+#[derive(Clone)]
+struct Config {
+    /// Bucket holding the user tree (`S3_BUCKET`, default `reflexu`)
+    bucket: String,
+    provider: S3Provider,
+    region: String,
+    /// Explicit endpoint; `None` lets AWS resolve its regional one
+    endpoint: Option<String>,
+    access_key: Option<String>,
+    secret_key: Option<String>,
+    session_token: Option<String>,
+    force_path_style: bool,
+    /// Skip TLS certificate verification for self-signed on-prem endpoints
+    /// (`S3_ACCEPT_INVALID_CERTS`)
+    accept_invalid_certs: bool,
+    /// Minutes between cycles in continuous mode (`INTERVAL_MINUTES`, default 30)
+    interval_minutes: u64,
+    /// Originals to work on per cycle before deferring the rest (`MAX_FILES_PER_CYCLE`,
+    /// default unlimited)
+    max_files_per_cycle: Option<u64>,
+    /// Originals of one event processed in parallel (`MAX_CONCURRENT_FILES`, default 4);
+    /// videos still queue on the FFmpeg semaphore
+    max_concurrent_files: usize,
+    process_order: ProcessOrder,
+    /// Only these user ids are processed when set (`UUID_ALLOWLIST`, comma-separated)
+    user_allowlist: Option<Vec<String>>,
+    /// Newline-delimited user ids processed instead of discovering them (`UUID_LIST_FILE`),
+    /// re-read every cycle
+    user_list_file: Option<PathBuf>,
+    /// User ids that are never processed (`UUID_DENYLIST`, comma-separated)
+    user_denylist: Vec<String>,
+    /// Process one `originals/` prefix at the bucket root instead of per-user event
+    /// directories (`FLAT_LAYOUT`)
+    flat_layout: bool,
+    /// Root under which user directories live (`USER_PREFIX`, default `users/`)
+    user_prefix: String,
+    /// Compiled `USER_ID_PATTERN`; `None` when unset, in which case every directory is a user
+    user_id_pattern: Option<Regex>,
+    /// Event subfolder holding the originals (`ORIGINALS_PREFIX`, default `originals/`)
+    originals_folder: String,
+    /// Event subfolder receiving the previews (`WATERMARKS_PREFIX`, default `watermarks/`)
+    watermarks_folder: String,
+    /// Event subfolder receiving full-resolution watermarked copies (`FULLSIZE_PREFIX`,
+    /// default `watermarked-full/`)
+    fullsize_folder: String,
+    /// Folder under each user receiving untouched copies of the originals (`ARCHIVE_PREFIX`,
+    /// default `archive/`)
+    archive_folder: String,
+    /// Validated `DEST_KEY_PATTERN`
+    dest_key_pattern: String,
+    /// Hold a bucket-wide lock during each cycle so replicas don't overlap (`ENABLE_LOCK`)
+    enable_lock: bool,
+    /// Lifetime of the cycle lock (`LOCK_TTL_SECONDS`, default 3600); a crashed holder
+    /// blocks other replicas at most this long
+    lock_ttl_seconds: u64,
+    /// Log per-stage timings for each file (`LOG_TIMINGS`)
+    log_timings: bool,
+    /// Backoff bounds after a failed cycle (`MIN_RETRY_SECONDS` default 30,
+    /// `MAX_RETRY_SECONDS` default 600)
+    min_retry_seconds: u64,
+    max_retry_seconds: u64,
+    /// Upper bound of the random offset added to each sleep (`INTERVAL_JITTER_SECONDS`, default 0)
+    interval_jitter_seconds: u64,
+    /// Extra attempts after the storage provider throttles a request (`THROTTLE_RETRIES`,
+    /// default 5)
+    throttle_retries: u32,
+    /// Max time for one download attempt of an original (`DOWNLOAD_TIMEOUT_SECONDS`, default 300)
+    download_timeout: Duration,
+    /// Extra attempts after a stalled or truncated download (`DOWNLOAD_RETRIES`, default 2)
+    download_retries: u32,
+    /// `Cache-Control` header for previews (`CACHE_CONTROL`); set it empty to send none
+    cache_control: Option<String>,
+    /// Outputs larger than this are uploaded in parts (`MULTIPART_THRESHOLD_MB`, default 100)
+    multipart_threshold_bytes: u64,
+    /// Check each upload's size with a HEAD and re-upload once on a mismatch (`UPLOAD_VERIFY`)
+    upload_verify: bool,
+    /// Uploads running at once across all files (`MAX_CONCURRENT_UPLOADS`, default 4)
+    max_concurrent_uploads: usize,
+    /// Directory for temp files (`TEMP_DIR`, default the system temp dir)
+    temp_dir: PathBuf,
+    /// Posted to when a preview is uploaded (`WEBHOOK_URL`)
+    webhook_url: Option<String>,
+    /// Posted a summary of each cycle's failures (`NOTIFY_WEBHOOK_URL`)
+    notify_webhook_url: Option<String>,
+    /// Local mode reads from here (`LOCAL_INPUT_DIR`, default `assets`)
+    local_input_dir: PathBuf,
+    /// Local mode writes here (`LOCAL_OUTPUT_DIR`, default `assets/watermarked`)
+    local_output_dir: PathBuf,
+    /// Longest side of the default preview
+    max_dimension: u32,
+    /// JPEG quality of previews, kept low to discourage unauthorized use
+    jpeg_quality: u8,
+    /// JPEG quality of the full-resolution copy, a deliverable rather than a preview
+    /// (`FULLSIZE_JPEG_QUALITY`, default 85)
+    fullsize_jpeg_quality: u8,
+    /// Write progressive instead of baseline JPEGs (`JPEG_PROGRESSIVE`)
+    jpeg_progressive: bool,
+    /// Chroma subsampling of every JPEG written (`JPEG_SUBSAMPLING`)
+    jpeg_subsampling: JpegSubsampling,
+    /// Byte size above which images are skipped instead of decoded (`MAX_IMAGE_BYTES`,
+    /// default 50MB)
+    max_image_bytes: u64,
+    /// Originals with more pixels than this get no full-resolution copy
+    /// (`FULLSIZE_MAX_PIXELS`, default 50 megapixels)
+    fullsize_max_pixels: u64,
+    /// Animated GIFs with more frames than this are rejected (`GIF_MAX_FRAMES`, default 300)
+    gif_max_frames: usize,
+    /// Cap on decoded pixels summed over all GIF frames (`GIF_MAX_TOTAL_PIXELS`, default 100M)
+    gif_max_total_pixels: u64,
+    zip_limits: ZipLimits,
+    video_output_format: VideoOutputFormat,
+    #[cfg(feature = "video")]
+    video: VideoSettings,
+    /// Re-watermark originals whose ETag differs from the one stored on their preview
+    /// (`REPROCESS_CHANGED`)
+    reprocess_changed: bool,
+    /// Only work on originals modified at or after this time, redoing their previews if
+    /// those are older (`PROCESS_SINCE`, RFC3339)
+    process_since: Option<DateTime>,
+    preview_sizes: Option<Vec<u32>>,
+    /// Watermark at the original resolution instead of downscaling (`DISABLE_RESIZE`)
+    disable_resize: bool,
+    /// Also upload a full-resolution watermarked copy of each image (`FULLSIZE_WATERMARK`)
+    fullsize_watermark: bool,
+    /// Upload a `{base}-watermark.json` metadata sidecar next to each image preview (`EMIT_SIDECAR`)
+    emit_sidecar: bool,
+    /// Watermark the images inside `.zip` originals (`PROCESS_ZIP`)
+    process_zip: bool,
+    /// Server-side copy each processed original to `ARCHIVE_PREFIX` (`ARCHIVE_ORIGINALS`)
+    archive_originals: bool,
+    /// Delete previews whose original is gone at the end of each run (`CLEANUP_ORPHANS`)
+    cleanup_orphans: bool,
+    /// Only log orphaned previews instead of deleting them (`CLEANUP_DRY_RUN`)
+    cleanup_dry_run: bool,
+    preview_format: PreviewFormat,
+    media_types: MediaTypes,
+    /// Watermark defaults, before per-user overrides
+    branding: Branding,
+}
+
+impl Config {
+    /// Reads and validates the configuration, reporting every missing or malformed
+    /// variable in a single error. Storage settings are only required when
+    /// `needs_storage` (i.e. not in local mode).
+    fn from_env(needs_storage: bool, cli: &CliSettings) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut problems = Vec::new();
+
+        let provider = S3Provider::from_env().unwrap_or_else(|e| {
+            if needs_storage {
+                problems.push(e.to_string());
+            }
+            S3Provider::Spaces
+        });
+        let endpoint = match provider {
+            // AWS resolves its own regional endpoint; Spaces and MinIO need an explicit one
+            S3Provider::Aws => None,
+            _ => env_any(&["DO_SPACES_ENDPOINT", "S3_ENDPOINT"]),
+        };
+        let access_key = env_any(&["DO_SPACES_KEY", "AWS_ACCESS_KEY_ID"]);
+        let secret_key = env_any(&["DO_SPACES_SECRET", "AWS_SECRET_ACCESS_KEY"]);
+        if needs_storage {
+            if provider != S3Provider::Aws && endpoint.is_none() {
+                problems.push(format!(
+                    "DO_SPACES_ENDPOINT (or S3_ENDPOINT) is required for S3_PROVIDER={}",
+                    provider.name()
+                ));
+            }
+            if let Some(problem) = endpoint.as_deref().and_then(endpoint_problem) {
+                problems.push(problem);
+            }
+            // Keys are optional (the default credential chain is used without them), but half a pair is a mistake
+            match (&access_key, &secret_key) {
+                (Some(_), None) => problems.push("DO_SPACES_SECRET (or AWS_SECRET_ACCESS_KEY) is missing".to_string()),
+                (None, Some(_)) => problems.push("DO_SPACES_KEY (or AWS_ACCESS_KEY_ID) is missing".to_string()),
+                _ => {}
+            }
+        }
+
+        for &var in INTEGER_ENV_VARS {
+            if let Some(value) = env_any(&[var]) {
+                if value.trim().parse::<u64>().is_err() {
+                    problems.push(format!("{} must be a whole number, got '{}'", var, value));
+                }
+            }
+        }
+        for &var in DECIMAL_ENV_VARS {
+            if let Some(value) = env_any(&[var]) {
+                if value.trim().parse::<f64>().is_err() {
+                    problems.push(format!("{} must be a number, got '{}'", var, value));
+                }
+            }
+        }
+        for var in ["WATERMARK_COLOR", "WATERMARK_OUTLINE_COLOR", "WATERMARK_TEXT_BORDER_COLOR", "WATERMARK_BAND_COLOR"] {
+            if let Some(value) = env_any(&[var]) {
+                if parse_hex_color(&value).is_none() {
+                    problems.push(format!("{} must be a hex color like #000000, got '{}'", var, value));
+                }
+            }
+        }
+
+        // Flag-backed settings, already taken from the command line or the environment by clap
+        for (var, value) in [
+            ("INTERVAL_MINUTES", &cli.interval_minutes),
+            ("MAX_FILES_PER_CYCLE", &cli.max_files_per_cycle),
+            ("MAX_IMAGE_BYTES", &cli.max_image_bytes),
+            ("VIDEO_TIMEOUT_SECONDS", &cli.video_timeout_seconds),
+        ] {
+            if let Some(value) = cli_value(value) {
+                if value.trim().parse::<u64>().is_err() {
+                    problems.push(format!("{} must be a whole number, got '{}'", var, value));
+                }
+            }
+        }
+        if let Some(value) = cli_value(&cli.video_max_mb) {
+            if value.trim().parse::<f64>().is_err() {
+                problems.push(format!("VIDEO_MAX_MB must be a number, got '{}'", value));
+            }
+        }
+        for (var, value, allowed) in [
+            ("VIDEO_ENCODER", &cli.video_encoder, &["libx264", "h264_nvenc", "h264_vaapi"][..]),
+            ("VIDEO_OUTPUT_FORMAT", &cli.video_output_format, &["mp4", "webm"]),
+        ] {
+            if let Some(value) = cli_value(value) {
+                if !allowed.contains(&value.trim().to_lowercase().as_str()) {
+                    problems.push(format!("{} must be one of {}, got '{}'", var, allowed.join(", "), value));
+                }
+            }
+        }
+
+        let process_since = env_any(&["PROCESS_SINCE"]).and_then(|value| match DateTime::from_str(value.trim(), DateTimeFormat::DateTime) {
+            Ok(since) => Some(since),
+            Err(_) => {
+                problems.push(format!("PROCESS_SINCE must be an RFC3339 timestamp like 2024-05-01T00:00:00Z, got '{}'", value));
+                None
+            }
+        });
+
+        if let Some(value) = env_any(&["FULLSIZE_JPEG_QUALITY"]) {
+            if value.trim().parse::<u64>().is_ok_and(|quality| !(1..=100).contains(&quality)) {
+                problems.push(format!("FULLSIZE_JPEG_QUALITY must be between 1 and 100, got '{}'", value));
+            }
+        }
+        for (var, allowed) in [
+            ("PROCESS_ORDER", &["key", "oldest", "newest"][..]),
+            ("MEDIA_TYPES", &["all", "images", "videos"]),
+            ("PREVIEW_FORMAT", &["jpeg", "jpg", "avif"]),
+            ("WATERMARK_STYLE", &["pattern", "corner", "tiled-logo"]),
+            ("WATERMARK_REGION", &["full", "top", "bottom", "center-band", "center"]),
+            ("WATERMARK_BAND", &["off", "false", "dark", "true", "light"]),
+            ("CORNER", &["top-left", "top-right", "bottom-left", "bottom-right"]),
+            ("PROTECTION_MODE", &["watermark", "blur", "pixelate"]),
+            ("JPEG_SUBSAMPLING", &["4:2:0", "420", "4:4:4", "444"]),
+        ] {
+            if let Some(value) = env_any(&[var]) {
+                if !allowed.contains(&value.trim().to_lowercase().as_str()) {
+                    problems.push(format!("{} must be one of {}, got '{}'", var, allowed.join(", "), value));
+                }
+            }
+        }
+        if !cfg!(feature = "avif") && env_any(&["PREVIEW_FORMAT"]).is_some_and(|v| v.trim().eq_ignore_ascii_case("avif")) {
+            problems.push("PREVIEW_FORMAT=avif needs AVIF encoding compiled in (build with --features avif)".to_string());
+        }
+        let preview_sizes = cli_value(&cli.preview_sizes).and_then(|raw| match parse_preview_sizes(raw) {
+            Ok(sizes) => Some(sizes),
+            Err(e) => {
+                problems.push(e);
+                None
+            }
+        });
+        if let Some(value) = env_any(&["WATERMARK_ELEMENT_GAP"]) {
+            if ElementGap::parse(&value).is_none() {
+                problems.push(format!("WATERMARK_ELEMENT_GAP must be pixels like 24 or 24px, or a fraction below 1 like 0.04, got '{}'", value));
+            }
+        }
+        if let Some(value) = env_any(&["WATERMARK_ANGLE"]) {
+            if value.trim().parse::<f32>().is_ok_and(|angle| !WATERMARK_ANGLE_RANGE.contains(&angle)) {
+                problems.push(format!("WATERMARK_ANGLE must be between -90 and 90 degrees, got '{}'", value));
+            }
+        }
+
+        let user_prefix = match cli.user_prefix.clone() {
+            Some(prefix) if prefix.is_empty() || prefix.ends_with('/') => prefix,
+            Some(prefix) => format!("{}/", prefix),
+            None => "users/".to_string(),
+        };
+        let user_id_pattern = user_id_pattern(cli_value(&cli.user_id_pattern), &user_prefix).unwrap_or_else(|e| {
+            problems.push(e.to_string());
+            None
+        });
+        let flat_layout = env_any(&["FLAT_LAYOUT"]).is_some_and(|v| v == "true");
+        if flat_layout && env_any(&["UUID_LIST_FILE"]).is_some() {
+            problems.push("UUID_LIST_FILE has no effect with FLAT_LAYOUT=true, which has no user directories".to_string());
+        }
+        if let Some(path) = env_any(&["UUID_LIST_FILE"]).filter(|_| needs_storage) {
+            if !Path::new(&path).is_file() {
+                problems.push(format!("UUID_LIST_FILE '{}' does not exist or is not a file", path));
+            }
+        }
+        let dest_key_pattern = dest_key_pattern(preview_sizes.is_some()).unwrap_or_else(|e| {
+            problems.push(e.to_string());
+            DEFAULT_DEST_KEY_PATTERN.to_string()
+        });
+
+        if !problems.is_empty() {
+            let list = problems.iter().map(|problem| format!("  - {}", problem)).collect::<Vec<_>>().join("\n");
+            return Err(format!("Invalid configuration ({} problem(s)):\n{}", problems.len(), list).into());
+        }
+
+        if let Some(sizes) = &preview_sizes {
+            log!("📏 Preview sizes: {:?}", sizes);
+        }
+        // Widths of resized previews; with DISABLE_RESIZE every image keeps its own width
+        let logo_cache_sizes = match (&preview_sizes, env_any(&["DISABLE_RESIZE"]).is_some_and(|v| v == "true")) {
+            (_, true) => Vec::new(),
+            (Some(sizes), false) => sizes.clone(),
+            (None, false) => vec![DEFAULT_PREVIEW_SIZE],
+        };
+
+        let number = |var: &str, default: u64| env_any(&[var]).and_then(|v| v.trim().parse::<u64>().ok()).unwrap_or(default);
+        // Zero would disable the knob outright, so it means "use the default" instead
+        let positive = |var: &str, default: u64| env_any(&[var]).and_then(|v| v.trim().parse::<u64>().ok()).filter(|v| *v > 0).unwrap_or(default);
+        let min_retry_seconds = positive("MIN_RETRY_SECONDS", 30);
+        let max_image_bytes = cli_value(&cli.max_image_bytes).and_then(|v| v.trim().parse().ok()).unwrap_or(50 * 1024 * 1024);
+
+        Ok(Self {
+            bucket: env_any(&["S3_BUCKET"]).unwrap_or_else(|| DEFAULT_BUCKET.to_string()),
+            provider,
+            region: env_any(&["AWS_REGION"]).unwrap_or_else(|| provider.default_region().to_string()),
+            endpoint,
+            access_key,
+            secret_key,
+            session_token: env_any(&["DO_SPACES_SESSION_TOKEN", "AWS_SESSION_TOKEN"]),
+            force_path_style: force_path_style(provider),
+            accept_invalid_certs: env_any(&["S3_ACCEPT_INVALID_CERTS"]).is_some_and(|v| v == "true"),
+            interval_minutes: cli_value(&cli.interval_minutes).and_then(|v| v.trim().parse().ok()).unwrap_or(30),
+            max_files_per_cycle: cli_value(&cli.max_files_per_cycle).and_then(|v| v.trim().parse().ok()),
+            max_concurrent_files: env_any(&["MAX_CONCURRENT_FILES"]).and_then(|v| v.trim().parse().ok()).filter(|v| *v > 0).unwrap_or(4),
+            process_order: ProcessOrder::from_env(),
+            user_allowlist: env_any(&["UUID_ALLOWLIST"]).map(|raw| parse_list(&raw)).filter(|ids| !ids.is_empty()),
+            user_denylist: env_any(&["UUID_DENYLIST"]).map(|raw| parse_list(&raw)).unwrap_or_default(),
+            user_list_file: env_any(&["UUID_LIST_FILE"]).map(PathBuf::from),
+            flat_layout,
+            user_prefix,
+            user_id_pattern,
+            originals_folder: event_folder("ORIGINALS_PREFIX", "originals/"),
+            watermarks_folder: event_folder("WATERMARKS_PREFIX", "watermarks/"),
+            fullsize_folder: event_folder("FULLSIZE_PREFIX", "watermarked-full/"),
+            archive_folder: event_folder("ARCHIVE_PREFIX", "archive/"),
+            dest_key_pattern,
+            enable_lock: env_any(&["ENABLE_LOCK"]).is_some_and(|v| v == "true"),
+            log_timings: env_any(&["LOG_TIMINGS"]).is_some_and(|v| v == "true"),
+            min_retry_seconds,
+            max_retry_seconds: positive("MAX_RETRY_SECONDS", 600).max(min_retry_seconds),
+            interval_jitter_seconds: number("INTERVAL_JITTER_SECONDS", 0),
+            throttle_retries: number("THROTTLE_RETRIES", 5) as u32,
+            download_timeout: Duration::from_secs(positive("DOWNLOAD_TIMEOUT_SECONDS", 300)),
+            download_retries: number("DOWNLOAD_RETRIES", 2) as u32,
+            cache_control: match env::var("CACHE_CONTROL") {
+                Ok(value) if value.is_empty() => None,
+                Ok(value) => Some(value),
+                Err(_) => Some("public, max-age=31536000, immutable".to_string()),
+            },
+            multipart_threshold_bytes: number("MULTIPART_THRESHOLD_MB", 100).max(5) * 1024 * 1024,
+            upload_verify: env_any(&["UPLOAD_VERIFY"]).is_some_and(|v| v == "true"),
+            max_concurrent_uploads: positive("MAX_CONCURRENT_UPLOADS", 4) as usize,
+            temp_dir: cli.temp_dir.clone().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(env::temp_dir),
+            webhook_url: env_any(&["WEBHOOK_URL"]),
+            notify_webhook_url: env_any(&["NOTIFY_WEBHOOK_URL"]),
+            local_input_dir: cli.local_input_dir.clone().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| PathBuf::from("assets")),
+            local_output_dir: cli.local_output_dir.clone().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| PathBuf::from("assets/watermarked")),
+            lock_ttl_seconds: env_any(&["LOCK_TTL_SECONDS"]).and_then(|v| v.trim().parse().ok()).filter(|v| *v > 0).unwrap_or(3600),
+            max_dimension: DEFAULT_PREVIEW_SIZE,
+            jpeg_quality: PREVIEW_JPEG_QUALITY,
+            fullsize_jpeg_quality: env_any(&["FULLSIZE_JPEG_QUALITY"])
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(DEFAULT_FULLSIZE_JPEG_QUALITY),
+            jpeg_progressive: jpeg_progressive(),
+            jpeg_subsampling: JpegSubsampling::from_env(),
+            max_image_bytes,
+            fullsize_max_pixels: number("FULLSIZE_MAX_PIXELS", 50_000_000),
+            gif_max_frames: number("GIF_MAX_FRAMES", 300) as usize,
+            gif_max_total_pixels: number("GIF_MAX_TOTAL_PIXELS", 100_000_000),
+            zip_limits: ZipLimits {
+                max_entries: number("ZIP_MAX_ENTRIES", 500) as usize,
+                max_total_bytes: number("ZIP_MAX_TOTAL_MB", 1024) * 1024 * 1024,
+                max_entry_bytes: max_image_bytes,
+            },
+            video_output_format: cli_value(&cli.video_output_format).and_then(VideoOutputFormat::parse).unwrap_or(VideoOutputFormat::Mp4),
+            #[cfg(feature = "video")]
+            video: VideoSettings::from_env(cli),
+            reprocess_changed: env_any(&["REPROCESS_CHANGED"]).is_some_and(|v| v == "true"),
+            process_since,
+            preview_sizes,
+            disable_resize: env_any(&["DISABLE_RESIZE"]).is_some_and(|v| v == "true"),
+            fullsize_watermark: env_any(&["FULLSIZE_WATERMARK"]).is_some_and(|v| v == "true"),
+            emit_sidecar: env_any(&["EMIT_SIDECAR"]).is_some_and(|v| v == "true"),
+            process_zip: env_any(&["PROCESS_ZIP"]).is_some_and(|v| v == "true"),
+            archive_originals: env_any(&["ARCHIVE_ORIGINALS"]).is_some_and(|v| v == "true"),
+            cleanup_orphans: env_any(&["CLEANUP_ORPHANS"]).is_some_and(|v| v == "true"),
+            cleanup_dry_run: env_any(&["CLEANUP_DRY_RUN"]).is_some_and(|v| v == "true"),
+            preview_format: PreviewFormat::from_env(),
+            media_types: MediaTypes::from_env(),
+            branding: Branding::from_env().with_logo_cache(BUNDLED_LOGO_PATH, &logo_cache_sizes),
+        })
+    }
+
+    /// Max dimension to resize a preview of `size` to; unbounded with `DISABLE_RESIZE`
+    fn resize_limit(&self, size: u32) -> u32 {
+        if self.disable_resize {
+            FULL_RESOLUTION
+        } else {
+            size
+        }
+    }
+
+    /// JPEG quality for an image output of `size`: the full-resolution copy gets its own
+    fn jpeg_quality_for(&self, size: u32) -> u8 {
+        if size == FULL_RESOLUTION {
+            self.fullsize_jpeg_quality
+        } else {
+            self.jpeg_quality
+        }
+    }
+}
+
+/// Credentials are redacted so the config can be logged
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redacted = |value: &Option<String>| value.as_ref().map(|_| "<redacted>");
+        let mut debug = f.debug_struct("Config");
+        debug
+            .field("bucket", &self.bucket)
+            .field("provider", &self.provider)
+            .field("region", &self.region)
+            .field("endpoint", &self.endpoint)
+            .field("access_key", &self.access_key)
+            .field("secret_key", &redacted(&self.secret_key))
+            .field("session_token", &redacted(&self.session_token))
+            .field("force_path_style", &self.force_path_style)
+            .field("accept_invalid_certs", &self.accept_invalid_certs)
+            .field("interval_minutes", &self.interval_minutes)
+            .field("max_files_per_cycle", &self.max_files_per_cycle)
+            .field("max_concurrent_files", &self.max_concurrent_files)
+            .field("process_order", &self.process_order)
+            .field("user_allowlist", &self.user_allowlist)
+            .field("user_denylist", &self.user_denylist)
+            .field("user_list_file", &self.user_list_file)
+            .field("flat_layout", &self.flat_layout)
+            .field("user_prefix", &self.user_prefix)
+            .field("user_id_pattern", &self.user_id_pattern)
+            .field("originals_folder", &self.originals_folder)
+            .field("watermarks_folder", &self.watermarks_folder)
+            .field("fullsize_folder", &self.fullsize_folder)
+            .field("archive_folder", &self.archive_folder)
+            .field("dest_key_pattern", &self.dest_key_pattern)
+            .field("enable_lock", &self.enable_lock)
+            .field("lock_ttl_seconds", &self.lock_ttl_seconds)
+            .field("log_timings", &self.log_timings)
+            .field("min_retry_seconds", &self.min_retry_seconds)
+            .field("max_retry_seconds", &self.max_retry_seconds)
+            .field("interval_jitter_seconds", &self.interval_jitter_seconds)
+            .field("throttle_retries", &self.throttle_retries)
+            .field("download_timeout", &self.download_timeout)
+            .field("download_retries", &self.download_retries)
+            .field("cache_control", &self.cache_control)
+            .field("multipart_threshold_bytes", &self.multipart_threshold_bytes)
+            .field("upload_verify", &self.upload_verify)
+            .field("max_concurrent_uploads", &self.max_concurrent_uploads)
+            .field("temp_dir", &self.temp_dir)
+            .field("webhook_url", &redacted(&self.webhook_url))
+            .field("notify_webhook_url", &redacted(&self.notify_webhook_url))
+            .field("local_input_dir", &self.local_input_dir)
+            .field("local_output_dir", &self.local_output_dir)
+            .field("max_dimension", &self.max_dimension)
+            .field("jpeg_quality", &self.jpeg_quality)
+            .field("fullsize_jpeg_quality", &self.fullsize_jpeg_quality)
+            .field("jpeg_progressive", &self.jpeg_progressive)
+            .field("jpeg_subsampling", &self.jpeg_subsampling)
+            .field("max_image_bytes", &self.max_image_bytes)
+            .field("fullsize_max_pixels", &self.fullsize_max_pixels)
+            .field("gif_max_frames", &self.gif_max_frames)
+            .field("gif_max_total_pixels", &self.gif_max_total_pixels)
+            .field("zip_limits", &self.zip_limits)
+            .field("video_output_format", &self.video_output_format)
+            .field("reprocess_changed", &self.reprocess_changed)
+            .field("process_since", &self.process_since)
+            .field("preview_sizes", &self.preview_sizes)
+            .field("disable_resize", &self.disable_resize)
+            .field("fullsize_watermark", &self.fullsize_watermark)
+            .field("emit_sidecar", &self.emit_sidecar)
+            .field("process_zip", &self.process_zip)
+            .field("archive_originals", &self.archive_originals)
+            .field("cleanup_orphans", &self.cleanup_orphans)
+            .field("cleanup_dry_run", &self.cleanup_dry_run)
+            .field("preview_format", &self.preview_format)
+            .field("media_types", &self.media_types)
+            .field("branding", &self.branding);
+        #[cfg(feature = "video")]
+        debug.field("video", &self.video);
+        debug.finish()
+    }
+}
+
+/// Storage backend selected by `S3_PROVIDER`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum S3Provider {
+    Spaces,
+    Aws,
+    Minio,
+}
+
+impl S3Provider {
+    fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        match env::var("S3_PROVIDER").unwrap_or_default().to_lowercase().as_str() {
+            "" | "spaces" => Ok(S3Provider::Spaces),
+            "aws" => Ok(S3Provider::Aws),
+            "minio" => Ok(S3Provider::Minio),
+            other => Err(format!("Unknown S3_PROVIDER '{}' (expected spaces, aws or minio)", other).into()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            S3Provider::Spaces => "spaces",
+            S3Provider::Aws => "aws",
+            S3Provider::Minio => "minio",
+        }
+    }
+
+    fn default_region(self) -> &'static str {
+        match self {
+            S3Provider::Spaces => "nyc3",
+            S3Provider::Aws | S3Provider::Minio => "us-east-1",
+        }
+    }
+}
+
+/// Whether to use path-style addressing (`S3_FORCE_PATH_STYLE`; defaults to on for MinIO)
+fn force_path_style(provider: S3Provider) -> bool {
+    match env::var("S3_FORCE_PATH_STYLE") {
+        Ok(value) if !value.is_empty() => value == "true" || value == "1",
+        _ => provider == S3Provider::Minio,
+    }
+}
+
+/// A `CliSettings` value, with an empty one (e.g. `INTERVAL_MINUTES=`) treated as unset
+/// like `env_any` does
+fn cli_value(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|v| !v.is_empty())
+}
+
+/// First non-empty value among `vars`
+fn env_any(vars: &[&str]) -> Option<String> {
+    vars.iter().find_map(|var| env::var(var).ok().filter(|v| !v.is_empty()))
+}
+
+/// Why an explicit endpoint URL is unusable, if it is. The scheme and any port are passed
+/// through as given, so both must be spelled out correctly (`https://minio.lan:9443`).
+fn endpoint_problem(endpoint: &str) -> Option<String> {
+    let uri = match endpoint.parse::<http::Uri>() {
+        Ok(uri) => uri,
+        Err(e) => return Some(format!("S3 endpoint '{}' is not a valid URL: {}", endpoint, e)),
+    };
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => return Some(format!("S3 endpoint '{}' must start with http:// or https://", endpoint)),
+    }
+    match uri.authority() {
+        Some(authority) if !authority.host().is_empty() => {}
+        _ => return Some(format!("S3 endpoint '{}' has no host", endpoint)),
+    }
+    if authority_port_is_invalid(&uri) {
+        return Some(format!("S3 endpoint '{}' has an invalid port", endpoint));
+    }
+    None
+}
+
+/// A `:` after the host with no usable port number (`http://minio:`, `http://minio:0`)
+fn authority_port_is_invalid(uri: &http::Uri) -> bool {
+    let Some(authority) = uri.authority() else { return false };
+    let has_port_separator = authority.as_str().rsplit_once(']').map_or(authority.as_str(), |(_, rest)| rest).contains(':');
+    has_port_separator && !matches!(authority.port_u16(), Some(port) if port > 0)
+}
+
+/// Builds the S3 client. Explicit keys (plus an optional session token) are used when
+/// set; otherwise credentials come from the SDK's default chain (env, profile, IMDS).
+async fn build_s3_client(config: &Config) -> Result<Client, Box<dyn std::error::Error>> {
+    let region = Region::new(config.region.clone());
+
+    let mut builder = aws_sdk_s3::config::Builder::new()
+        .behavior_version(BehaviorVersion::latest())
+        .region(region.clone())
+        .force_path_style(config.force_path_style);
+
+    builder = match (&config.access_key, &config.secret_key) {
+        (Some(access_key), Some(secret_key)) => builder.credentials_provider(Credentials::new(
+            access_key,
+            secret_key,
+            config.session_token.clone(),
+            None,
+            config.provider.name(),
+        )),
+        _ => {
+            let chain = aws_config::default_provider::credentials::DefaultCredentialsChain::builder()
+                .region(region)
+                .build()
+                .await;
+            builder.credentials_provider(chain)
+        }
+    };
+
+    if let Some(endpoint_url) = &config.endpoint {
+        builder = builder.endpoint_url(endpoint_url);
+    }
+    if config.accept_invalid_certs {
+        builder = builder.http_client(InsecureS3Http::new()?);
+    }
+
+    let s3_config = builder.build();
+
+    let client = Client::from_conf(s3_config);
+
+    Ok(client)
+}
+
+/// S3 transport for `S3_ACCEPT_INVALID_CERTS=true`: hyper over rustls with certificate
+/// verification turned off. Handshake signatures are still checked, so the connection is
+/// encrypted, just not authenticated.
+#[derive(Debug, Clone)]
+struct InsecureS3Http {
+    client: hyper_util::client::legacy::Client<
+        hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>,
+        SdkBody,
+    >,
+}
+
+impl InsecureS3Http {
+    fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyServerCert(provider)))
+            .with_no_client_auth();
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(https);
+        Ok(Self { client })
+    }
+}
+
+impl aws_smithy_runtime_api::client::http::HttpConnector for InsecureS3Http {
+    fn call(&self, request: aws_smithy_runtime_api::client::orchestrator::HttpRequest) -> HttpConnectorFuture {
+        let client = self.client.clone();
+        HttpConnectorFuture::new(async move {
+            let request = request.try_into_http1x().map_err(|e| ConnectorError::other(e.into(), None))?;
+            let response = client.request(request).await.map_err(|e| {
+                if e.is_connect() { ConnectorError::io(e.into()) } else { ConnectorError::other(e.into(), None) }
+            })?;
+            let (parts, body) = response.into_parts();
+            let response = http::Response::from_parts(parts, SdkBody::from_body_1_x(body));
+            HttpResponse::try_from(response)
+                .map_err(|e| ConnectorError::other(e.into(), None))
+        })
+    }
+}
+
+impl aws_smithy_runtime_api::client::http::HttpClient for InsecureS3Http {
+    fn http_connector(&self, _settings: &HttpConnectorSettings, _components: &RuntimeComponents) -> SharedHttpConnector {
+        SharedHttpConnector::new(self.clone())
+    }
+}
+
+#[derive(Debug)]
+struct AcceptAnyServerCert(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Longest wait honored from a `Retry-After` header
+const MAX_THROTTLE_DELAY: Duration = Duration::from_secs(300);
+
+/// How long to back off before retry `attempt` (1-based) of a throttled request, or
+/// `None` if the failure isn't throttling: Spaces' `503 SlowDown`, HTTP 429 or the
+/// throttling error codes. A `Retry-After` in seconds wins; otherwise the wait starts at
+/// 5s and doubles, plus up to a second of jitter so replicas don't retry in lockstep.
+fn throttle_delay(code: Option<&str>, status: Option<u16>, retry_after: Option<&str>, attempt: u32) -> Option<Duration> {
+    let throttled = matches!(code, Some("SlowDown" | "Throttling" | "ThrottlingException" | "TooManyRequests" | "RequestLimitExceeded"))
+        || status == Some(429);
+    if !throttled {
+        return None;
+    }
+    if let Some(seconds) = retry_after.and_then(|v| v.trim().parse::<u64>().ok()) {
+        return Some(Duration::from_secs(seconds).min(MAX_THROTTLE_DELAY));
+    }
+    Some(Duration::from_secs(5u64 << (attempt - 1).min(5)) + Duration::from_millis(fastrand::u64(0..1000)))
+}
+
+/// Runs an S3 request, retrying it with `throttle_delay` backoff up to `THROTTLE_RETRIES`
+/// times while the provider throttles. The SDK's own quick retries come first; these wait
+/// much longer. Other errors are returned as they are.
+async fn with_throttle_retry<T, E, F, Fut>(config: &Config, what: &str, mut request: F) -> Result<T, SdkError<E, HttpResponse>>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, SdkError<E, HttpResponse>>>,
+    E: ProvideErrorMetadata,
+{
+    let mut attempt = 1;
+    loop {
+        let error = match request().await {
+            Ok(output) => return Ok(output),
+            Err(error) => error,
+        };
+        let response = error.raw_response();
+        let delay = throttle_delay(
+            error.code(),
+            response.map(|response| response.status().as_u16()),
+            response.and_then(|response| response.headers().get("retry-after")),
+            attempt,
+        );
+        match delay {
+            Some(delay) if attempt <= config.throttle_retries => {
+                log_error!(
+                    "🐢 Storage is throttling ({}), retrying {} in {:.1}s (attempt {}/{})",
+                    error.code().unwrap_or("HTTP 429"),
+                    what,
+                    delay.as_secs_f64(),
+                    attempt,
+                    config.throttle_retries
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return Err(error),
+        }
+    }
+}
+
+async fn discover_user_ids(config: &Config, client: &Client) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // List objects under users/ with delimiter to get user directories
+    let user_prefix = &config.user_prefix;
+    let objects = with_throttle_retry(config, "list users", || {
+        client.list_objects_v2().bucket(&config.bucket).prefix(user_prefix).delimiter("/").send()
+    })
+    .await?;
+
+    let mut user_ids = Vec::new();
+
+    // Check common prefixes (directories)
+    for prefix in objects.common_prefixes() {
+        if let Some(prefix_str) = prefix.prefix() {
+            // Extract user ID from "users/{userId}/"
+            if let Some(user_id) = prefix_str.strip_prefix(user_prefix.as_str()) {
+                let user_id = user_id.trim_end_matches('/');
+                if user_id.is_empty() {
+                    continue;
+                }
+                if !is_valid_user_id(config, user_id) {
+                    log!("   ⏭️  Ignoring directory not matching USER_ID_PATTERN: {}", user_id);
+                    continue;
+                }
+                user_ids.push(user_id.to_string());
+            }
+        }
+    }
+
+    log!("🔍 Discovered {} user directories", user_ids.len());
+    for user_id in &user_ids {
+        log!("   👤 {}", user_id);
+    }
+
+    Ok(user_ids)
+}
+
+async fn discover_event_ids(config: &Config, client: &Client, user_id: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    // List objects under users/{userId}/events/ with delimiter to get event directories
+    let prefix = format!("{}{}/events/", config.user_prefix, user_id);
+    let objects = with_throttle_retry(config, "list events", || {
+        client.list_objects_v2().bucket(&config.bucket).prefix(&prefix).delimiter("/").send()
+    })
+    .await?;
+
+    let mut event_ids = Vec::new();
+
+    // Check common prefixes (directories)
+    for prefix_obj in objects.common_prefixes() {
+        if let Some(prefix_str) = prefix_obj.prefix() {
+            // Extract event ID from "users/{userId}/events/{eventId}/"
+            if let Some(event_part) = prefix_str.strip_prefix(&prefix) {
+                let event_id = event_part.trim_end_matches('/');
+                if !event_id.is_empty() {
+                    event_ids.push(event_id.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(event_ids)
+}
+
+async fn process_files_in_paths(
+    config: &Config,
+    client: &Client,
+    originals_prefix: &str,
+    watermarks_prefix: &str,
+    branding: &Branding,
+    status: &SharedStatus,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut objects = list_all_objects(config, client, originals_prefix).await?;
+    sort_for_processing(&mut objects, config.process_order);
+    if let Some(since) = &config.process_since {
+        let listed = objects.len();
+        objects.retain(|obj| modified_since(obj.last_modified(), since));
+        log!(
+            "🕒 {} of {} files in {} modified since {}",
+            objects.len(),
+            listed,
+            originals_prefix,
+            since.fmt(DateTimeFormat::DateTime).unwrap_or_default()
+        );
+    }
+
+    let keys = objects.iter().filter_map(|obj| {
+        let Some(key) = obj.key() else {
+            log_error!("⚠️  Skipping listed object without a key");
+            return None;
+        };
+        if key.ends_with('/') { return None; }
+        if is_junk_file(key) {
+            log!("⏭️  Skipping hidden/system file: {}", key);
+            return None;
+        }
+        Some((key, obj.e_tag()))
+    });
+
+    // Files are started lazily, in order, so the cycle limit is checked as each one
+    // begins; a failing file is recorded without stopping the others
+    stream::iter(keys)
+        .map(|(key, etag)| async move {
+            if cycle_limit_reached(config, status) {
+                return;
+            }
+            if let Err(e) = process_one_object(config, client, key, etag, watermarks_prefix, branding, status).await {
+                log_error!("❌ Failed to process {}: {}", key, e);
+                status.write().unwrap().record_failure(key, e.to_string());
+            }
+        })
+        .buffer_unordered(config.max_concurrent_files)
+        .collect::<Vec<()>>()
+        .await;
+
+    if config.cleanup_orphans {
+        cleanup_orphans(config, client, originals_prefix, watermarks_prefix).await?;
+    }
+
+    Ok(())
+}
+
+/// Order in which an event's originals are processed (`PROCESS_ORDER`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProcessOrder {
+    /// Listing order, i.e. lexicographic by key (default)
+    Key,
+    /// Oldest `LastModified` first
+    Oldest,
+    /// Newest `LastModified` first
+    Newest,
+}
+
+impl ProcessOrder {
+    /// Unknown values are rejected by `Config::from_env` before this runs
+    fn from_env() -> Self {
+        match env_any(&["PROCESS_ORDER"]).unwrap_or_default().trim().to_lowercase().as_str() {
+            "oldest" => ProcessOrder::Oldest,
+            "newest" => ProcessOrder::Newest,
+            _ => ProcessOrder::Key,
+        }
+    }
+}
+
+/// Sorts listed originals by upload time for `oldest`/`newest`; the sort is stable, so
+/// objects with equal (or missing) timestamps keep their key order
+fn sort_for_processing(objects: &mut [Object], order: ProcessOrder) {
+    match order {
+        ProcessOrder::Key => {}
+        ProcessOrder::Oldest => objects.sort_by(|a, b| a.last_modified().cmp(&b.last_modified())),
+        ProcessOrder::Newest => objects.sort_by(|a, b| b.last_modified().cmp(&a.last_modified())),
+    }
+}
+
+/// Whether an object falls in the `PROCESS_SINCE` window; objects without a timestamp are
+/// kept rather than silently dropped
+fn modified_since(last_modified: Option<&DateTime>, since: &DateTime) -> bool {
+    last_modified.is_none_or(|modified| modified >= since)
+}
+
+/// Every object under `prefix`, following list pagination; a throttled page is retried
+/// on its own instead of restarting the listing
+async fn list_all_objects(config: &Config, client: &Client, prefix: &str) -> Result<Vec<Object>, Box<dyn std::error::Error>> {
+    let mut objects = Vec::new();
+    let mut continuation_token: Option<String> = None;
+    loop {
+        let page = with_throttle_retry(config, "list objects", || {
+            client
+                .list_objects_v2()
+                .bucket(&config.bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token.clone())
+                .send()
+        })
+        .await?;
+        objects.extend(page.contents().iter().cloned());
+        match page.next_continuation_token() {
+            Some(token) if page.is_truncated() == Some(true) => continuation_token = Some(token.to_string()),
+            _ => return Ok(objects),
+        }
+    }
+}
+
+/// Every key under `prefix`, following list pagination
+async fn list_all_keys(config: &Config, client: &Client, prefix: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let objects = list_all_objects(config, client, prefix).await?;
+    Ok(objects.iter().filter_map(|obj| obj.key()).map(str::to_string).collect())
+}
+
+/// Preview keys an original may have produced, across all sizes and video formats,
+/// so a configuration change never makes a live preview look orphaned
+fn expected_preview_keys(config: &Config, original_key: &str, watermarks_prefix: &str, preview_sizes: Option<&[u32]>) -> Vec<String> {
+    let Some(filename) = original_key.rsplit('/').next() else { return Vec::new() };
+    let Some((base, ext)) = split_filename(filename) else { return Vec::new() };
+
+    let mut keys = vec![
+        dest_key(config, watermarks_prefix, base, &ext, None),
+        poster_key(watermarks_prefix, base),
+        webp_preview_key(watermarks_prefix, base),
+        sidecar_key(config, watermarks_prefix, base, None),
+    ];
+    for size in preview_sizes.unwrap_or_default() {
+        keys.push(sidecar_key(config, watermarks_prefix, base, Some(*size)));
+    }
+    for format in [VideoOutputFormat::Mp4, VideoOutputFormat::Webm] {
+        keys.push(dest_key(config, watermarks_prefix, base, format.extension(), None));
+    }
+    for format in [PreviewFormat::Jpeg, PreviewFormat::Avif] {
+        let image_ext = image_preview_extension(&ext, format);
+        keys.push(dest_key(config, watermarks_prefix, base, image_ext, None));
+        for size in preview_sizes.unwrap_or_default() {
+            keys.push(dest_key(config, watermarks_prefix, base, image_ext, Some(*size)));
+        }
+    }
+    keys
+}
+
+/// Deletes previews whose original no longer exists (`CLEANUP_ORPHANS=true`).
+/// With `CLEANUP_DRY_RUN=true` orphans are only logged.
+async fn cleanup_orphans(
+    config: &Config,
+    client: &Client,
+    originals_prefix: &str,
+    watermarks_prefix: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bucket = config.bucket.as_str();
+    let originals = list_all_keys(config, client, originals_prefix).await?;
+    let expected: std::collections::HashSet<String> = originals
+        .iter()
+        .flat_map(|key| expected_preview_keys(config, key, watermarks_prefix, config.preview_sizes.as_deref()))
+        .collect();
+    // An archive's previews are only known after extracting it; its whole folder is kept
+    let archive_folders: Vec<String> = originals
+        .iter()
+        .filter_map(|key| key.rsplit('/').next().and_then(split_filename))
+        .filter(|(_, ext)| ext == "zip")
+        .map(|(base, _)| zip_output_prefix(watermarks_prefix, base))
+        .collect();
+
+    for key in list_all_keys(config, client, watermarks_prefix).await? {
+        if key.ends_with('/') || expected.contains(&key) || archive_folders.iter().any(|folder| key.starts_with(folder.as_str())) {
+            continue;
+        }
+        if config.cleanup_dry_run {
+            log!("🧹 [dry run] Would delete orphaned preview: {}", key);
+            continue;
+        }
+        match client.delete_object().bucket(bucket).key(&key).send().await {
+            Ok(_) => log!("🧹 Deleted orphaned preview: {}", key),
+            Err(e) => log_error!("⚠️  Failed to delete orphaned preview {}: {}", key, e),
+        }
+    }
+
+    Ok(())
+}
+
+async fn process_one_object(
+    config: &Config,
+    client: &Client,
+    key: &str,
+    listed_etag: Option<&str>,
+    watermarks_prefix: &str,
+    branding: &Branding,
+    status: &SharedStatus,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bucket = config.bucket.as_str();
+    let path = PathBuf::from(key);
+    let Some(filename) = path.file_name().and_then(|name| name.to_str()) else {
+        log_error!("⚠️  Skipping key without a usable filename: {}", key);
+        return Ok(());
+    };
+    let Some((base, ext)) = split_filename(filename) else {
+        log!("❌ Unsupported file type (no extension): {}", filename);
+        return Ok(());
+    };
+
+    if is_raw_extension(&ext) {
+        log!("📷 RAW format (.{}) not supported, please upload JPEG/PNG: {}", ext, filename);
+        status.write().unwrap().raw_skipped.push(key.to_string());
+        return Ok(());
+    }
+
+    if ext == "zip" {
+        if !config.process_zip {
+            log!("⏭️  Skipping ZIP archive (set PROCESS_ZIP=true to watermark its images): {}", filename);
+            return Ok(());
+        }
+        if !config.media_types.images {
+            log!("⏭️  Skipping {} (media type not enabled by MEDIA_TYPES)", filename);
+            return Ok(());
+        }
+        return process_zip_archive(config, client, key, base, listed_etag, watermarks_prefix, branding, status).await;
+    }
+
+    let is_image = matches!(ext.as_str(), "jpg" | "jpeg" | "png" | "avif" | "tif" | "tiff" | "bmp");
+    if ext == "avif" && !cfg!(feature = "avif-decode") {
+        log!("⏭️  Skipping AVIF original (AVIF decoding not compiled in, build with --features avif-decode): {}", filename);
+        return Ok(());
+    }
+    let is_video = matches!(ext.as_str(), "mp4" | "mov" | "webm");
+    if is_video && !cfg!(feature = "video") {
+        log!("⏭️  Skipping video (video support not compiled in, build with --features video): {}", filename);
+        return Ok(());
+    }
+    let is_gif = ext == "gif";
+
+    let media_types = config.media_types;
+    if ((is_image || is_gif) && !media_types.images) || (is_video && !media_types.videos) {
+        log!("⏭️  Skipping {} (media type not enabled by MEDIA_TYPES)", filename);
+        return Ok(());
+    }
+
+    // Video previews are re-muxed, so their extension follows the output container;
+    // image previews follow the preview encoding
+    let preview_format = config.preview_format;
+    let output_ext = if is_video {
+        config.video_output_format.extension()
+    } else if is_image {
+        image_preview_extension(&ext, preview_format)
+    } else {
+        ext.as_str()
+    };
+    let watermark_key = dest_key(config, watermarks_prefix, base, output_ext, None);
+
+    // Images can fan out into several preview sizes; everything else has a single output
+    let mut targets: Vec<(u32, String)> = match config.preview_sizes.as_deref() {
+        Some(sizes) if is_image => sizes
+            .iter()
+            .map(|size| (*size, dest_key(config, watermarks_prefix, base, output_ext, Some(*size))))
+            .collect(),
+        _ => vec![(config.max_dimension, watermark_key.clone())],
+    };
+    // Videos also need their poster; one missing from an older run is made on its own
+    if is_video {
+        targets.push((config.max_dimension, poster_key(watermarks_prefix, base)));
+    }
+    if is_image && config.fullsize_watermark {
+        if let Some(event_prefix) = watermarks_prefix.strip_suffix(&config.watermarks_folder) {
+            targets.push((FULL_RESOLUTION, format!("{}{}{}.{}", event_prefix, config.fullsize_folder, base, output_ext)));
+        }
+    }
+
+    // Check which watermarks already exist, and with REPROCESS_CHANGED whether they were
+    // made from the current version of the original
+    let mut pending = Vec::new();
+    for (size, target_key) in targets {
+        match client.head_object().bucket(bucket).key(&target_key).send().await {
+            Ok(head) if config.reprocess_changed && preview_is_stale(head.metadata(), listed_etag) => {
+                log!("🔄 Original changed since {} was made, re-watermarking", target_key);
+                pending.push((size, target_key));
+            }
+            Ok(head) if config.process_since.as_ref().is_some_and(|since| !modified_since(head.last_modified(), since)) => {
+                log!("🔄 {} predates PROCESS_SINCE, re-watermarking", target_key);
+                pending.push((size, target_key));
+            }
+            Ok(_) => {}
+            Err(_) => pending.push((size, target_key)),
+        }
+    }
+    if pending.is_empty() {
+        log!("⏭️  Skipping already watermarked: {}", filename);
+        return Ok(());
+    }
+    status.write().unwrap().files_attempted += 1;
+    let _timer = FileTimer { status, start: Instant::now() };
+    if config.archive_originals {
+        archive_original(config, client, key).await;
+    }
+
+    log!("📥 Downloading: {}", key);
+    let download_start = Instant::now();
+    let (body, original_etag) = download_original(config, client, key, filename).await?;
+    status.write().unwrap().bytes_downloaded += body.len() as u64;
+    log_stage_time(config, "Download", download_start);
+
+    // Usually an interrupted upload; nothing to decode
+    if body.is_empty() {
+        log!("⏭️  Skipping empty file (0 bytes): {}", filename);
+        status.write().unwrap().empty_skipped.push(key.to_string());
+        return Ok(());
+    }
+
+    match ext.as_str() {
+        "jpg" | "jpeg" | "png" | "avif" | "tif" | "tiff" | "bmp" => {
+            // Skip oversized images before decoding to avoid exhausting memory
+            let file_size_mb = body.len() as f64 / 1024.0 / 1024.0;
+            let max_image_bytes = config.max_image_bytes;
+            if body.len() as u64 > max_image_bytes {
+                log_error!("⚠️  Skipping large image ({:.1}MB, limit {}MB): {}", file_size_mb, max_image_bytes / 1024 / 1024, filename);
+                return Ok(());
+            }
+
+            log!("🖼️  Processing image ({:.1}MB): {}", file_size_mb, filename);
+
+            // For very large images, save to temp file first to avoid memory issues
+            let decode_start = Instant::now();
+            let img = if file_size_mb > 20.0 {
+                log!("📁 Large image detected, using temp file approach");
+                let temp_file = new_temp_file(config, &format!(".{}", ext))?;
+                let temp_path = temp_file.path().to_path_buf();
+                fs::write(&temp_path, &body).await?;
+
+                // Load from file which uses memory-mapped I/O internally
+                match image::open(&temp_path) {
+                    Ok(img) => img,
+                    Err(e) => {
+                        log_error!("❌ Failed to load large image {}: {}", filename, e);
+                        status.write().unwrap().record_failure(key, format!("failed to load large image: {}", e));
+                        return Ok(());
+                    }
+                }
+            } else {
+                match image::load_from_memory(&body) {
+                    Ok(img) => img,
+                    Err(e) => {
+                        log_error!("❌ Failed to decode image {}: {}", filename, e);
+                        status.write().unwrap().record_failure(key, format!("failed to decode image: {}", e));
+                        return Ok(());
+                    }
+                }
+            };
+            log_stage_time(config, "Decode", decode_start);
+
+            // Every preview size is derived from the same decoded original
+            for (max_dimension, target_key) in pending {
+                // The full-resolution copy is a deliverable: no resize and no blur/pixelation
+                let full_resolution = max_dimension == FULL_RESOLUTION;
+                let (width, height) = img.dimensions();
+                if full_resolution && width as u64 * height as u64 > config.fullsize_max_pixels {
+                    log_error!("⚠️  Skipping full-resolution watermark of {}x{} image (FULLSIZE_MAX_PIXELS): {}", width, height, filename);
+                    continue;
+                }
+
+                let resize_start = Instant::now();
+                let resized_img = if full_resolution {
+                    img.clone()
+                } else {
+                    apply_protection(resize_for_preview(&img, config.resize_limit(max_dimension)), branding.settings.protection)
+                };
+                log_stage_time(config, "Resize", resize_start);
+
+                if full_resolution {
+                    log!("🖋️ Watermarking image (full resolution)...");
+                } else {
+                    log!("🖋️ Watermarking image ({}px)...", max_dimension);
+                }
+                let watermark_start = Instant::now();
+                let watermarked = watermark_image(resized_img, branding);
+                log_stage_time(config, "Watermark", watermark_start);
+                let preview_size = watermarked.dimensions();
+
+                let encode_start = Instant::now();
+                let final_bytes = encode_preview(&watermarked, preview_format, config.jpeg_quality_for(max_dimension), config.jpeg_subsampling, config.jpeg_progressive).await?;
+                let final_len = final_bytes.len();
+                log_stage_time(config, "Encode", encode_start);
+
+                log!("📤 Uploading watermarked image ({:.1}MB)...", final_bytes.len() as f64 / 1024.0 / 1024.0);
+                let upload_start = Instant::now();
+                let upload = upload_public(config, client, &target_key, key, original_etag.as_deref(), final_bytes).await;
+                log_stage_time(config, "Upload", upload_start);
+                match upload {
+                    Ok(_) => {
+                        log!("✅ Uploaded: {}", target_key);
+                        status.write().unwrap().bytes_uploaded += final_len as u64;
+                        notify_preview_ready(config, key, &target_key, final_len, "image").await;
+                        // The full-resolution copy is a deliverable, not a preview, and gets none
+                        if config.emit_sidecar && !full_resolution {
+                            let sidecar = sidecar_payload((width, height), preview_size, output_ext, final_len, &branding.for_images(), unix_now());
+                            let sidecar_size = config.preview_sizes.is_some().then_some(max_dimension);
+                            let sidecar_key = sidecar_key(config, watermarks_prefix, base, sidecar_size);
+                            match upload_public(config, client, &sidecar_key, key, original_etag.as_deref(), sidecar.into_bytes()).await {
+                                Ok(_) => log!("🧾 Uploaded sidecar: {}", sidecar_key),
+                                Err(e) => log_error!("⚠️  Failed to upload sidecar {}: {}", sidecar_key, e),
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log_error!("❌ Failed to upload {}: {}", target_key, e);
+                        status.write().unwrap().record_failure(key, format!("failed to upload {}: {}", target_key, e));
+                    }
+                };
+            }
+            status.write().unwrap().files_processed += 1;
+        }
+        "gif" => {
+            let file_size_mb = body.len() as f64 / 1024.0 / 1024.0;
+            if body.len() as u64 > config.max_image_bytes {
+                log_error!("⚠️  Skipping large GIF ({:.1}MB): {}", file_size_mb, filename);
+                return Ok(());
+            }
+
+            log!("🎞️  Watermarking animated GIF ({:.1}MB): {}", file_size_mb, filename);
+            let watermark_start = Instant::now();
+            let gif = watermark_gif(config, &body, config.resize_limit(config.max_dimension), branding);
+            log_stage_time(config, "Watermark", watermark_start);
+            let gif = match gif {
+                Ok(gif) => gif,
+                Err(e) => {
+                    log_error!("❌ Failed to watermark GIF {}: {}", filename, e);
+                    status.write().unwrap().record_failure(key, format!("failed to watermark GIF: {}", e));
+                    return Ok(());
+                }
+            };
+
+            let gif_len = gif.len();
+            let upload_start = Instant::now();
+            let upload = upload_public(config, client, &watermark_key, key, original_etag.as_deref(), gif).await;
+            log_stage_time(config, "Upload", upload_start);
+            match upload {
+                Ok(_) => {
+                    log!("✅ Uploaded: {}", watermark_key);
+                    status.write().unwrap().bytes_uploaded += gif_len as u64;
+                    notify_preview_ready(config, key, &watermark_key, gif_len, "image").await;
+                    status.write().unwrap().files_processed += 1;
+                }
+                Err(e) => {
+                    log_error!("❌ Failed to upload {}: {}", watermark_key, e);
+                    status.write().unwrap().record_failure(key, format!("failed to upload {}: {}", watermark_key, e));
+                }
+            }
+        }
+        #[cfg(feature = "video")]
+        "mp4" | "mov" | "webm" => {
+            // Skip very large videos to avoid resource issues
+            let file_size_mb = body.len() as f64 / 1024.0 / 1024.0;
+            if file_size_mb > config.video.max_mb {
+                log_error!("⚠️  Skipping large video ({}MB): {}", file_size_mb as u32, filename);
+                return Ok(());
+            }
+
+            // A video whose preview exists but whose poster is missing only gets the poster
+            let video_pending = pending.iter().any(|(_, target_key)| *target_key == watermark_key);
+            if video_pending {
+                log!("🎬 Watermarking video ({:.1}MB)...", file_size_mb);
+
+                // Add timeout to prevent hanging
+                let timeout_duration = config.video.timeout;
+                let watermark_start = Instant::now();
+                let watermarked = watermark_video_with_timeout(config, &body, branding).await;
+                log_stage_time(config, "Watermark", watermark_start);
+                let output = match watermarked {
+                    Ok(Ok(output)) => {
+                        log!("✅ Video watermarking completed");
+                        output
+                    },
+                    Ok(Err(e)) => {
+                        log_error!("❌ Failed to watermark video {}: {}", filename, e);
+                        status.write().unwrap().record_failure(key, format!("failed to watermark video: {}", e));
+                        return Ok(());
+                    },
+                    Err(_) => {
+                        log_error!("❌ Video watermarking timed out after {}s: {}", timeout_duration.as_secs(), filename);
+                        status.write().unwrap().record_failure(key, format!("video watermarking timed out after {}s", timeout_duration.as_secs()));
+                        return Ok(());
+                    }
+                };
+
+                let content = match UploadBody::file(output.path()).await {
+                    Ok(content) => content,
+                    Err(e) => {
+                        log_error!("❌ Failed to read watermarked video {}: {}", filename, e);
+                        status.write().unwrap().record_failure(key, format!("failed to read watermarked video: {}", e));
+                        return Ok(());
+                    }
+                };
+                let content_len = content.len() as usize;
+                log!("📤 Uploading watermarked video to: {}", watermark_key);
+                let upload_start = Instant::now();
+                let upload = upload_public(config, client, &watermark_key, key, original_etag.as_deref(), content).await;
+                drop(output);
+                log_stage_time(config, "Upload", upload_start);
+                match upload {
+                    Ok(_) => {
+                        log!("✅ Video upload completed: {}", watermark_key);
+                        status.write().unwrap().bytes_uploaded += content_len as u64;
+                        notify_preview_ready(config, key, &watermark_key, content_len, "video").await;
+                    }
+                    Err(e) => {
+                        log_error!("❌ Failed to upload video {}: {}", watermark_key, e);
+                        status.write().unwrap().record_failure(key, format!("failed to upload {}: {}", watermark_key, e));
+                        return Ok(());
+                    }
+                };
+                status.write().unwrap().files_processed += 1;
+
+                // Optional looping hover preview from the first seconds, watermarked per frame
+                if let Some(webp) = &config.video.webp_preview {
+                    let webp_key = webp_preview_key(watermarks_prefix, base);
+                    log!("🎞️  Building animated WebP preview ({}s at {}fps)...", webp.seconds, webp.fps);
+                    let webp_start = Instant::now();
+                    let preview = video_webp_preview(config, &body, webp, &branding.for_videos()).await;
+                    log_stage_time(config, "WebP preview", webp_start);
+                    match preview {
+                        Ok(preview) => match upload_public(config, client, &webp_key, key, original_etag.as_deref(), preview.clone()).await {
+                            Ok(_) => {
+                                log!("✅ Uploaded WebP preview: {}", webp_key);
+                                status.write().unwrap().bytes_uploaded += preview.len() as u64;
+                            }
+                            Err(e) => {
+                                log_error!("❌ Failed to upload WebP preview {}: {}", webp_key, e);
+                                status.write().unwrap().record_failure(key, format!("failed to upload {}: {}", webp_key, e));
+                            }
+                        },
+                        Err(e) => {
+                            log_error!("❌ Failed to build WebP preview for {}: {}", filename, e);
+                            status.write().unwrap().record_failure(key, format!("failed to build WebP preview: {}", e));
+                        }
+                    }
+                }
+            }
+
+            // Poster frame for video cards, watermarked with the image pipeline
+            let poster_key = poster_key(watermarks_prefix, base);
+            let poster_time = config.video.poster_time;
+            log!("🖼️  Extracting poster frame at {}s...", poster_time);
+            let poster_start = Instant::now();
+            let frame = extract_video_frame(config, &body, poster_time).await;
+            log_stage_time(config, "Poster extract", poster_start);
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    log_error!("❌ Failed to extract poster frame for {}: {}", filename, e);
+                    status.write().unwrap().record_failure(key, format!("failed to extract poster frame: {}", e));
+                    return Ok(());
+                }
+            };
+            let watermarked = watermark_image(resize_for_preview(&frame, config.resize_limit(config.max_dimension)), &branding.for_videos());
+            let poster = encode_preview(&watermarked, PreviewFormat::Jpeg, config.jpeg_quality, config.jpeg_subsampling, config.jpeg_progressive).await?;
+
+            let poster_len = poster.len();
+            match upload_public(config, client, &poster_key, key, original_etag.as_deref(), poster).await {
+                Ok(_) => {
+                    log!("✅ Uploaded poster: {}", poster_key);
+                    status.write().unwrap().bytes_uploaded += poster_len as u64;
+                    if !video_pending {
+                        status.write().unwrap().files_processed += 1;
+                    }
+                }
+                Err(e) => {
+                    log_error!("❌ Failed to upload poster {}: {}", poster_key, e);
+                    status.write().unwrap().record_failure(key, format!("failed to upload {}: {}", poster_key, e));
+                }
+            };
+        }
+        _ => {
+            log!("❌ Unsupported file type: {}", filename);
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Downloads taking longer than this are logged as slow
+const SLOW_DOWNLOAD: Duration = Duration::from_secs(30);
+
+/// Downloads an original as (body, ETag without quotes). The SDK retries the request
+/// itself but not the body stream, so a body that stalls past `DOWNLOAD_TIMEOUT_SECONDS`
+/// or ends short of `Content-Length` is fetched again (`DOWNLOAD_RETRIES`) with a growing
+/// delay.
+async fn download_original(config: &Config, client: &Client, key: &str, filename: &str) -> Result<(Bytes, Option<String>), Box<dyn std::error::Error>> {
+    let (timeout, max_retries) = (config.download_timeout, config.download_retries);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let start = Instant::now();
+        let object = client.get_object().bucket(&config.bucket).key(key).send().await?;
+        let etag = object.e_tag().map(|etag| etag.trim_matches('"').to_string());
+        let expected = object.content_length();
+
+        let error = match tokio::time::timeout(timeout, read_body_with_progress(object, filename)).await {
+            Ok(Ok(body)) if expected.is_some_and(|expected| body.len() as i64 != expected) => {
+                format!("partial body ({} of {} bytes)", body.len(), expected.unwrap_or_default())
+            }
+            Ok(Ok(body)) => {
+                if start.elapsed() > SLOW_DOWNLOAD {
+                    log!("🐢 Slow download: {} took {:.1}s ({:.1}MB)", filename, start.elapsed().as_secs_f64(), body.len() as f64 / 1024.0 / 1024.0);
+                }
+                return Ok((body, etag));
+            }
+            Ok(Err(e)) => format!("body read failed: {}", e),
+            Err(_) => format!("stalled for {}s", timeout.as_secs()),
+        };
+
+        if attempt > max_retries {
+            return Err(format!("download of {} failed after {} attempts: {}", key, attempt, error).into());
+        }
+        let delay = Duration::from_secs(2u64 << (attempt - 1).min(5));
+        log_error!("⚠️  Download of {} failed ({}), retrying in {}s ({}/{})", filename, error, delay.as_secs(), attempt, max_retries);
+        sleep(delay).await;
+    }
+}
+
+/// Objects at least this large log download progress
+const DOWNLOAD_PROGRESS_MIN_BYTES: i64 = 20 * 1024 * 1024;
+
+/// Reads an object body chunk by chunk. Objects of `DOWNLOAD_PROGRESS_MIN_BYTES` or more
+/// log progress against `Content-Length` every 25%, so slow transfers are visible.
+async fn read_body_with_progress(mut object: GetObjectOutput, filename: &str) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let total = object.content_length().unwrap_or(0);
+    if total < DOWNLOAD_PROGRESS_MIN_BYTES {
+        return Ok(object.body.collect().await?.into_bytes());
+    }
+
+    let start = Instant::now();
+    let mut body = Vec::with_capacity(total as usize);
+    let mut logged_quarter = 0;
+    while let Some(chunk) = object.body.try_next().await? {
+        body.extend_from_slice(&chunk);
+        let quarter = download_quarter(body.len() as i64, total);
+        if quarter > logged_quarter && quarter < 4 {
+            logged_quarter = quarter;
+            let mb = body.len() as f64 / 1024.0 / 1024.0;
+            log!(
+                "   📥 {}%: {:.1}/{:.1}MB of {} ({:.1}MB/s)",
+                quarter * 25,
+                mb,
+                total as f64 / 1024.0 / 1024.0,
+                filename,
+                mb / start.elapsed().as_secs_f64().max(0.001)
+            );
+        }
+    }
+    Ok(Bytes::from(body))
+}
+
+/// Completed quarters (0-4) of a download of `total` bytes
+fn download_quarter(received: i64, total: i64) -> i64 {
+    if total <= 0 {
+        return 0;
+    }
+    (received.min(total) * 4) / total
+}
+
+/// Camera RAW extensions photographers commonly upload, which can't be decoded
+const RAW_EXTENSIONS: &[&str] = &[
+    "cr2", "cr3", "crw", "nef", "nrw", "arw", "srf", "sr2", "dng", "raf", "orf", "rw2", "pef", "srw", "x3f", "3fr", "iiq", "rwl",
+];
+
+fn is_raw_extension(ext: &str) -> bool {
+    RAW_EXTENSIONS.contains(&ext)
+}
+
+/// Logs how long a processing stage took when `LOG_TIMINGS=true`, in the same format as
+/// local mode
+fn log_stage_time(config: &Config, stage: &str, start: Instant) {
+    if config.log_timings {
+        log!("   {} time: {:.2}ms", stage, start.elapsed().as_secs_f64() * 1000.0);
+    }
+}
+
+/// Files left behind by OS and sync tools that are never media
+const JUNK_FILENAMES: &[&str] = &["thumbs.db", "ehthumbs.db", "desktop.ini", "icon\r"];
+
+/// Whether a key is a dotfile, lives in a hidden or `__MACOSX` folder, or is a known
+/// system artifact such as `Thumbs.db`
+fn is_junk_file(key: &str) -> bool {
+    key.split('/').any(|part| part.starts_with('.') || part == "__MACOSX")
+        || key
+            .rsplit('/')
+            .next()
+            .is_some_and(|filename| JUNK_FILENAMES.contains(&filename.to_lowercase().as_str()))
+}
+
+/// Splits a filename into its base (everything before the last dot) and lowercased
+/// extension. Returns `None` when there is no extension.
+fn split_filename(filename: &str) -> Option<(&str, String)> {
+    let path = Path::new(filename);
+    let base = path.file_stem()?.to_str()?;
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    Some((base, ext))
+}
+
+/// Key of a video's poster frame: `{watermarks_prefix}{base}-poster.jpg`
+fn poster_key(watermarks_prefix: &str, base: &str) -> String {
+    format!("{}{}-poster.jpg", watermarks_prefix, base)
+}
+
+/// Key of a video's animated hover preview: `{watermarks_prefix}{base}-preview.webp`
+fn webp_preview_key(watermarks_prefix: &str, base: &str) -> String {
+    format!("{}{}-preview.webp", watermarks_prefix, base)
+}
+
+/// Default `DEST_KEY_PATTERN`, giving `{base}-watermark[-{size}].{ext}`
+const DEFAULT_DEST_KEY_PATTERN: &str = "{base}-watermark{size}.{ext}";
+
+/// Reads and validates `DEST_KEY_PATTERN` so a pattern that would produce colliding
+/// keys fails fast. Supported placeholders: `{base}`, `{ext}`, `{uuid}` (the user id)
+/// and `{size}` (`-{px}` for sized previews, empty otherwise).
+fn dest_key_pattern(sized_previews: bool) -> Result<String, Box<dyn std::error::Error>> {
+    let pattern = match env::var("DEST_KEY_PATTERN") {
+        Ok(pattern) if !pattern.is_empty() => pattern,
+        _ => DEFAULT_DEST_KEY_PATTERN.to_string(),
+    };
+    if !pattern.contains("{base}") || !pattern.contains("{ext}") {
+        return Err(format!("DEST_KEY_PATTERN '{}' must contain {{base}} and {{ext}}", pattern).into());
+    }
+    if sized_previews && !pattern.contains("{size}") {
+        return Err(format!("DEST_KEY_PATTERN '{}' must contain {{size}} when PREVIEW_SIZES is set", pattern).into());
+    }
+    if pattern != DEFAULT_DEST_KEY_PATTERN {
+        log!("🏷️  Preview keys use pattern: {}", pattern);
+    }
+    Ok(pattern)
+}
+
+/// Destination key of a preview, rendered from `DEST_KEY_PATTERN` under `watermarks_prefix`
+fn dest_key(config: &Config, watermarks_prefix: &str, base: &str, ext: &str, size: Option<u32>) -> String {
+    let user_id = user_id_from_key(config, watermarks_prefix).unwrap_or_default();
+    let size = size.map(|size| format!("-{}", size)).unwrap_or_default();
+    let name = config
+        .dest_key_pattern
+        .replace("{base}", base)
+        .replace("{ext}", ext)
+        .replace("{uuid}", &user_id)
+        .replace("{size}", &size);
+    format!("{}{}", watermarks_prefix, name)
+}
+
+/// MIME type of an uploaded preview, from its extension
+fn content_type_for(key: &str) -> &'static str {
+    match key.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mov") => "video/quicktime",
+        Some("webp") => "image/webp",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Size of each part of a multipart upload (S3 requires at least 5MB)
+const MULTIPART_PART_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Content of an upload: encoded previews in memory, or a watermarked video left on disk
+/// by FFmpeg, which is read from the file as each request is sent
+enum UploadBody {
+    Memory(Bytes),
+    #[cfg(feature = "video")]
+    File { path: PathBuf, len: u64 },
+}
+
+impl UploadBody {
+    #[cfg(feature = "video")]
+    async fn file(path: &Path) -> std::io::Result<Self> {
+        let len = fs::metadata(path).await?.len();
+        Ok(Self::File { path: path.to_path_buf(), len })
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Self::Memory(bytes) => bytes.len() as u64,
+            #[cfg(feature = "video")]
+            Self::File { len, .. } => *len,
+        }
+    }
+
+    /// `length` bytes starting at `offset`, e.g. one part of a multipart upload
+    async fn stream(&self, offset: u64, length: u64) -> Result<ByteStream, aws_smithy_types::byte_stream::error::Error> {
+        match self {
+            Self::Memory(bytes) => Ok(bytes.slice(offset as usize..(offset + length) as usize).into()),
+            #[cfg(feature = "video")]
+            Self::File { path, .. } => ByteStream::read_from().path(path).offset(offset).length(Length::Exact(length)).build().await,
+        }
+    }
+}
+
+impl From<Vec<u8>> for UploadBody {
+    fn from(body: Vec<u8>) -> Self {
+        Self::Memory(Bytes::from(body))
+    }
+}
+
+/// Uploads a public-read object. With `UPLOAD_VERIFY=true` the object is checked with
+/// a HEAD afterwards and re-uploaded once if its size doesn't match what was sent.
+async fn upload_public(config: &Config, client: &Client, key: &str, original_key: &str, original_etag: Option<&str>, body: impl Into<UploadBody>) -> Result<(), Box<dyn std::error::Error>> {
+    let bucket = config.bucket.as_str();
+    let _permit = acquire_upload_permit(config).await;
+    let body = body.into();
+    put_public(config, client, key, original_key, original_etag, &body).await?;
+    if !config.upload_verify {
+        return Ok(());
+    }
+
+    let expected = body.len() as i64;
+    let stored = stored_content_length(client, bucket, key).await?;
+    if stored == Some(expected) {
+        log!("🔍 Verified upload: {} ({} bytes)", key, expected);
+        return Ok(());
+    }
+
+    log_error!("⚠️  Upload verification failed for {}: expected {} bytes, found {:?}; re-uploading", key, expected, stored);
+    put_public(config, client, key, original_key, original_etag, &body).await?;
+    let stored = stored_content_length(client, bucket, key).await?;
+    if stored != Some(expected) {
+        return Err(format!("upload of {} still mismatched after retry: expected {} bytes, found {:?}", key, expected, stored).into());
+    }
+    log!("🔍 Verified upload after retry: {} ({} bytes)", key, expected);
+    Ok(())
+}
+
+/// Caps how many uploads run at once across all concurrently processed files, separately
+/// from `MAX_CONCURRENT_FILES`
+static UPLOAD_SEMAPHORE: OnceLock<Semaphore> = OnceLock::new();
+
+/// Waits for an upload slot (`MAX_CONCURRENT_UPLOADS`, default 4). Hold the permit until
+/// the upload, and its verification, is done.
+async fn acquire_upload_permit(config: &Config) -> SemaphorePermit<'static> {
+    let semaphore = UPLOAD_SEMAPHORE.get_or_init(|| Semaphore::new(config.max_concurrent_uploads));
+
+    match semaphore.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            log!("⏳ Waiting for a free upload slot (MAX_CONCURRENT_UPLOADS)...");
+            semaphore.acquire().await.expect("upload semaphore is never closed")
+        }
+    }
+}
+
+async fn stored_content_length(client: &Client, bucket: &str, key: &str) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+    let head = client.head_object().bucket(bucket).key(key).send().await?;
+    Ok(head.content_length())
+}
+
+/// User metadata of every preview: `original-key`, `reflexu-version` and, when known,
+/// `original-etag` so a changed original can be detected (`REPROCESS_CHANGED`)
+fn preview_metadata(original_key: &str, original_etag: Option<&str>) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::from([
+        ("original-key".to_string(), original_key.to_string()),
+        ("reflexu-version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+    ]);
+    if let Some(etag) = original_etag {
+        metadata.insert("original-etag".to_string(), etag.to_string());
+    }
+    Some(metadata)
+}
+
+/// Whether an existing preview was made from an older version of its original. A
+/// preview without a stored ETag counts as stale, so it is redone once and then tracked.
+fn preview_is_stale(metadata: Option<&HashMap<String, String>>, original_etag: Option<&str>) -> bool {
+    let Some(original_etag) = original_etag else { return false };
+    match metadata.and_then(|metadata| metadata.get("original-etag")) {
+        Some(stored) => stored != original_etag.trim_matches('"'),
+        None => true,
+    }
+}
+
+/// PUTs a public-read object, switching to a multipart upload above
+/// `MULTIPART_THRESHOLD_MB`. A failed multipart upload is aborted so no orphaned
+/// parts are left behind. The object records its original (key and ETag) and the
+/// worker version as user metadata.
+async fn put_public(config: &Config, client: &Client, key: &str, original_key: &str, original_etag: Option<&str>, body: &UploadBody) -> Result<(), Box<dyn std::error::Error>> {
+    let bucket = config.bucket.as_str();
+    if body.len() <= config.multipart_threshold_bytes {
+        with_throttle_retry(config, "upload", || async {
+            let stream = body.stream(0, body.len()).await.map_err(SdkError::construction_failure)?;
+            client.put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(stream)
+                .acl(ObjectCannedAcl::PublicRead)
+                .set_metadata(preview_metadata(original_key, original_etag))
+                .set_cache_control(config.cache_control.clone())
+                .content_type(content_type_for(key))
+                .send()
+                .await
+        })
+        .await?;
+        return Ok(());
+    }
+
+    let upload = with_throttle_retry(config, "start multipart upload", || {
+        client.create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .acl(ObjectCannedAcl::PublicRead)
+            .set_metadata(preview_metadata(original_key, original_etag))
+            .set_cache_control(config.cache_control.clone())
+            .content_type(content_type_for(key))
+            .send()
+    })
+    .await?;
+    let upload_id = upload.upload_id().ok_or("create_multipart_upload returned no upload id")?;
+
+    match upload_parts(client, bucket, key, upload_id, body).await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            if let Err(abort_err) = client.abort_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                log_error!("⚠️  Failed to abort multipart upload of {}: {}", key, abort_err);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Uploads `body` in `MULTIPART_PART_SIZE` parts; a file body is read one part at a time
+async fn upload_parts(client: &Client, bucket: &str, key: &str, upload_id: &str, body: &UploadBody) -> Result<(), Box<dyn std::error::Error>> {
+    let total_parts = body.len().div_ceil(MULTIPART_PART_SIZE);
+    let mut completed = Vec::with_capacity(total_parts as usize);
+
+    for (index, start) in (0..body.len()).step_by(MULTIPART_PART_SIZE as usize).enumerate() {
+        let part_number = index as i32 + 1;
+        let length = MULTIPART_PART_SIZE.min(body.len() - start);
+        let part = client.upload_part()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(body.stream(start, length).await?)
+            .send()
+            .await?;
+        log!("   📦 Uploaded part {}/{}", part_number, total_parts);
+        completed.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(part.e_tag().map(str::to_string))
+                .build(),
+        );
+    }
+
+    client.complete_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(completed)).build())
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Opacity the built-in watermark look was designed around
+const DEFAULT_WATERMARK_OPACITY: f32 = 0.6;
+
+/// Text, brand name, logo and opacity used for watermarks. Defaults come from the
+/// environment and can be overridden per user via `.reflexu-brand.json`.
+#[derive(Debug, Clone)]
+pub(crate) struct Branding {
+    /// Main watermark text, e.g. the site URL
+    text: String,
+    /// Replaces `text` on images, GIFs and posters (`WATERMARK_TEXT_IMAGE`)
+    image_text: Option<String>,
+    /// Replaces `text` on videos and their posters (`WATERMARK_TEXT_VIDEO`)
+    video_text: Option<String>,
+    /// Short brand name flanking the text in videos
+    brand: String,
+    /// Logo drawn beside the text in images; `None` uses `assets/logo.png`
+    logo: Option<Arc<DynamicImage>>,
+    /// The bundled logo already resized for the configured preview widths; dropped when a
+    /// user brings their own logo
+    logo_cache: Option<Arc<LogoCache>>,
+    /// Overall opacity from 0.0 to 1.0
+    opacity: f32,
+    /// Optional copyright line template, e.g. `© {year} Studio`
+    copyright: Option<String>,
+    /// Layout, colors and protection of the watermark, the same for every user
+    settings: WatermarkSettings,
+}
+
+impl Branding {
+    pub(crate) fn from_env() -> Self {
+        Self {
+            // A literal `\n` in the variable starts a new line, as do real newlines
+            text: env::var("WATERMARK_TEXT")
+                .map(|text| text.replace("\\n", "\n"))
+                .unwrap_or_else(|_| "www.reflexu.com".to_string()),
+            image_text: env::var("WATERMARK_TEXT_IMAGE").ok().filter(|text| !text.is_empty()).map(|text| text.replace("\\n", "\n")),
+            video_text: env::var("WATERMARK_TEXT_VIDEO").ok().filter(|text| !text.is_empty()).map(|text| text.replace("\\n", "\n")),
+            brand: env::var("WATERMARK_BRAND").unwrap_or_else(|_| "REFLEXU".to_string()),
+            logo: None,
+            logo_cache: None,
+            copyright: env::var("WATERMARK_COPYRIGHT").ok().filter(|template| !template.is_empty()),
+            opacity: env::var("WATERMARK_OPACITY")
+                .ok()
+                .and_then(|v| v.parse::<f32>().ok())
+                .unwrap_or(DEFAULT_WATERMARK_OPACITY)
+                .clamp(0.0, 1.0),
+            settings: WatermarkSettings::from_env(),
+        }
+    }
+
+    /// Branding for the image pipeline, with `WATERMARK_TEXT_IMAGE` in place of the text
+    fn for_images(&self) -> Cow<'_, Self> {
+        self.with_media_text(self.image_text.as_ref())
+    }
+
+    /// Branding for the video pipeline, with `WATERMARK_TEXT_VIDEO` in place of the text
+    #[cfg_attr(not(feature = "video"), allow(dead_code))]
+    fn for_videos(&self) -> Cow<'_, Self> {
+        self.with_media_text(self.video_text.as_ref())
+    }
+
+    /// Resolves the text for one media type. Both overrides are cleared in the result, so
+    /// a video poster passed on to `watermark_image` keeps the video text.
+    fn with_media_text(&self, text: Option<&String>) -> Cow<'_, Self> {
+        if self.image_text.is_none() && self.video_text.is_none() {
+            return Cow::Borrowed(self);
+        }
+        Cow::Owned(Self {
+            text: text.unwrap_or(&self.text).clone(),
+            image_text: None,
+            video_text: None,
+            ..self.clone()
+        })
+    }
+
+    /// Precomputes the bundled logo for the widths of images resized to `preview_sizes`,
+    /// so `watermark_image` doesn't resize it for every landscape preview
+    fn with_logo_cache(mut self, logo_path: &str, preview_sizes: &[u32]) -> Self {
+        match image::open(logo_path) {
+            Ok(logo) => self.logo_cache = Some(Arc::new(LogoCache::new(&logo, preview_sizes, &self.settings))),
+            Err(e) => log_error!("⚠️  Could not precompute logo from {}: {}", logo_path, e),
+        }
+        self
+    }
+
+    /// Copyright line with `{year}` filled in from the clock, so it rolls over on
+    /// long-running workers
+    fn copyright_line(&self) -> Option<String> {
+        let template = self.copyright.as_ref()?;
+        Some(template.replace("{year}", &current_year()))
+    }
+
+    /// Scales an element's designed opacity by the configured overall opacity
+    fn scale_opacity(&self, designed: f32) -> f32 {
+        (designed * self.opacity / DEFAULT_WATERMARK_OPACITY).clamp(0.0, 1.0)
+    }
+
+    /// Applies the overrides of a `.reflexu-brand.json` document: `text`, `brand`,
+    /// `logo_key` and `opacity`, all optional. Returns the logo key to fetch, if any.
+    fn apply_overrides(&mut self, json: &[u8]) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let mut tokens = aws_smithy_json::deserialize::json_token_iter(json).peekable();
+        let Document::Object(fields) = aws_smithy_json::deserialize::token::expect_document(&mut tokens)? else {
+            return Err("brand config must be a JSON object".into());
+        };
+
+        let mut logo_key = None;
+        for (name, value) in fields {
+            match (name.as_str(), value) {
+                ("text", Document::String(text)) => {
+                    // A user's own text applies to every media type
+                    self.text = text;
+                    self.image_text = None;
+                    self.video_text = None;
+                }
+                ("brand", Document::String(brand)) => self.brand = brand,
+                ("logo_key", Document::String(key)) => logo_key = Some(key),
+                ("opacity", Document::Number(opacity)) => self.opacity = (opacity.to_f64_lossy() as f32).clamp(0.0, 1.0),
+                ("copyright", Document::String(copyright)) => self.copyright = Some(copyright).filter(|c| !c.is_empty()),
+                (_, Document::Null) => {}
+                (name, _) => log_error!("⚠️  Ignoring unknown or mistyped brand field '{}'", name),
+            }
+        }
+        Ok(logo_key)
+    }
+}
+
+/// Current UTC year, e.g. "2025"
+fn current_year() -> String {
+    DateTime::from(SystemTime::now())
+        .fmt(DateTimeFormat::DateTime)
+        .map(|timestamp| timestamp.chars().take(4).collect())
+        .unwrap_or_default()
+}
+
+/// Loads `{user_prefix}{user_id}/.reflexu-brand.json` on top of `defaults`. Falls back to
+/// `defaults` when the file is absent or invalid, and to the default logo when `logo_key`
+/// is outside the user's directory, missing, over `MAX_IMAGE_BYTES` or not an image.
+/// Any other storage error is returned, so a transient failure never produces previews
+/// with the wrong branding.
+async fn load_user_branding(config: &Config, client: &Client, user_id: &str, defaults: &Branding) -> Result<Branding, Box<dyn std::error::Error>> {
+    let user_dir = format!("{}{}/", config.user_prefix, user_id);
+    let key = format!("{}.reflexu-brand.json", user_dir);
+    let object = match client.get_object().bucket(&config.bucket).key(&key).send().await {
+        Ok(object) => object,
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(defaults.clone()),
+        Err(e) => return Err(format!("failed to fetch {}: {}", key, aws_smithy_types::error::display::DisplayErrorContext(&e)).into()),
+    };
+    let body = object.body.collect().await.map_err(|e| format!("failed to read {}: {}", key, e))?.into_bytes();
+
+    let mut branding = defaults.clone();
+    let logo_key = match branding.apply_overrides(&body) {
+        Ok(logo_key) => logo_key,
+        Err(e) => {
+            log_error!("⚠️  Invalid brand config {}: {}, using default branding", key, e);
+            return Ok(defaults.clone());
+        }
+    };
+
+    if let Some(logo_key) = logo_key {
+        match load_brand_logo(config, client, &user_dir, &logo_key).await? {
+            Ok(logo) => {
+                branding.logo = Some(Arc::new(logo));
+                branding.logo_cache = None;
+            }
+            Err(e) => log_error!("⚠️  Brand logo {} rejected: {}, using default logo", logo_key, e),
+        }
+    }
+
+    log!("   🎨 Using custom branding for user {}", user_id);
+    Ok(branding)
+}
+
+/// Fetches and decodes a brand logo. The outer error is a storage failure worth retrying;
+/// the inner one is a logo that will never load (outside `user_dir`, missing, too large or
+/// undecodable).
+async fn load_brand_logo(config: &Config, client: &Client, user_dir: &str, logo_key: &str) -> Result<Result<DynamicImage, String>, Box<dyn std::error::Error>> {
+    if !logo_key.starts_with(user_dir) {
+        return Ok(Err(format!("logo_key must be inside {}", user_dir)));
+    }
+    let object = match client.get_object().bucket(&config.bucket).key(logo_key).send().await {
+        Ok(object) => object,
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(Err("no such object".to_string())),
+        Err(e) => return Err(format!("failed to fetch brand logo {}: {}", logo_key, aws_smithy_types::error::display::DisplayErrorContext(&e)).into()),
+    };
+    let max_bytes = config.max_image_bytes;
+    if object.content_length().is_some_and(|len| len as u64 > max_bytes) {
+        return Ok(Err(format!("larger than MAX_IMAGE_BYTES ({} bytes)", max_bytes)));
+    }
+    let body = object.body.collect().await.map_err(|e| format!("failed to read brand logo {}: {}", logo_key, e))?.into_bytes();
+    if body.len() as u64 > max_bytes {
+        return Ok(Err(format!("larger than MAX_IMAGE_BYTES ({} bytes)", max_bytes)));
+    }
+    Ok(image::load_from_memory(&body).map_err(|e| e.to_string()))
+}
+
+/// Max dimension of the preview when `PREVIEW_SIZES` is not set
+const DEFAULT_PREVIEW_SIZE: u32 = 800;
+
+/// Target "size" of the full-resolution watermarked copy (`FULLSIZE_WATERMARK`)
+const FULL_RESOLUTION: u32 = u32::MAX;
+
+/// JPEG quality of previews; very low (25%) to discourage unauthorized use
+const PREVIEW_JPEG_QUALITY: u8 = 25;
+
+/// JPEG quality of the `FULLSIZE_WATERMARK` copy when `FULLSIZE_JPEG_QUALITY` is unset
+const DEFAULT_FULLSIZE_JPEG_QUALITY: u8 = 85;
+
+/// Parses `PREVIEW_SIZES` (e.g. "400,800,1600") into a sorted, de-duplicated list of
+/// max dimensions. Blank entries are ignored; anything else that isn't a positive whole
+/// number is an error.
+fn parse_preview_sizes(raw: &str) -> Result<Vec<u32>, String> {
+    let mut sizes = Vec::new();
+    for size in raw.split(',').map(str::trim).filter(|size| !size.is_empty()) {
+        match size.parse::<u32>() {
+            Ok(size) if size > 0 => sizes.push(size),
+            _ => return Err(format!("PREVIEW_SIZES entries must be positive whole numbers like 400,800,1600, got '{}'", raw)),
+        }
+    }
+    if sizes.is_empty() {
+        return Err(format!("PREVIEW_SIZES '{}' lists no sizes", raw));
+    }
+    sizes.sort_unstable();
+    sizes.dedup();
+    Ok(sizes)
+}
+
+/// Creates a temp file in `TEMP_DIR`. The file is deleted when the returned handle is
+/// dropped.
+fn new_temp_file(config: &Config, suffix: &str) -> std::io::Result<NamedTempFile> {
+    tempfile::Builder::new().suffix(suffix).tempfile_in(&config.temp_dir)
+}
+
+/// Free bytes available to unprivileged users on the filesystem containing `path`
+#[cfg(all(unix, feature = "video"))]
+fn available_disk_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)] // field widths differ between platforms
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(all(not(unix), feature = "video"))]
+fn available_disk_space(_path: &Path) -> std::io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Exponential backoff: `min` after the first failure, doubling up to `max`
+fn retry_delay_seconds(consecutive_failures: u32, min: u64, max: u64) -> u64 {
+    let exponent = consecutive_failures.saturating_sub(1).min(32);
+    min.saturating_mul(1u64 << exponent).min(max)
+}
+
+/// Watermarks every image in a `.zip` original into `{watermarks}/{archive}/`, then
+/// writes an `index.json` listing the previews. The index marks the archive as done, so
+/// it's only written when every image succeeded and a partial run is retried.
+#[allow(clippy::too_many_arguments)]
+async fn process_zip_archive(
+    config: &Config,
+    client: &Client,
+    key: &str,
+    archive_base: &str,
+    listed_etag: Option<&str>,
+    watermarks_prefix: &str,
+    branding: &Branding,
+    status: &SharedStatus,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bucket = config.bucket.as_str();
+    let index_key = format!("{}index.json", zip_output_prefix(watermarks_prefix, archive_base));
+    match client.head_object().bucket(bucket).key(&index_key).send().await {
+        Ok(head) if config.reprocess_changed && preview_is_stale(head.metadata(), listed_etag) => {
+            log!("🔄 Archive changed since {} was made, re-watermarking", index_key);
+        }
+        Ok(_) => {
+            log!("⏭️  Skipping already watermarked archive: {}", key);
+            return Ok(());
+        }
+        Err(_) => {}
+    }
+    status.write().unwrap().files_attempted += 1;
+    let _timer = FileTimer { status, start: Instant::now() };
+    if config.archive_originals {
+        archive_original(config, client, key).await;
+    }
+
+    log!("📥 Downloading archive: {}", key);
+    let (archive, original_etag) = download_original(config, client, key, archive_base).await?;
+    status.write().unwrap().bytes_downloaded += archive.len() as u64;
+
+    let entries = match zip_entries(&archive, &config.zip_limits) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log_error!("❌ Rejected archive {}: {}", key, e);
+            status.write().unwrap().record_failure(key, format!("rejected archive: {}", e));
+            return Ok(());
+        }
+    };
+    let images: Vec<&ZipEntry> = entries.iter().filter(|entry| zip_entry_is_image(&entry.name)).collect();
+    log!("🗜️  Archive {} holds {} images ({} entries)", key, images.len(), entries.len());
+
+    let mut previews = Vec::new();
+    let mut failed = false;
+    for entry in images {
+        let subject = format!("{}:{}", key, entry.name);
+        let Some(target_key) = zip_preview_key(config, watermarks_prefix, archive_base, &entry.name, config.preview_format) else {
+            continue;
+        };
+        let result = match zip_entry_data(&archive, entry) {
+            Ok(data) => image::load_from_memory(&data).map_err(|e| format!("failed to decode image: {}", e)),
+            Err(e) => Err(e.to_string()),
+        };
+        let img = match result {
+            Ok(img) => img,
+            Err(e) => {
+                log_error!("❌ Skipping {}: {}", subject, e);
+                status.write().unwrap().record_failure(&subject, e);
+                failed = true;
+                continue;
+            }
+        };
+
+        log!("🖋️ Watermarking archive entry: {}", entry.name);
+        let resized = apply_protection(resize_for_preview(&img, config.resize_limit(config.max_dimension)), branding.settings.protection);
+        let watermarked = watermark_image(resized, branding);
+        let bytes = encode_preview(&watermarked, config.preview_format, config.jpeg_quality, config.jpeg_subsampling, config.jpeg_progressive).await?;
+        let len = bytes.len();
+        match upload_public(config, client, &target_key, key, original_etag.as_deref(), bytes).await {
+            Ok(_) => {
+                log!("✅ Uploaded: {}", target_key);
+                status.write().unwrap().bytes_uploaded += len as u64;
+                previews.push(target_key);
+            }
+            Err(e) => {
+                log_error!("❌ Failed to upload {}: {}", target_key, e);
+                status.write().unwrap().record_failure(&subject, format!("failed to upload {}: {}", target_key, e));
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        log_error!("⚠️  Archive {} only partly watermarked, it will be retried next cycle", key);
+    } else {
+        let index = zip_index_payload(key, &previews);
+        upload_public(config, client, &index_key, key, original_etag.as_deref(), index.into_bytes()).await?;
+        log!("🧾 Uploaded archive index: {}", index_key);
+    }
+    status.write().unwrap().files_processed += 1;
+    Ok(())
+}
+
+/// Where `ARCHIVE_ORIGINALS` copies an original: the same path below
+/// `users/{uuid}/archive/` (`users/u/archive/events/e/originals/a.jpg`), or below the
+/// archive folder at the bucket root for keys outside the user tree (`FLAT_LAYOUT`)
+fn archive_key(config: &Config, original_key: &str) -> String {
+    let archive = &config.archive_folder;
+    match user_id_from_key(config, original_key) {
+        Some(user_id) => {
+            let user_root = format!("{}{}/", config.user_prefix, user_id);
+            let rest = original_key.strip_prefix(&user_root).unwrap_or(original_key);
+            format!("{}{}{}", user_root, archive, rest)
+        }
+        None => format!("{}{}", archive, original_key),
+    }
+}
+
+/// `x-amz-copy-source` value: the bucket and the percent-encoded key
+fn copy_source(bucket: &str, key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!("{}/{}", bucket, encoded)
+}
+
+/// Copies the original to its `archive_key` inside the storage provider, without
+/// downloading it. The copy stays private; failures are logged and don't stop the preview.
+async fn archive_original(config: &Config, client: &Client, key: &str) {
+    let target = archive_key(config, key);
+    let copied = with_throttle_retry(config, "archive copy", || {
+        client.copy_object().bucket(&config.bucket).key(&target).copy_source(copy_source(&config.bucket, key)).send()
+    })
+    .await;
+    match copied {
+        Ok(_) => log!("🗄️  Archived original to {}", target),
+        Err(e) => log_error!("⚠️  Failed to archive {} to {}: {}", key, target, e),
+    }
+}
+
+/// Folder receiving the previews of archive `archive_base`: `{watermarks}/{archive}/`
+fn zip_output_prefix(watermarks_prefix: &str, archive_base: &str) -> String {
+    format!("{}{}/", watermarks_prefix, archive_base)
+}
+
+/// Whether an archive entry is an image the worker can watermark; folders, hidden files
+/// and `__MACOSX` resource forks are not
+fn zip_entry_is_image(name: &str) -> bool {
+    if name.ends_with('/') || is_junk_file(name) {
+        return false;
+    }
+    let filename = name.rsplit('/').next().unwrap_or(name);
+    split_filename(filename).is_some_and(|(_, ext)| matches!(ext.as_str(), "jpg" | "jpeg" | "png" | "tif" | "tiff" | "bmp"))
+}
+
+/// Preview key of an archive entry, keeping its folders below the archive's folder:
+/// `{watermarks}/{archive}/{dir}/{stem}-watermark.jpg`. Entry names come from untrusted
+/// input, so `.`/`..` and empty components are dropped; `None` if no filename is left.
+fn zip_preview_key(config: &Config, watermarks_prefix: &str, archive_base: &str, entry_name: &str, format: PreviewFormat) -> Option<String> {
+    let parts: Vec<&str> = entry_name
+        .split(['/', '\\'])
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .collect();
+    let (filename, dirs) = parts.split_last()?;
+    let (base, ext) = split_filename(filename)?;
+    let mut prefix = zip_output_prefix(watermarks_prefix, archive_base);
+    for dir in dirs {
+        prefix.push_str(dir);
+        prefix.push('/');
+    }
+    Some(dest_key(config, &prefix, base, image_preview_extension(&ext, format), None))
+}
+
+/// `index.json` of a watermarked archive: the original's key and its preview keys
+fn zip_index_payload(archive_key: &str, previews: &[String]) -> String {
+    let mut out = String::new();
+    let mut object = JsonObjectWriter::new(&mut out);
+    object.key("archive").string(archive_key);
+    let mut list = object.key("previews").start_array();
+    for preview in previews {
+        list.value().string(preview);
+    }
+    list.finish();
+    object.finish();
+    out
+}
+
+/// Zip-bomb guards for `.zip` originals (`ZIP_MAX_ENTRIES`, default 500, and
+/// `ZIP_MAX_TOTAL_MB` of uncompressed data, default 1024). Each image entry is also held
+/// to `MAX_IMAGE_BYTES`.
+#[derive(Debug, Clone)]
+struct ZipLimits {
+    max_entries: usize,
+    max_total_bytes: u64,
+    max_entry_bytes: u64,
+}
+
+/// One file of an archive, as described by its central directory record
+#[derive(Debug, Clone, PartialEq)]
+struct ZipEntry {
+    name: String,
+    /// 0 (stored) or 8 (deflate); others are rejected when the entry is read
+    method: u16,
+    crc32: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    local_header_offset: u64,
+}
+
+fn zip_u16(data: &[u8], offset: usize) -> Result<u16, Box<dyn std::error::Error>> {
+    let bytes = data.get(offset..offset + 2).ok_or("truncated archive")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn zip_u32(data: &[u8], offset: usize) -> Result<u32, Box<dyn std::error::Error>> {
+    let bytes = data.get(offset..offset + 4).ok_or("truncated archive")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Lists an archive's entries from its central directory. The entry count and the
+/// declared uncompressed sizes are checked against `limits` before anything is
+/// decompressed; `zip_entry_data` then refuses to inflate past the declared size.
+/// ZIP64 and encrypted entries are not supported.
+fn zip_entries(archive: &[u8], limits: &ZipLimits) -> Result<Vec<ZipEntry>, Box<dyn std::error::Error>> {
+    const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+    const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+
+    // The end record is 22 bytes plus a comment of up to 64KiB
+    let search_start = archive.len().saturating_sub(22 + u16::MAX as usize);
+    let end = (search_start..archive.len().saturating_sub(21))
+        .rev()
+        .find(|&offset| zip_u32(archive, offset).is_ok_and(|signature| signature == END_OF_CENTRAL_DIRECTORY))
+        .ok_or("not a ZIP archive (no end of central directory)")?;
+    let entry_count = zip_u16(archive, end + 10)? as usize;
+    let directory_offset = zip_u32(archive, end + 16)?;
+    if entry_count == u16::MAX as usize || directory_offset == u32::MAX {
+        return Err("ZIP64 archives are not supported".into());
+    }
+    if entry_count > limits.max_entries {
+        return Err(format!("{} entries, more than ZIP_MAX_ENTRIES ({})", entry_count, limits.max_entries).into());
+    }
+
+    let mut entries = Vec::with_capacity(entry_count);
+    let mut total_bytes = 0u64;
+    let mut offset = directory_offset as usize;
+    for _ in 0..entry_count {
+        if zip_u32(archive, offset)? != CENTRAL_DIRECTORY_HEADER {
+            return Err("corrupt central directory".into());
+        }
+        let flags = zip_u16(archive, offset + 8)?;
+        let name_len = zip_u16(archive, offset + 28)? as usize;
+        let extra_len = zip_u16(archive, offset + 30)? as usize;
+        let comment_len = zip_u16(archive, offset + 32)? as usize;
+        let name = archive.get(offset + 46..offset + 46 + name_len).ok_or("truncated archive")?;
+        let entry = ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: zip_u16(archive, offset + 10)?,
+            crc32: zip_u32(archive, offset + 16)?,
+            compressed_size: zip_u32(archive, offset + 20)? as u64,
+            uncompressed_size: zip_u32(archive, offset + 24)? as u64,
+            local_header_offset: zip_u32(archive, offset + 42)? as u64,
+        };
+        offset += 46 + name_len + extra_len + comment_len;
+
+        if flags & 1 != 0 {
+            return Err(format!("entry {} is encrypted", entry.name).into());
+        }
+        if zip_entry_is_image(&entry.name) && entry.uncompressed_size > limits.max_entry_bytes {
+            return Err(format!("entry {} is {} bytes uncompressed, more than MAX_IMAGE_BYTES", entry.name, entry.uncompressed_size).into());
+        }
+        total_bytes += entry.uncompressed_size;
+        if total_bytes > limits.max_total_bytes {
+            return Err(format!("more than ZIP_MAX_TOTAL_MB ({}MB) uncompressed", limits.max_total_bytes / 1024 / 1024).into());
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Decompresses one entry, reading at most its declared size and checking its CRC
+fn zip_entry_data(archive: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    use std::io::Read;
+    const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+    let header = entry.local_header_offset as usize;
+    if zip_u32(archive, header)? != LOCAL_FILE_HEADER {
+        return Err(format!("corrupt local header for {}", entry.name).into());
+    }
+    let start = header + 30 + zip_u16(archive, header + 26)? as usize + zip_u16(archive, header + 28)? as usize;
+    let compressed = archive
+        .get(start..start + entry.compressed_size as usize)
+        .ok_or_else(|| format!("truncated data for {}", entry.name))?;
+
+    let mut data = Vec::with_capacity(entry.uncompressed_size as usize);
+    match entry.method {
+        0 => data.extend_from_slice(compressed),
+        8 => {
+            // One byte past the declared size is enough to tell that the entry lied
+            flate2::read::DeflateDecoder::new(compressed).take(entry.uncompressed_size + 1).read_to_end(&mut data)?;
+        }
+        method => return Err(format!("unsupported compression method {} for {}", method, entry.name).into()),
+    }
+    if data.len() as u64 != entry.uncompressed_size {
+        return Err(format!("{} doesn't match its declared size", entry.name).into());
+    }
+    if crc32fast::hash(&data) != entry.crc32 {
+        return Err(format!("CRC mismatch for {}", entry.name).into());
+    }
+    Ok(data)
+}
+
+/// Which originals a cycle processes (`MEDIA_TYPES`: `images`, `videos` or `all`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MediaTypes {
+    images: bool,
+    videos: bool,
+}
+
+impl MediaTypes {
+    /// Unknown values are rejected by `Config::from_env` before this runs
+    fn from_env() -> Self {
+        match env_any(&["MEDIA_TYPES"]).unwrap_or_default().trim().to_lowercase().as_str() {
+            "images" => Self { images: true, videos: false },
+            "videos" => Self { images: false, videos: true },
+            _ => Self { images: true, videos: true },
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match (self.images, self.videos) {
+            (true, false) => "images only",
+            (false, true) => "videos only",
+            _ => "images and videos",
+        }
+    }
+}
+
+/// Encoding of image previews (`PREVIEW_FORMAT`: `jpeg` default, or `avif`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PreviewFormat {
+    Jpeg,
+    Avif,
+}
+
+impl PreviewFormat {
+    /// Unknown values, and `avif` without the `avif` cargo feature, are rejected by
+    /// `Config::from_env` before this runs
+    fn from_env() -> Self {
+        match env_any(&["PREVIEW_FORMAT"]).unwrap_or_default().trim().to_lowercase().as_str() {
+            "avif" if cfg!(feature = "avif") => PreviewFormat::Avif,
+            _ => PreviewFormat::Jpeg,
+        }
+    }
+}
+
+/// Chroma subsampling of JPEG previews (`JPEG_SUBSAMPLING`): `4:2:0` (default) halves the
+/// color resolution for smaller files, `4:4:4` keeps colored watermark text crisp
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JpegSubsampling {
+    Ratio420,
+    Ratio444,
+}
+
+impl JpegSubsampling {
+    fn from_env() -> Self {
+        match env_any(&["JPEG_SUBSAMPLING"]).unwrap_or_default().trim() {
+            "4:4:4" | "444" => Self::Ratio444,
+            _ => Self::Ratio420,
+        }
+    }
+}
+
+/// Reads `JPEG_PROGRESSIVE`; falls back to baseline with a warning unless the
+/// `jpegtran` cargo feature is compiled in
+fn jpeg_progressive() -> bool {
+    match env_any(&["JPEG_PROGRESSIVE"]).is_some_and(|v| v == "true") {
+        true if cfg!(feature = "jpegtran") => true,
+        true => {
+            log_error!("⚠️  JPEG_PROGRESSIVE=true but jpegtran support is not compiled in (build with --features jpegtran), writing baseline JPEGs");
+            false
+        }
+        false => false,
+    }
+}
+
+/// Extension of an image preview key: AVIF previews use `.avif`; TIFF and BMP originals
+/// get JPEG previews, so `.jpg`; everything else keeps the original extension
+fn image_preview_extension(ext: &str, format: PreviewFormat) -> &str {
+    match (format, ext) {
+        (PreviewFormat::Avif, _) => "avif",
+        (PreviewFormat::Jpeg, "tif" | "tiff" | "bmp") => "jpg",
+        _ => ext,
+    }
+}
+
+/// Encodes a watermarked preview. JPEG uses `jpeg_quality` and `jpeg_subsampling`,
+/// rewritten as progressive when `jpeg_progressive` is set; AVIF uses a comparably
+/// aggressive quality at a fraction of the size.
+async fn encode_preview(
+    img: &DynamicImage,
+    format: PreviewFormat,
+    jpeg_quality: u8,
+    jpeg_subsampling: JpegSubsampling,
+    jpeg_progressive: bool,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match format {
+        PreviewFormat::Jpeg => {
+            let baseline = encode_jpeg(img, jpeg_quality, jpeg_subsampling)?;
+            Ok(if jpeg_progressive { make_progressive(baseline).await } else { baseline })
+        }
+        #[cfg(feature = "avif")]
+        PreviewFormat::Avif => {
+            use image::ImageEncoder;
+            let rgba = img.to_rgba8();
+            let mut out = Vec::new();
+            image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut out, 8, 40)
+                .write_image(rgba.as_raw(), rgba.width(), rgba.height(), image::ColorType::Rgba8)?;
+            Ok(out)
+        }
+        #[cfg(not(feature = "avif"))]
+        PreviewFormat::Avif => Err("AVIF encoding is not compiled in (build with --features avif)".into()),
+    }
+}
+
+/// Baseline JPEG at `quality` with the given chroma subsampling. Written with
+/// `jpeg-encoder`, since the `image` encoder only produces 4:4:4.
+fn encode_jpeg(img: &DynamicImage, quality: u8, subsampling: JpegSubsampling) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (width, height) = img.dimensions();
+    let (width, height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err(format!("{}x{} exceeds the JPEG size limit of 65535px", width, height).into()),
+    };
+    let mut out = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut out, quality);
+    encoder.set_sampling_factor(match subsampling {
+        JpegSubsampling::Ratio420 => jpeg_encoder::SamplingFactor::R_4_2_0,
+        JpegSubsampling::Ratio444 => jpeg_encoder::SamplingFactor::R_4_4_4,
+    });
+    // Box-averaged like libjpeg rather than the top-left pixel of each 2x2 block
+    encoder.set_chroma_subsampling_method(jpeg_encoder::ChromaSubsamplingMethod::Average);
+    if img.color().has_color() {
+        encoder.encode(img.to_rgb8().as_raw(), width, height, jpeg_encoder::ColorType::Rgb)?;
+    } else {
+        encoder.encode(img.to_luma8().as_raw(), width, height, jpeg_encoder::ColorType::Luma)?;
+    }
+    Ok(out)
+}
+
+/// Set once `jpegtran` has failed, so the warning is logged only once
+#[cfg(feature = "jpegtran")]
+static JPEGTRAN_FAILED: OnceLock<()> = OnceLock::new();
+
+/// Losslessly converts a baseline JPEG to progressive with `jpegtran` (libjpeg-turbo),
+/// since `encode_jpeg` only writes baseline. Runs on the blocking pool so the
+/// subprocess doesn't hold up a runtime worker. Falls back to the baseline bytes if
+/// `jpegtran` is missing or fails.
+#[cfg(feature = "jpegtran")]
+async fn make_progressive(baseline: Vec<u8>) -> Vec<u8> {
+    let input = baseline.clone();
+    let convert = move || -> std::io::Result<Vec<u8>> {
+        let mut child = std::process::Command::new("jpegtran")
+            .args(["-copy", "none", "-optimize", "-progressive"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()?;
+        // Written from a thread so a full stdout pipe can't deadlock the write
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let writer = std::thread::spawn(move || std::io::Write::write_all(&mut stdin, &input));
+        let output = child.wait_with_output()?;
+        writer.join().map_err(|_| std::io::Error::other("jpegtran writer panicked"))??;
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(std::io::Error::other(format!("jpegtran exited with {}", output.status)));
+        }
+        Ok(output.stdout)
+    };
+    let converted = tokio::task::spawn_blocking(convert)
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    match converted {
+        Ok(progressive) => progressive,
+        Err(e) => {
+            if JPEGTRAN_FAILED.set(()).is_ok() {
+                log_error!("⚠️  JPEG_PROGRESSIVE is set but jpegtran failed ({}); writing baseline JPEGs. Install libjpeg-turbo-progs for progressive output.", e);
+            }
+            baseline
+        }
+    }
+}
+
+/// Without the `jpegtran` feature `JPEG_PROGRESSIVE` is turned off at startup, so this
+/// is never asked to convert
+#[cfg(not(feature = "jpegtran"))]
+async fn make_progressive(baseline: Vec<u8>) -> Vec<u8> {
+    baseline
+}
+
+/// Watermarks every frame of an animated GIF and re-encodes it, keeping frame delays
+/// and looping forever. GIFs over `GIF_MAX_FRAMES` or `GIF_MAX_TOTAL_PIXELS` are rejected.
+fn watermark_gif(config: &Config, bytes: &[u8], max_dimension: u32, branding: &Branding) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let decoder = GifDecoder::new(Cursor::new(bytes))?;
+    let (width, height) = decoder.dimensions();
+    let (new_width, new_height) = preview_dimensions(width, height, max_dimension);
+    let frame_pixels = width as u64 * height as u64;
+    let (max_frames, max_total_pixels) = (config.gif_max_frames, config.gif_max_total_pixels);
+
+    let mut frames = Vec::new();
+    for frame in decoder.into_frames() {
+        let frame = frame?;
+        if frames.len() >= max_frames {
+            return Err(format!("GIF has more than {} frames", max_frames).into());
+        }
+        if (frames.len() as u64 + 1) * frame_pixels > max_total_pixels {
+            return Err(format!("GIF exceeds {} decoded pixels", max_total_pixels).into());
+        }
+
+        let delay = frame.delay();
+        let img = DynamicImage::ImageRgba8(frame.into_buffer())
+            .resize_exact(new_width, new_height, imageops::FilterType::Nearest);
+        let watermarked = watermark_image(apply_protection(img, branding.settings.protection), branding).to_rgba8();
+        frames.push(Frame::from_parts(watermarked, 0, 0, delay));
+    }
+    log!("   Watermarked {} GIF frames at {}x{}", frames.len(), new_width, new_height);
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut out, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
+    }
+    Ok(out)
+}
+
+/// Extra obfuscation applied to image previews before the watermark (`PROTECTION_MODE`):
+/// a blur of `BLUR_SIGMA` (default 8.0) or blocks of `PIXELATE_BLOCK_SIZE` (default 16px)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProtectionMode {
+    Watermark,
+    Blur { sigma: f32 },
+    Pixelate { block: u32 },
+}
+
+impl ProtectionMode {
+    fn from_env() -> Self {
+        match env::var("PROTECTION_MODE").unwrap_or_default().trim().to_lowercase().as_str() {
+            "" | "watermark" => Self::Watermark,
+            "blur" => {
+                let sigma = env::var("BLUR_SIGMA")
+                    .ok()
+                    .and_then(|v| v.parse::<f32>().ok())
+                    .filter(|v| *v > 0.0)
+                    .unwrap_or(8.0);
+                Self::Blur { sigma }
+            }
+            "pixelate" => {
+                let block = env::var("PIXELATE_BLOCK_SIZE")
+                    .ok()
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .filter(|v| *v > 0)
+                    .unwrap_or(16);
+                Self::Pixelate { block }
+            }
+            other => {
+                log_error!("⚠️  Unknown PROTECTION_MODE '{}', using watermark only", other);
+                Self::Watermark
+            }
+        }
+    }
+}
+
+/// Blurs or pixelates the preview according to `mode`; the watermark is drawn on top
+/// afterwards
+fn apply_protection(img: DynamicImage, mode: ProtectionMode) -> DynamicImage {
+    match mode {
+        ProtectionMode::Watermark => img,
+        ProtectionMode::Blur { sigma } => img.blur(sigma),
+        ProtectionMode::Pixelate { block } => pixelate(&img, block),
+    }
+}
+
+/// Downscales by `block` and scales back up with Nearest so each block is one flat color
+fn pixelate(img: &DynamicImage, block: u32) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let small = img.resize_exact(
+        width.div_ceil(block).max(1),
+        height.div_ceil(block).max(1),
+        imageops::FilterType::Triangle,
+    );
+    small.resize_exact(width, height, imageops::FilterType::Nearest)
+}
+
+/// Dimensions fitting `width`x`height` inside `max_dimension`, keeping the aspect ratio.
+/// Images that already fit are left alone, and neither side is ever rounded down to 0
+/// (which would make `resize` panic) for extreme aspect ratios.
+fn preview_dimensions(width: u32, height: u32, max_dimension: u32) -> (u32, u32) {
+    if width <= max_dimension && height <= max_dimension {
+        return (width, height);
     }
+    let ratio = max_dimension as f32 / width.max(height) as f32;
+    (((width as f32 * ratio) as u32).max(1), ((height as f32 * ratio) as u32).max(1))
+}
 
-    // Check if we should run once or continuously
-    let run_once_env = env::var("RUN_ONCE").unwrap_or_default();
-    let run_once = run_once_env == "true";
+/// Resizes the image so neither side exceeds `max_dimension`, keeping aspect ratio
+fn resize_for_preview(img: &DynamicImage, max_dimension: u32) -> DynamicImage {
+    let (orig_width, orig_height) = img.dimensions();
 
-    println!("🔧 RUN_ONCE environment variable: '{}' (parsed as: {})", run_once_env, run_once);
+    if orig_width > max_dimension || orig_height > max_dimension {
+        let (new_width, new_height) = preview_dimensions(orig_width, orig_height, max_dimension);
+        log!("📐 Resizing image from {}x{} to {}x{}", orig_width, orig_height, new_width, new_height);
 
-    if run_once {
-        println!("▶️  Running in one-time mode");
-        process_files().await?;
+        // Nearest is the fastest and most memory efficient filter
+        img.resize_exact(new_width, new_height, imageops::FilterType::Nearest)
     } else {
-        // Run continuously with configurable interval
-        let interval_minutes = env::var("INTERVAL_MINUTES")
-            .unwrap_or_else(|_| "30".to_string())
-            .parse::<u64>()
-            .unwrap_or(30);
+        log!("📐 Image size {}x{} is already optimal", orig_width, orig_height);
+        img.clone()
+    }
+}
 
-        println!("🔄 Starting continuous worker (interval: {} minutes)", interval_minutes);
+/// Fraction of the image width the logo/text pattern spans (`WATERMARK_COVERAGE`,
+/// 0.1–1.0, default 0.5)
+fn watermark_coverage() -> f32 {
+    env::var("WATERMARK_COVERAGE")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|v| v.is_finite())
+        .map(|v| v.clamp(0.1, 1.0))
+        .unwrap_or(0.5)
+}
 
-        // Start health check server
-        tokio::spawn(start_health_server());
+/// Logo used when the branding doesn't provide one
+pub(crate) const BUNDLED_LOGO_PATH: &str = "assets/logo.png";
 
-        let mut processing = false;
+/// Spacing between the logo, dash and text elements of a pattern row
+/// (`WATERMARK_ELEMENT_GAP`): whole pixels (`24` or `24px`), or a fraction of the
+/// pattern's available width below 1 (`0.04`). Unset, the gap is derived from the text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ElementGap {
+    Pixels(u32),
+    Fraction(f32),
+}
 
-        loop {
-            if processing {
-                println!("⏭️  Skipping cycle - previous processing still in progress");
-            } else {
-                #[allow(unused_assignments)]
-                {
-                    processing = true;
-                }
-                match process_files().await {
-                    Ok(_) => println!("✅ Processing cycle completed"),
-                    Err(e) => eprintln!("❌ Processing cycle failed: {}", e),
-                }
-                processing = false;
-            }
+impl ElementGap {
+    fn from_env() -> Option<Self> {
+        env::var("WATERMARK_ELEMENT_GAP").ok().and_then(|value| Self::parse(&value))
+    }
 
-            println!("⏳ Waiting {} minutes until next cycle...", interval_minutes);
-            sleep(Duration::from_secs(interval_minutes * 60)).await;
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if let Some(pixels) = value.strip_suffix("px") {
+            return pixels.trim().parse().ok().map(Self::Pixels);
+        }
+        if let Ok(pixels) = value.parse::<u32>() {
+            return Some(Self::Pixels(pixels));
+        }
+        value.parse::<f32>().ok().filter(|fraction| (0.0..1.0).contains(fraction)).map(Self::Fraction)
+    }
+
+    fn pixels(self, available_width: i32) -> i32 {
+        match self {
+            Self::Pixels(pixels) => pixels as i32,
+            Self::Fraction(fraction) => (available_width as f32 * fraction) as i32,
         }
     }
-    
-    Ok(())
 }
 
-async fn process_files() -> Result<(), Box<dyn std::error::Error>> {
+/// Width of the pattern logo on an image `image_width` pixels wide: 4% of it, at least 25px
+fn pattern_logo_width(image_width: u32) -> u32 {
+    (image_width as f32 * 0.04).max(25.0) as u32
+}
 
-    let bucket = "reflexu";
+/// Logo sized for the pattern by `pattern_logo_width`: Lanczos3-resized and, with
+/// `WATERMARK_TINT_LOGO`, tinted
+fn resize_pattern_logo(logo: &DynamicImage, logo_width: u32, settings: &WatermarkSettings) -> RgbaImage {
+    let logo_height = (logo_width as f32 * logo.height() as f32 / logo.width() as f32) as u32;
+    let mut logo_rgba = logo.resize(logo_width, logo_height, imageops::FilterType::Lanczos3).to_rgba8();
+    if settings.tint_logo {
+        tint_logo(&mut logo_rgba, settings.color);
+    }
+    logo_rgba
+}
 
-    // Discover all user IDs under users/
-    let user_ids = discover_user_ids(bucket).await?;
+/// Pattern logos keyed by logo width. Previews as wide as their max dimension (most
+/// landscape photos) hit the cache; other widths are resized per image as before.
+struct LogoCache(HashMap<u32, RgbaImage>);
+
+impl LogoCache {
+    fn new(logo: &DynamicImage, preview_sizes: &[u32], settings: &WatermarkSettings) -> Self {
+        let logos = preview_sizes
+            .iter()
+            .map(|size| pattern_logo_width(*size))
+            .map(|logo_width| (logo_width, resize_pattern_logo(logo, logo_width, settings)))
+            .collect();
+        Self(logos)
+    }
 
-    if user_ids.is_empty() {
-        println!("ℹ️  No user directories found in users/");
-        return Ok(());
+    fn get(&self, logo_width: u32) -> Option<&RgbaImage> {
+        self.0.get(&logo_width)
     }
+}
 
-    println!("👥 Found {} user directories to process", user_ids.len());
+impl std::fmt::Debug for LogoCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut widths: Vec<&u32> = self.0.keys().collect();
+        widths.sort();
+        f.debug_tuple("LogoCache").field(&widths).finish()
+    }
+}
 
-    for user_id in user_ids {
-        println!("👤 Processing user: {}", user_id);
+pub(crate) fn watermark_image(img: DynamicImage, branding: &Branding) -> DynamicImage {
+    watermark_image_with_logo_file(img, &branding.for_images(), BUNDLED_LOGO_PATH, branding.settings.region)
+}
 
-        // Discover all events for this user
-        let event_ids = discover_event_ids(bucket, &user_id).await?;
+/// `watermark_image` with the fallback logo read from `logo_path`; a missing file
+/// switches to the text-only watermark. The pattern rows stay inside `region`.
+fn watermark_image_with_logo_file(img: DynamicImage, branding: &Branding, logo_path: &str, region: WatermarkRegion) -> DynamicImage {
+    let settings = &branding.settings;
+    match settings.style {
+        WatermarkStyle::Corner => return watermark_image_corner(img, branding, logo_path, &settings.corner),
+        WatermarkStyle::TiledLogo => return watermark_image_tiled(img, branding, logo_path, &settings.tile),
+        WatermarkStyle::Pattern => {}
+    }
 
-        if event_ids.is_empty() {
-            println!("   ℹ️  No events found for user {}", user_id);
-            continue;
+    let (width, height) = img.dimensions();
+    let font = watermark_font();
+    let mut rgba: RgbaImage = img.to_rgba8();
+
+    // Calculate watermark element sizes - much more subtle
+    let logo_width = pattern_logo_width(width); // Much smaller logo (4% of width)
+
+    // Use the precomputed logo if this width was expected, else resize the branding logo
+    // or the bundled one
+    let cached_logo = match &branding.logo {
+        None => branding.logo_cache.as_ref().and_then(|cache| cache.get(logo_width)),
+        Some(_) => None,
+    };
+    let resized_logo;
+    let logo_rgba = match cached_logo {
+        Some(logo) => logo,
+        None => {
+            let logo_img = match &branding.logo {
+                Some(logo) => Cow::Borrowed(logo.as_ref()),
+                None => match image::open(logo_path) {
+                    Ok(img) => Cow::Owned(img),
+                    Err(_) => {
+                        log_error!("⚠️  Could not load logo.png, using text-only watermark");
+                        return watermark_image_text_only(img, branding);
+                    }
+                },
+            };
+            resized_logo = resize_pattern_logo(&logo_img, logo_width, &branding.settings);
+            &resized_logo
         }
+    };
+    let logo_height = logo_rgba.height();
 
-        println!("   📅 Found {} events for user {}", event_ids.len(), user_id);
+    // Text settings
+    let text = branding.text.as_str();
+    let text_color = settings.rgba(branding.scale_opacity(150.0 / 255.0));
+    let logo_opacity = branding.scale_opacity(0.7);
+    let font_size = (logo_width as f32 * 0.6).max(10.0); // Smaller font relative to logo
+    let scale = Scale::uniform(font_size);
+    let outline = &settings.outline;
+    let band = settings.band.as_ref();
 
-        for event_id in event_ids {
-            println!("   🎯 Processing event: {}", event_id);
-            let originals_prefix = format!("users/{}/events/{}/originals/", user_id, event_id);
-            let watermarks_prefix = format!("users/{}/events/{}/watermarks/", user_id, event_id);
+    // Calculate text dimensions (the text may span several lines)
+    let (text_width, text_height) = text_block_size(scale, font, text);
+    let text_width = text_width as f32;
+    let dash_width = font_size * 0.3; // Width of dash character
 
-            match process_files_in_paths(bucket, &originals_prefix, &watermarks_prefix).await {
-                Ok(_) => println!("   ✅ Completed processing event {} for user {}", event_id, user_id),
-                Err(e) => {
-                    eprintln!("   ❌ Failed to process event {} for user {}: {}", event_id, user_id, e);
-                    // Continue processing other events
-                    continue;
-                }
-            }
-        }
+    // Calculate pattern dimensions: the pattern spans WATERMARK_COVERAGE of the image width
+    let available_width = (width as f32 * settings.coverage) as i32;
+    let gap = match settings.element_gap {
+        Some(gap) => gap.pixels(available_width),
+        None => ((available_width - (2 * logo_width as i32) - text_width as i32 - (2 * dash_width as i32)) / 6).max(0), // More gaps for dashes
+    };
+    let pattern_width = logo_width as i32 + gap + dash_width as i32 + gap + text_width as i32 + gap + dash_width as i32 + gap + logo_width as i32;
+    if pattern_width > width as i32 {
+        log!("⚠️  Watermark pattern ({}px) wider than image ({}px), using text-only watermark", pattern_width, width);
+        return watermark_image_text_only(img, branding);
     }
 
-    Ok(())
-}
+    // Calculate center positions
+    let center_x = width as i32 / 2;
+    let center_y = height as i32 / 2;
+    let (region_top, region_bottom) = region.bounds(height);
+
+    // Create 5 horizontal lines for better coverage; a partial region spreads them
+    // evenly over its own height instead
+    let (line_spacing, start_y) = if region == WatermarkRegion::Full {
+        let line_spacing = (height as f32 * 0.12) as i32; // Spacing between lines
+        let total_pattern_height = line_spacing * 4; // 4 gaps between 5 lines
+        (line_spacing, center_y - total_pattern_height / 2)
+    } else {
+        let line_spacing = (region_bottom - region_top) / 5;
+        (line_spacing, region_top + line_spacing / 2)
+    };
+    let mut texts_drawn = 0;
 
-async fn discover_user_ids(bucket: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let region = Region::new("nyc3");
-    let endpoint_url = env::var("DO_SPACES_ENDPOINT")
-        .map_err(|_| "DO_SPACES_ENDPOINT environment variable not found")?;
-    let access_key = env::var("DO_SPACES_KEY")
-        .map_err(|_| "DO_SPACES_KEY environment variable not found")?;
-    let secret_key = env::var("DO_SPACES_SECRET")
-        .map_err(|_| "DO_SPACES_SECRET environment variable not found")?;
+    for line in 0..5 {
+        let y = start_y + line * line_spacing;
 
-    let credentials = Credentials::new(access_key, secret_key, None, None, "do-spaces");
+        // Center the pattern horizontally
+        let pattern_start_x = center_x - pattern_width / 2;
 
-    let s3_config = aws_sdk_s3::config::Builder::new()
-        .behavior_version(BehaviorVersion::latest())
-        .region(region)
-        .endpoint_url(endpoint_url)
-        .credentials_provider(credentials)
-        .build();
+        // Contrast band behind the whole row, padded around the tallest element
+        if let Some(band) = band {
+            let padding = (font_size * 0.4) as i32;
+            let band_height = (logo_height as i32).max(text_height) + 2 * padding;
+            let band_top = (y - band_height / 2).max(region_top);
+            let band_bottom = (y + band_height / 2).min(region_bottom);
+            draw_band(
+                &mut rgba,
+                band,
+                (pattern_start_x - padding, band_top),
+                (pattern_width + 2 * padding) as u32,
+                (band_bottom - band_top).max(0) as u32,
+            );
+        }
 
-    let client = Client::from_conf(s3_config);
+        // Draw left logo
+        let left_logo_x = pattern_start_x;
+        let left_logo_y = y - (logo_height as i32 / 2); // Center logo vertically on the line
 
-    // List objects under users/ with delimiter to get user directories
-    let objects = client
-        .list_objects_v2()
-        .bucket(bucket)
-        .prefix("users/")
-        .delimiter("/")
-        .send()
-        .await?;
+        if left_logo_x >= 0 && left_logo_x + logo_width as i32 <= width as i32 &&
+           left_logo_y >= region_top && left_logo_y + logo_height as i32 <= region_bottom {
+            draw_logo(&mut rgba, logo_rgba, left_logo_x, left_logo_y, logo_opacity);
+        }
 
-    let mut user_ids = Vec::new();
+        // Draw left dash
+        let left_dash_x = pattern_start_x + logo_width as i32 + gap;
+        let left_dash_y = y - (font_size as i32 / 2); // Center dash vertically on the line
 
-    // Check common prefixes (directories)
-    for prefix in objects.common_prefixes() {
-        if let Some(prefix_str) = prefix.prefix() {
-            // Extract user ID from "users/{userId}/"
-            if let Some(user_id) = prefix_str.strip_prefix("users/") {
-                let user_id = user_id.trim_end_matches('/');
-                if !user_id.is_empty() {
-                    user_ids.push(user_id.to_string());
-                }
-            }
+        if left_dash_x >= 0 && left_dash_x + dash_width as i32 <= width as i32 &&
+           left_dash_y >= region_top && left_dash_y + font_size as i32 <= region_bottom {
+            draw_outlined_text(&mut rgba, text_color, (left_dash_x, left_dash_y), scale, font, "-", outline);
+        }
+
+        // Draw center text
+        let text_x = pattern_start_x + logo_width as i32 + gap + dash_width as i32 + gap;
+        let text_y = y - (text_height / 2); // Center the text block vertically on the line
+
+        if text_x >= 0 && text_x + text_width as i32 <= width as i32 &&
+           text_y >= region_top && text_y + text_height <= region_bottom {
+            draw_outlined_lines(&mut rgba, text_color, (text_x, text_y), scale, font, text, outline);
+            texts_drawn += 1;
+        }
+
+        // Draw right dash
+        let right_dash_x = pattern_start_x + logo_width as i32 + gap + dash_width as i32 + gap + text_width as i32 + gap;
+        let right_dash_y = y - (font_size as i32 / 2); // Center dash vertically on the line
+
+        if right_dash_x >= 0 && right_dash_x + dash_width as i32 <= width as i32 &&
+           right_dash_y >= region_top && right_dash_y + font_size as i32 <= region_bottom {
+            draw_outlined_text(&mut rgba, text_color, (right_dash_x, right_dash_y), scale, font, "-", outline);
+        }
+
+        // Draw right logo
+        let right_logo_x = pattern_start_x + logo_width as i32 + gap + dash_width as i32 + gap + text_width as i32 + gap + dash_width as i32 + gap;
+        let right_logo_y = y - (logo_height as i32 / 2); // Center logo vertically on the line
+
+        if right_logo_x >= 0 && right_logo_x + logo_width as i32 <= width as i32 &&
+           right_logo_y >= region_top && right_logo_y + logo_height as i32 <= region_bottom {
+            draw_logo(&mut rgba, logo_rgba, right_logo_x, right_logo_y, logo_opacity);
         }
     }
 
-    println!("🔍 Discovered {} user directories", user_ids.len());
-    for user_id in &user_ids {
-        println!("   👤 {}", user_id);
+    // Never hand out a preview without any visible text
+    if texts_drawn == 0 {
+        log!("⚠️  No watermark line fit a {}x{} image, using text-only watermark", width, height);
+        return watermark_image_text_only(img, branding);
     }
 
-    Ok(user_ids)
+    draw_copyright(&mut rgba, branding, font, outline);
+
+    DynamicImage::ImageRgba8(rgba)
 }
 
-async fn discover_event_ids(bucket: &str, user_id: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let region = Region::new("nyc3");
-    let endpoint_url = env::var("DO_SPACES_ENDPOINT")
-        .map_err(|_| "DO_SPACES_ENDPOINT environment variable not found")?;
-    let access_key = env::var("DO_SPACES_KEY")
-        .map_err(|_| "DO_SPACES_KEY environment variable not found")?;
-    let secret_key = env::var("DO_SPACES_SECRET")
-        .map_err(|_| "DO_SPACES_SECRET environment variable not found")?;
+/// Typeface used for watermark text, resolved once at startup
+struct WatermarkFont {
+    font: Font<'static>,
+    /// Custom font file, also handed to FFmpeg so video text matches; `None` for the
+    /// embedded DejaVu Sans Bold
+    #[cfg_attr(not(feature = "video"), allow(dead_code))]
+    path: Option<String>,
+}
 
-    let credentials = Credentials::new(access_key, secret_key, None, None, "do-spaces");
+static WATERMARK_FONT: OnceLock<WatermarkFont> = OnceLock::new();
 
-    let s3_config = aws_sdk_s3::config::Builder::new()
-        .behavior_version(BehaviorVersion::latest())
-        .region(region)
-        .endpoint_url(endpoint_url)
-        .credentials_provider(credentials)
-        .build();
+/// Loads `WATERMARK_FONT_PATH`, falling back to the embedded DejaVu Sans Bold when it is
+/// unset, unreadable or not a valid font
+fn load_watermark_font() -> WatermarkFont {
+    let embedded = || WatermarkFont {
+        font: Font::try_from_bytes(include_bytes!("../fonts/DejaVuSans-Bold.ttf") as &[u8]).unwrap(),
+        path: None,
+    };
+    let path = match env::var("WATERMARK_FONT_PATH") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return embedded(),
+    };
+    match std::fs::read(&path).map(Font::try_from_vec) {
+        Ok(Some(font)) => {
+            log!("🔤 Watermark font: {}", path);
+            WatermarkFont { font, path: Some(path) }
+        }
+        Ok(None) => {
+            log_error!("⚠️  WATERMARK_FONT_PATH '{}' is not a valid font, using embedded DejaVu Sans Bold", path);
+            embedded()
+        }
+        Err(e) => {
+            log_error!("⚠️  Could not read WATERMARK_FONT_PATH '{}': {}, using embedded DejaVu Sans Bold", path, e);
+            embedded()
+        }
+    }
+}
 
-    let client = Client::from_conf(s3_config);
+fn init_watermark_font() {
+    WATERMARK_FONT.get_or_init(load_watermark_font);
+}
 
-    // List objects under users/{userId}/events/ with delimiter to get event directories
-    let prefix = format!("users/{}/events/", user_id);
-    let objects = client
-        .list_objects_v2()
-        .bucket(bucket)
-        .prefix(&prefix)
-        .delimiter("/")
-        .send()
-        .await?;
+fn watermark_font() -> &'static Font<'static> {
+    &WATERMARK_FONT.get_or_init(load_watermark_font).font
+}
 
-    let mut event_ids = Vec::new();
+/// `fontfile=...:` prefix for drawtext when a custom font is configured
+#[cfg(feature = "video")]
+fn ffmpeg_fontfile_option() -> String {
+    match &WATERMARK_FONT.get_or_init(load_watermark_font).path {
+        Some(path) => format!("fontfile={}:", drawtext_escape(path)),
+        None => String::new(),
+    }
+}
 
-    // Check common prefixes (directories)
-    for prefix_obj in objects.common_prefixes() {
-        if let Some(prefix_str) = prefix_obj.prefix() {
-            // Extract event ID from "users/{userId}/events/{eventId}/"
-            if let Some(event_part) = prefix_str.strip_prefix(&prefix) {
-                let event_id = event_part.trim_end_matches('/');
-                if !event_id.is_empty() {
-                    event_ids.push(event_id.to_string());
-                }
+/// Escapes a value for a drawtext option inside an `-vf` filtergraph.
+///
+/// FFmpeg unescapes twice: once when splitting the filtergraph (`\`, `'`, `[`, `]`,
+/// `,`, `;`) and once when splitting the filter's `key=value` options (`\`, `'`, `:`).
+/// `%` is left alone because every drawtext we build sets `expansion=none`.
+#[cfg(feature = "video")]
+fn drawtext_escape(value: &str) -> String {
+    fn escape(value: &str, special: &[char]) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
             }
+            escaped.push(c);
         }
+        escaped
     }
+    let option = escape(value, &['\\', '\'', ':']);
+    escape(&option, &['\\', '\'', '[', ']', ',', ';'])
+}
 
-    Ok(event_ids)
+/// Fonts consulted, in order, for characters the watermark font has no glyph for
+static FALLBACK_FONTS: OnceLock<Vec<Font<'static>>> = OnceLock::new();
+
+/// Loads the comma-separated `WATERMARK_FALLBACK_FONTS` paths, skipping (and logging)
+/// any that can't be read or parsed
+fn fallback_fonts() -> &'static [Font<'static>] {
+    FALLBACK_FONTS.get_or_init(|| {
+        env::var("WATERMARK_FALLBACK_FONTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .filter_map(|path| match std::fs::read(path).map(Font::try_from_vec) {
+                Ok(Some(font)) => {
+                    log!("🔤 Fallback font: {}", path);
+                    Some(font)
+                }
+                Ok(None) => {
+                    log_error!("⚠️  Fallback font '{}' is not a valid font, ignoring it", path);
+                    None
+                }
+                Err(e) => {
+                    log_error!("⚠️  Could not read fallback font '{}': {}, ignoring it", path, e);
+                    None
+                }
+            })
+            .collect()
+    })
 }
 
-async fn process_files_in_paths(bucket: &str, originals_prefix: &str, watermarks_prefix: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// Splits `text` into runs that can each be drawn with a single font: the primary font
+/// where it has the glyph, otherwise the first fallback that does
+fn font_runs<'f>(primary: &'f Font<'f>, text: &str) -> Vec<(&'f Font<'f>, String)> {
+    let mut runs: Vec<(&Font, String)> = Vec::new();
+    for c in text.chars() {
+        let font = if primary.glyph(c).id().0 != 0 {
+            primary
+        } else {
+            fallback_fonts().iter().find(|font| font.glyph(c).id().0 != 0).unwrap_or(primary)
+        };
+        match runs.last_mut() {
+            Some((run_font, run)) if std::ptr::eq(*run_font, font) => run.push(c),
+            _ => runs.push((font, c.to_string())),
+        }
+    }
+    runs
+}
 
-    let region = Region::new("nyc3");
-    let endpoint_url = env::var("DO_SPACES_ENDPOINT")
-        .map_err(|_| "DO_SPACES_ENDPOINT environment variable not found")?;
-    let access_key = env::var("DO_SPACES_KEY")
-        .map_err(|_| "DO_SPACES_KEY environment variable not found")?;
-    let secret_key = env::var("DO_SPACES_SECRET")
-        .map_err(|_| "DO_SPACES_SECRET environment variable not found")?;
+/// Horizontal advance of `text` drawn in a single font
+fn run_advance(scale: Scale, font: &Font, text: &str) -> i32 {
+    font.layout(text, scale, rusttype::point(0.0, 0.0))
+        .last()
+        .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+        .ceil() as i32
+}
 
-    let credentials = Credentials::new(access_key, secret_key, None, None, "do-spaces");
+/// Width of one line of text, including glyphs taken from fallback fonts
+fn text_width(scale: Scale, font: &Font, text: &str) -> i32 {
+    font_runs(font, text).iter().map(|(run_font, run)| run_advance(scale, run_font, run)).sum()
+}
 
-    let s3_config = aws_sdk_s3::config::Builder::new()
-        .behavior_version(BehaviorVersion::latest())
-        .region(region)
-        .endpoint_url(endpoint_url)
-        .credentials_provider(credentials)
-        .build();
+/// `draw_text_mut` that renders glyphs missing from `font` with the fallback fonts
+fn draw_text_runs(canvas: &mut RgbaImage, color: Rgba<u8>, (x, y): (i32, i32), scale: Scale, font: &Font, text: &str) {
+    let mut run_x = x;
+    for (run_font, run) in font_runs(font, text) {
+        draw_text_mut(canvas, color, run_x, y, scale, run_font, &run);
+        run_x += run_advance(scale, run_font, &run);
+    }
+}
 
-    let client = Client::from_conf(s3_config);
+/// Part of the image the repeated pattern is confined to (`WATERMARK_REGION`): `full`
+/// (default), `top` or `bottom` third, or `center-band` for the middle third
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WatermarkRegion {
+    Full,
+    Top,
+    Bottom,
+    CenterBand,
+}
 
-    let objects = client
-        .list_objects_v2()
-        .bucket(bucket)
-        .prefix(originals_prefix)
-        .send()
-        .await?;
+impl WatermarkRegion {
+    fn from_env() -> Self {
+        match env::var("WATERMARK_REGION").unwrap_or_default().trim().to_lowercase().as_str() {
+            "" | "full" => Self::Full,
+            "top" => Self::Top,
+            "bottom" => Self::Bottom,
+            "center-band" | "center" => Self::CenterBand,
+            other => {
+                log_error!("⚠️  Unknown WATERMARK_REGION '{}', covering the full image", other);
+                Self::Full
+            }
+        }
+    }
+
+    /// Vertical pixel range (top inclusive, bottom exclusive) of the region
+    fn bounds(self, height: u32) -> (i32, i32) {
+        let height = height as i32;
+        match self {
+            Self::Full => (0, height),
+            Self::Top => (0, height / 3),
+            Self::Bottom => (height - height / 3, height),
+            Self::CenterBand => (height / 3, height - height / 3),
+        }
+    }
+}
 
-    for obj in objects.contents() {
-            let key = obj.key().unwrap();
-            if key.ends_with('/') { continue; }
+/// Overall watermark layout (`WATERMARK_STYLE`): the repeated pattern, or a single
+/// discreet mark in one corner
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WatermarkStyle {
+    Pattern,
+    Corner,
+    /// The logo alone, repeated over the whole image; videos keep the pattern
+    TiledLogo,
+}
 
-            let path = PathBuf::from(key);
-            let filename = path.file_name().unwrap().to_str().unwrap();
-            let ext = path.extension()
-                .unwrap_or_default()
-                .to_string_lossy()
-                .to_string();
+impl WatermarkStyle {
+    fn from_env() -> Self {
+        match env::var("WATERMARK_STYLE").unwrap_or_default().trim().to_lowercase().as_str() {
+            "corner" => Self::Corner,
+            "tiled-logo" => Self::TiledLogo,
+            _ => Self::Pattern,
+        }
+    }
+}
 
-            let base = filename.trim_end_matches(&format!(".{}", ext));
-            let watermark_key = format!("{}{}-watermark.{}", watermarks_prefix, base, ext);
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
 
-            // Check if watermark already exists
-            if client.head_object().bucket(bucket).key(&watermark_key).send().await.is_ok() {
-                println!("⏭️  Skipping already watermarked: {}", filename);
-                continue;
-            }
+/// Settings of `WATERMARK_STYLE=corner`: `CORNER` (default `bottom-right`),
+/// `CORNER_SIZE` as a fraction of the image width (default 0.12), `CORNER_OPACITY`
+/// (default 0.8 scaled by `WATERMARK_OPACITY`) and `CORNER_TEXT=true` to add the text
+#[derive(Debug, Clone, Copy)]
+struct CornerStyle {
+    corner: Corner,
+    size: f32,
+    opacity: Option<f32>,
+    with_text: bool,
+}
 
-            println!("📥 Downloading: {}", key);
-            let object = client.get_object().bucket(bucket).key(key).send().await?;
-            let body = object.body.collect().await?.into_bytes();
+impl CornerStyle {
+    fn from_env() -> Self {
+        let corner = match env::var("CORNER").unwrap_or_default().trim().to_lowercase().as_str() {
+            "top-left" => Corner::TopLeft,
+            "top-right" => Corner::TopRight,
+            "bottom-left" => Corner::BottomLeft,
+            _ => Corner::BottomRight,
+        };
+        let size = env::var("CORNER_SIZE")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .filter(|v| v.is_finite())
+            .map(|v| v.clamp(0.02, 0.5))
+            .unwrap_or(0.12);
+        let opacity = env::var("CORNER_OPACITY")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|v| v.clamp(0.0, 1.0));
+        let with_text = env::var("CORNER_TEXT").unwrap_or_default() == "true";
+        CornerStyle { corner, size, opacity, with_text }
+    }
 
-            match ext.to_lowercase().as_str() {
-                "jpg" | "jpeg" | "png" => {
-                    let file_size_mb = body.len() as f64 / 1024.0 / 1024.0;
-                    println!("🖼️  Processing image ({:.1}MB): {}", file_size_mb, filename);
+    fn opacity(&self, branding: &Branding) -> f32 {
+        self.opacity.unwrap_or_else(|| branding.scale_opacity(0.8))
+    }
 
-                    // For very large images, save to temp file first to avoid memory issues
-                    let img = if file_size_mb > 20.0 {
-                        println!("📁 Large image detected, using temp file approach");
-                        let temp_file = NamedTempFile::with_suffix(&format!(".{}", ext))?;
-                        let temp_path = temp_file.path().to_path_buf();
-                        fs::write(&temp_path, &body).await?;
+    /// Top-left position of an `item`-sized block in the corner of a `canvas`
+    fn position(&self, canvas: (u32, u32), item: (u32, u32), margin: u32) -> (i32, i32) {
+        let right = canvas.0 as i32 - item.0 as i32 - margin as i32;
+        let bottom = canvas.1 as i32 - item.1 as i32 - margin as i32;
+        let (x, y) = match self.corner {
+            Corner::TopLeft => (margin as i32, margin as i32),
+            Corner::TopRight => (right, margin as i32),
+            Corner::BottomLeft => (margin as i32, bottom),
+            Corner::BottomRight => (right, bottom),
+        };
+        (x.max(0), y.max(0))
+    }
 
-                        // Load from file which uses memory-mapped I/O internally
-                        match image::open(&temp_path) {
-                            Ok(img) => img,
-                            Err(e) => {
-                                eprintln!("❌ Failed to load large image {}: {}", filename, e);
-                                continue;
-                            }
-                        }
-                    } else {
-                        match image::load_from_memory(&body) {
-                            Ok(img) => img,
-                            Err(e) => {
-                                eprintln!("❌ Failed to decode image {}: {}", filename, e);
-                                continue;
-                            }
-                        }
-                    };
-
-                    let (orig_width, orig_height) = img.dimensions();
-
-                    // Resize image to max 800px for preview (lower quality for protection)
-                    let max_dimension = 800u32;
-                    let resized_img = if orig_width > max_dimension || orig_height > max_dimension {
-                        let ratio = if orig_width > orig_height {
-                            max_dimension as f32 / orig_width as f32
-                        } else {
-                            max_dimension as f32 / orig_height as f32
-                        };
-                        let new_width = (orig_width as f32 * ratio) as u32;
-                        let new_height = (orig_height as f32 * ratio) as u32;
-                        println!("📐 Resizing image from {}x{} to {}x{}", orig_width, orig_height, new_width, new_height);
-
-                        // For large images, use a more memory-efficient filter
-                        let filter = if file_size_mb > 20.0 {
-                            imageops::FilterType::Nearest  // Fastest and most memory efficient
-                        } else {
-                            imageops::FilterType::Nearest  // Already using Nearest
-                        };
-                        img.resize_exact(new_width, new_height, filter)
-                    } else {
-                        println!("📐 Image size {}x{} is already optimal", orig_width, orig_height);
-                        img
-                    };
-
-                    println!("🖋️ Watermarking image...");
-                    let watermarked = watermark_image(resized_img, "REFLEXU PREVIEW");
-
-                    let mut buf = Cursor::new(Vec::new());
-                    // Very low JPEG quality (25%) to discourage unauthorized use
-                    watermarked.write_to(&mut buf, image::ImageOutputFormat::Jpeg(25))?;
-                    let final_bytes = buf.into_inner();
-
-                    println!("📤 Uploading watermarked image ({:.1}MB)...", final_bytes.len() as f64 / 1024.0 / 1024.0);
-                    match client.put_object()
-                        .bucket(bucket)
-                        .key(&watermark_key)
-                        .body(final_bytes.into())
-                        .acl(ObjectCannedAcl::PublicRead)
-                        .send()
-                        .await {
-                        Ok(_) => println!("✅ Uploaded: {}", watermark_key),
-                        Err(e) => {
-                            eprintln!("❌ Failed to upload {}: {}", watermark_key, e);
-                            continue;
-                        }
-                    };
-                }
-                "mp4" | "mov" | "webm" => {
-                    // Skip very large videos to avoid resource issues
-                    let file_size_mb = body.len() as f64 / 1024.0 / 1024.0;
-                    if file_size_mb > 300.0 {
-                        eprintln!("⚠️  Skipping large video ({}MB): {}", file_size_mb as u32, filename);
-                        continue;
-                    }
+    /// FFmpeg `x`/`y` expressions placing text in the corner
+    #[cfg(feature = "video")]
+    fn ffmpeg_position(&self) -> (&'static str, &'static str) {
+        match self.corner {
+            Corner::TopLeft => ("w*0.03", "h*0.03"),
+            Corner::TopRight => ("w-tw-w*0.03", "h*0.03"),
+            Corner::BottomLeft => ("w*0.03", "h-th-h*0.03"),
+            Corner::BottomRight => ("w-tw-w*0.03", "h-th-h*0.03"),
+        }
+    }
+}
 
-                    println!("🎬 Watermarking video ({:.1}MB)...", file_size_mb);
+/// Settings of `WATERMARK_STYLE=tiled-logo`: `TILE_SIZE` as a fraction of the image
+/// width (default 0.08), `TILE_SPACING` between tile origins as a fraction of the image
+/// width (default 0.2) and `TILE_OPACITY` (default 0.35 scaled by `WATERMARK_OPACITY`)
+#[derive(Debug, Clone, Copy)]
+struct TileStyle {
+    size: f32,
+    spacing: f32,
+    opacity: Option<f32>,
+}
 
-                    // Add timeout to prevent hanging
-                    let timeout_duration = Duration::from_secs(300); // 5 minutes max
-                    let content = match tokio::time::timeout(timeout_duration, watermark_video(&body, "REFLEXU PREVIEW")).await {
-                        Ok(Ok(v)) => {
-                            println!("✅ Video watermarking completed, size: {} bytes", v.len());
-                            v
-                        },
-                        Ok(Err(e)) => {
-                            eprintln!("❌ Failed to watermark video {}: {}", filename, e);
-                            continue;
-                        },
-                        Err(_) => {
-                            eprintln!("❌ Video watermarking timed out after 5 minutes: {}", filename);
-                            continue;
-                        }
-                    };
-
-                    println!("📤 Uploading watermarked video to: {}", watermark_key);
-                    match client.put_object()
-                        .bucket(bucket)
-                        .key(&watermark_key)
-                        .body(content.into())
-                        .acl(ObjectCannedAcl::PublicRead)
-                        .send()
-                        .await {
-                        Ok(_) => println!("✅ Video upload completed: {}", watermark_key),
-                        Err(e) => {
-                            eprintln!("❌ Failed to upload video {}: {}", watermark_key, e);
-                            continue;
-                        }
-                    };
-                }
-                _ => {
-                    println!("❌ Unsupported file type: {}", filename);
-                    continue;
-                }
+impl TileStyle {
+    fn from_env() -> Self {
+        let fraction = |var: &str, min: f32, max: f32, default: f32| {
+            env::var(var)
+                .ok()
+                .and_then(|v| v.parse::<f32>().ok())
+                .filter(|v| v.is_finite())
+                .map(|v| v.clamp(min, max))
+                .unwrap_or(default)
+        };
+        let size = fraction("TILE_SIZE", 0.02, 0.5, 0.08);
+        // Never closer than the tiles' own width, so they don't overlap
+        let spacing = fraction("TILE_SPACING", 0.02, 1.0, 0.2).max(size);
+        let opacity = env::var("TILE_OPACITY")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .map(|v| v.clamp(0.0, 1.0));
+        TileStyle { size, spacing, opacity }
+    }
+
+    fn opacity(&self, branding: &Branding) -> f32 {
+        self.opacity.unwrap_or_else(|| branding.scale_opacity(0.35))
+    }
+}
+
+/// Maximum-protection watermark: the semi-transparent logo repeated on a grid over the
+/// whole image, every other row shifted by half a step so no clean strip is left to crop.
+/// Without a logo it falls back to the text-only watermark.
+fn watermark_image_tiled(img: DynamicImage, branding: &Branding, logo_path: &str, style: &TileStyle) -> DynamicImage {
+    let logo = match &branding.logo {
+        Some(logo) => Cow::Borrowed(logo.as_ref()),
+        None => match image::open(logo_path) {
+            Ok(logo) => Cow::Owned(logo),
+            Err(_) => {
+                log_error!("⚠️  Could not load logo.png, using text-only watermark");
+                return watermark_image_text_only(img, branding);
+            }
+        },
+    };
+
+    let (width, height) = img.dimensions();
+    let mut rgba: RgbaImage = img.to_rgba8();
+    let logo_width = ((width as f32 * style.size) as u32).clamp(8, width.max(8));
+    let logo = resize_pattern_logo(&logo, logo_width, &branding.settings);
+    let opacity = style.opacity(branding);
+
+    let step = ((width as f32 * style.spacing) as i32).max(logo_width as i32);
+    let (logo_w, logo_h) = (logo.width() as i32, logo.height() as i32);
+    // Start half a step outside the canvas so partial tiles cover the edges too
+    for (row, y) in (-step / 2..height as i32).step_by(step as usize).enumerate() {
+        let shift = if row % 2 == 1 { step / 2 } else { 0 };
+        for x in (-step + shift..width as i32).step_by(step as usize) {
+            if x + logo_w > 0 && y + logo_h > 0 {
+                draw_logo(&mut rgba, &logo, x, y, opacity);
             }
         }
+    }
 
-    Ok(())
+    draw_copyright(&mut rgba, branding, watermark_font(), &branding.settings.outline);
+    DynamicImage::ImageRgba8(rgba)
 }
 
-fn watermark_image(img: DynamicImage, _text: &str) -> DynamicImage {
+/// Minimal watermark: the logo (plus the text if enabled, or the text alone when no
+/// logo is available) in a single corner
+fn watermark_image_corner(img: DynamicImage, branding: &Branding, logo_path: &str, style: &CornerStyle) -> DynamicImage {
     let (width, height) = img.dimensions();
-    let font_data = include_bytes!("../fonts/DejaVuSans-Bold.ttf") as &[u8];
-    let font = Font::try_from_bytes(font_data).unwrap();
+    let font = watermark_font();
     let mut rgba: RgbaImage = img.to_rgba8();
+    let opacity = style.opacity(branding);
+    let outline = &branding.settings.outline;
 
-    // Load the logo image
-    let logo_img = match image::open("assets/logo.png") {
-        Ok(img) => img,
-        Err(_) => {
-            eprintln!("⚠️  Could not load logo.png, using text-only watermark");
-            return watermark_image_text_only(img, "www.reflexu.com");
-        }
+    let logo = match &branding.logo {
+        Some(logo) => Some(logo.as_ref().clone()),
+        None => image::open(logo_path).ok(),
     };
+    let logo_width = ((width as f32 * style.size) as u32).clamp(8, width.max(8));
+    let logo_rgba = logo.map(|logo| {
+        let logo_height = ((logo_width as f32 * logo.height() as f32 / logo.width() as f32) as u32).max(1);
+        let mut logo = logo.resize(logo_width, logo_height, imageops::FilterType::Lanczos3).to_rgba8();
+        if branding.settings.tint_logo {
+            tint_logo(&mut logo, branding.settings.color);
+        }
+        logo
+    });
+
+    let text = (style.with_text || logo_rgba.is_none()).then_some(branding.text.as_str());
+    let scale = Scale::uniform((logo_width as f32 * 0.2).max(10.0));
+    let (text_width, text_height) = text.map_or((0, 0), |text| text_block_size(scale, font, text));
+    let (logo_w, logo_h) = logo_rgba.as_ref().map_or((0, 0), |logo| logo.dimensions());
+    let spacing = if logo_rgba.is_some() && text.is_some() { (scale.y * 0.3) as u32 } else { 0 };
+
+    // Logo and text are stacked and centered on each other inside one block
+    let block = (logo_w.max(text_width.max(0) as u32), logo_h + spacing + text_height.max(0) as u32);
+    let margin = (width.min(height) as f32 * 0.03) as u32;
+    let (x, y) = style.position((width, height), block, margin);
+
+    if let Some(logo) = &logo_rgba {
+        draw_logo(&mut rgba, logo, x + (block.0 - logo_w) as i32 / 2, y, opacity);
+    }
+    if let Some(text) = text {
+        let color = branding.settings.rgba(opacity);
+        let text_x = x + (block.0 as i32 - text_width) / 2;
+        draw_outlined_lines(&mut rgba, color, (text_x, y + (logo_h + spacing) as i32), scale, font, text, outline);
+    }
 
-    // Calculate watermark element sizes - much more subtle
-    let logo_width = (width as f32 * 0.04).max(25.0) as u32; // Much smaller logo (4% of width)
-    let logo_height = (logo_width as f32 * logo_img.height() as f32 / logo_img.width() as f32) as u32;
-
-    // Resize logo to watermark size
-    let resized_logo = logo_img.resize(logo_width, logo_height, imageops::FilterType::Lanczos3);
-    let logo_rgba = resized_logo.to_rgba8();
+    draw_copyright(&mut rgba, branding, font, outline);
+    DynamicImage::ImageRgba8(rgba)
+}
 
-    // Text settings
-    let text = "www.reflexu.com";
-    let font_size = (logo_width as f32 * 0.6).max(10.0); // Smaller font relative to logo
-    let scale = Scale::uniform(font_size);
+/// Height of one line of watermark text, including spacing
+fn line_height(scale: Scale) -> i32 {
+    (scale.y * 1.2).ceil() as i32
+}
 
-    // Calculate text dimensions
-    let text_width = text.len() as f32 * font_size * 0.6; // Approximate text width
-    let dash_width = font_size * 0.3; // Width of dash character
+/// Width of the widest line and total height of `text` split on newlines
+fn text_block_size(scale: Scale, font: &Font, text: &str) -> (i32, i32) {
+    let width = text.lines().map(|line| text_width(scale, font, line)).max().unwrap_or(0);
+    let lines = text.lines().count().max(1) as i32;
+    (width, lines * line_height(scale))
+}
 
-    // Calculate pattern dimensions for subtle coverage
-    // Use only 50% of image width for the watermark (increased for more spacing)
-    let available_width = (width as f32 * 0.5) as i32;
-    let gap = (available_width - (2 * logo_width as i32) - text_width as i32 - (2 * dash_width as i32)) / 6; // More gaps for dashes
-    let pattern_width = logo_width as i32 + gap + dash_width as i32 + gap + text_width as i32 + gap + dash_width as i32 + gap + logo_width as i32;
+/// Draws each line of `text` below the previous one, centered within the widest line,
+/// with the block's top-left corner at `(x, y)`
+fn draw_outlined_lines(
+    canvas: &mut RgbaImage,
+    color: Rgba<u8>,
+    (x, y): (i32, i32),
+    scale: Scale,
+    font: &Font,
+    text: &str,
+    outline: &TextOutline,
+) {
+    let (block_width, _) = text_block_size(scale, font, text);
+    for (index, line) in text.lines().enumerate() {
+        let line_x = x + (block_width - text_width(scale, font, line)) / 2;
+        let line_y = y + index as i32 * line_height(scale);
+        draw_outlined_text(canvas, color, (line_x, line_y), scale, font, line, outline);
+    }
+}
 
-    // Calculate center positions
-    let center_x = width as i32 / 2;
-    let center_y = height as i32 / 2;
+/// Draws the copyright line, if configured, centered near the bottom edge
+fn draw_copyright(canvas: &mut RgbaImage, branding: &Branding, font: &Font, outline: &TextOutline) {
+    let Some(line) = branding.copyright_line() else { return };
+    let (width, height) = canvas.dimensions();
+    let font_size = (width.min(height) as f32 * 0.035).max(10.0);
+    let text_width = (line.chars().count() as f32 * font_size * 0.6) as i32;
+    let x = ((width as i32 - text_width) / 2).max(0);
+    let y = (height as i32 - (font_size * 1.8) as i32).max(0);
+    let color = branding.settings.rgba(branding.scale_opacity(150.0 / 255.0));
+    draw_outlined_text(canvas, color, (x, y), Scale::uniform(font_size), font, &line, outline);
+}
 
-    // Create 5 horizontal lines for better coverage
-    let line_spacing = (height as f32 * 0.12) as i32; // Spacing between lines
-    let total_pattern_height = line_spacing * 4; // 4 gaps between 5 lines
-    let start_y = center_y - total_pattern_height / 2;
+/// Color of watermark text (`WATERMARK_COLOR` hex, default `FFFFFF`), e.g. dark for
+/// bright images
+fn watermark_color() -> [u8; 3] {
+    env::var("WATERMARK_COLOR")
+        .ok()
+        .and_then(|value| parse_hex_color(&value))
+        .unwrap_or([255, 255, 255])
+}
 
-    for line in 0..5 {
-        let y = start_y + line * line_spacing;
+/// Whether the logo is recolored to `WATERMARK_COLOR` (`WATERMARK_TINT_LOGO`)
+fn tint_logo_enabled() -> bool {
+    env::var("WATERMARK_TINT_LOGO").unwrap_or_default() == "true"
+}
 
-        // Center the pattern horizontally
-        let pattern_start_x = center_x - pattern_width / 2;
+/// Recolors every visible logo pixel to `color`, keeping its alpha so the shape and
+/// anti-aliased edges are preserved
+fn tint_logo(logo: &mut RgbaImage, [r, g, b]: [u8; 3]) {
+    for pixel in logo.pixels_mut() {
+        if pixel[3] > 0 {
+            *pixel = Rgba([r, g, b, pixel[3]]);
+        }
+    }
+}
 
-        // Draw left logo
-        let left_logo_x = pattern_start_x;
-        let left_logo_y = y - (logo_height as i32 / 2); // Center logo vertically on the line
+/// Contrasting stroke drawn behind watermark text so it stays readable on busy
+/// backgrounds, the image counterpart of FFmpeg's `borderw`/`bordercolor`
+/// (`WATERMARK_OUTLINE_COLOR` hex, default `000000`; `WATERMARK_OUTLINE_WIDTH` px,
+/// default 1, 0 disables). `WATERMARK_TEXT_BORDER_COLOR`/`_WIDTH` are accepted as
+/// aliases and win when both are set.
+#[derive(Debug, Clone)]
+struct TextOutline {
+    color: [u8; 3],
+    width: i32,
+}
 
-        if left_logo_x >= 0 && left_logo_x + logo_width as i32 <= width as i32 &&
-           left_logo_y >= 0 && left_logo_y + logo_height as i32 <= height as i32 {
-            draw_logo(&mut rgba, &logo_rgba, left_logo_x, left_logo_y, 0.7); // Higher opacity
-        }
+impl TextOutline {
+    fn from_env() -> Self {
+        let color = env_any(&["WATERMARK_TEXT_BORDER_COLOR", "WATERMARK_OUTLINE_COLOR"])
+            .and_then(|value| parse_hex_color(&value))
+            .unwrap_or([0, 0, 0]);
+        let width = env_any(&["WATERMARK_TEXT_BORDER_WIDTH", "WATERMARK_OUTLINE_WIDTH"])
+            .and_then(|v| v.trim().parse::<i32>().ok())
+            .unwrap_or(1)
+            .clamp(0, 10);
+        TextOutline { color, width }
+    }
+}
 
-        // Draw left dash
-        let left_dash_x = pattern_start_x + logo_width as i32 + gap;
-        let left_dash_y = y - (font_size as i32 / 2); // Center dash vertically on the line
+/// Semi-transparent band drawn across each watermark row, behind the text and logos
+/// (`WATERMARK_BAND`: `off` default, `dark` or `light`; `WATERMARK_BAND_COLOR` hex
+/// overrides the color, `WATERMARK_BAND_OPACITY` defaults to 0.35)
+#[derive(Debug, Clone)]
+struct TextBand {
+    color: [u8; 3],
+    opacity: f32,
+}
 
-        if left_dash_x >= 0 && left_dash_x + dash_width as i32 <= width as i32 &&
-           left_dash_y >= 0 && left_dash_y + font_size as i32 <= height as i32 {
-            draw_text_mut(
-                &mut rgba,
-                Rgba([255, 255, 255, 150]), // Higher opacity
-                left_dash_x,
-                left_dash_y,
-                scale,
-                &font,
-                "-"
-            );
-        }
+impl TextBand {
+    fn from_env() -> Option<Self> {
+        let default_color = match env::var("WATERMARK_BAND").unwrap_or_default().trim().to_lowercase().as_str() {
+            "" | "off" | "false" => return None,
+            "dark" | "true" => [0, 0, 0],
+            "light" => [255, 255, 255],
+            other => {
+                log_error!("⚠️  Unknown WATERMARK_BAND '{}', drawing no band", other);
+                return None;
+            }
+        };
+        let color = env::var("WATERMARK_BAND_COLOR")
+            .ok()
+            .and_then(|value| parse_hex_color(&value))
+            .unwrap_or(default_color);
+        let opacity = env::var("WATERMARK_BAND_OPACITY")
+            .ok()
+            .and_then(|v| v.parse::<f32>().ok())
+            .unwrap_or(0.35)
+            .clamp(0.0, 1.0);
+        Some(TextBand { color, opacity })
+    }
+}
 
-        // Draw center text
-        let text_x = pattern_start_x + logo_width as i32 + gap + dash_width as i32 + gap;
-        let text_y = y - (font_size as i32 / 2); // Center text vertically on the line
+/// Every watermark knob besides the branding itself, read from the environment once at
+/// startup instead of for each image
+#[derive(Debug, Clone)]
+struct WatermarkSettings {
+    style: WatermarkStyle,
+    corner: CornerStyle,
+    tile: TileStyle,
+    region: WatermarkRegion,
+    outline: TextOutline,
+    band: Option<TextBand>,
+    element_gap: Option<ElementGap>,
+    coverage: f32,
+    color: [u8; 3],
+    tint_logo: bool,
+    angle: f32,
+    protection: ProtectionMode,
+}
 
-        if text_x >= 0 && text_x + text_width as i32 <= width as i32 &&
-           text_y >= 0 && text_y + font_size as i32 <= height as i32 {
-            draw_text_mut(
-                &mut rgba,
-                Rgba([255, 255, 255, 150]), // Higher opacity
-                text_x,
-                text_y,
-                scale,
-                &font,
-                text
-            );
+impl WatermarkSettings {
+    fn from_env() -> Self {
+        Self {
+            style: WatermarkStyle::from_env(),
+            corner: CornerStyle::from_env(),
+            tile: TileStyle::from_env(),
+            region: WatermarkRegion::from_env(),
+            outline: TextOutline::from_env(),
+            band: TextBand::from_env(),
+            element_gap: ElementGap::from_env(),
+            coverage: watermark_coverage(),
+            color: watermark_color(),
+            tint_logo: tint_logo_enabled(),
+            angle: watermark_angle(),
+            protection: ProtectionMode::from_env(),
         }
+    }
 
-        // Draw right dash
-        let right_dash_x = pattern_start_x + logo_width as i32 + gap + dash_width as i32 + gap + text_width as i32 + gap;
-        let right_dash_y = y - (font_size as i32 / 2); // Center dash vertically on the line
+    /// The watermark color at `opacity` (0.0 to 1.0)
+    fn rgba(&self, opacity: f32) -> Rgba<u8> {
+        let [r, g, b] = self.color;
+        Rgba([r, g, b, (opacity * 255.0) as u8])
+    }
+}
 
-        if right_dash_x >= 0 && right_dash_x + dash_width as i32 <= width as i32 &&
-           right_dash_y >= 0 && right_dash_y + font_size as i32 <= height as i32 {
-            draw_text_mut(
-                &mut rgba,
-                Rgba([255, 255, 255, 150]), // Higher opacity
-                right_dash_x,
-                right_dash_y,
-                scale,
-                &font,
-                "-"
-            );
-        }
+/// Blends the band color over `width`x`height` at (x, y); parts outside the canvas are clipped
+fn draw_band(canvas: &mut RgbaImage, band: &TextBand, (x, y): (i32, i32), width: u32, height: u32) {
+    let [r, g, b] = band.color;
+    let color = Rgba([r, g, b, (band.opacity * 255.0).round() as u8]);
+    let mut blended = Blend(std::mem::take(canvas));
+    draw_filled_rect_mut(&mut blended, Rect::at(x, y).of_size(width.max(1), height.max(1)), color);
+    *canvas = blended.0;
+}
 
-        // Draw right logo
-        let right_logo_x = pattern_start_x + logo_width as i32 + gap + dash_width as i32 + gap + text_width as i32 + gap + dash_width as i32 + gap;
-        let right_logo_y = y - (logo_height as i32 / 2); // Center logo vertically on the line
+/// Parses `RRGGBB` or `#RRGGBB`
+fn parse_hex_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
 
-        if right_logo_x >= 0 && right_logo_x + logo_width as i32 <= width as i32 &&
-           right_logo_y >= 0 && right_logo_y + logo_height as i32 <= height as i32 {
-            draw_logo(&mut rgba, &logo_rgba, right_logo_x, right_logo_y, 0.7); // Higher opacity
+/// Draws `text` with the outline color at every offset within the outline width,
+/// then the text itself on top
+fn draw_outlined_text(
+    canvas: &mut RgbaImage,
+    color: Rgba<u8>,
+    (x, y): (i32, i32),
+    scale: Scale,
+    font: &Font,
+    text: &str,
+    outline: &TextOutline,
+) {
+    if outline.width > 0 {
+        let [r, g, b] = outline.color;
+        let outline_color = Rgba([r, g, b, color[3]]);
+        for dy in -outline.width..=outline.width {
+            for dx in -outline.width..=outline.width {
+                if dx != 0 || dy != 0 {
+                    draw_text_runs(canvas, outline_color, (x + dx, y + dy), scale, font, text);
+                }
+            }
         }
     }
-
-    DynamicImage::ImageRgba8(rgba)
+    draw_text_runs(canvas, color, (x, y), scale, font, text);
 }
 
-fn draw_logo(canvas: &mut RgbaImage, logo: &RgbaImage, x: i32, y: i32, opacity: f32) {
+pub(crate) fn draw_logo(canvas: &mut RgbaImage, logo: &RgbaImage, x: i32, y: i32, opacity: f32) {
     let (canvas_width, canvas_height) = canvas.dimensions();
     let (logo_width, logo_height) = logo.dimensions();
 
@@ -542,244 +4447,1166 @@ fn draw_logo(canvas: &mut RgbaImage, logo: &RgbaImage, x: i32, y: i32, opacity:
                 let logo_pixel = logo.get_pixel(logo_x, logo_y);
                 let canvas_pixel = canvas.get_pixel_mut(canvas_x as u32, canvas_y as u32);
 
-                // Alpha blend with opacity
+                // Source-over compositing with opacity, including the canvas alpha so
+                // transparent canvas regions end up with the logo's coverage
                 let logo_alpha = (logo_pixel[3] as f32 / 255.0) * opacity;
-                let inv_alpha = 1.0 - logo_alpha;
+                let canvas_alpha = canvas_pixel[3] as f32 / 255.0;
+                let out_alpha = logo_alpha + canvas_alpha * (1.0 - logo_alpha);
+                if out_alpha <= 0.0 {
+                    continue;
+                }
 
-                canvas_pixel[0] = (canvas_pixel[0] as f32 * inv_alpha + logo_pixel[0] as f32 * logo_alpha) as u8;
-                canvas_pixel[1] = (canvas_pixel[1] as f32 * inv_alpha + logo_pixel[1] as f32 * logo_alpha) as u8;
-                canvas_pixel[2] = (canvas_pixel[2] as f32 * inv_alpha + logo_pixel[2] as f32 * logo_alpha) as u8;
+                for channel in 0..3 {
+                    let blended = (logo_pixel[channel] as f32 * logo_alpha
+                        + canvas_pixel[channel] as f32 * canvas_alpha * (1.0 - logo_alpha))
+                        / out_alpha;
+                    canvas_pixel[channel] = blended.round().clamp(0.0, 255.0) as u8;
+                }
+                canvas_pixel[3] = (out_alpha * 255.0).round() as u8;
             }
         }
     }
 }
 
-fn watermark_image_text_only(img: DynamicImage, text: &str) -> DynamicImage {
+/// Angles steeper than a right angle would turn the text upside down
+const WATERMARK_ANGLE_RANGE: std::ops::RangeInclusive<f32> = -90.0..=90.0;
+
+/// Rotation of the diagonal text-only watermark in degrees (`WATERMARK_ANGLE`, default
+/// -30); negative angles rise from left to right
+fn watermark_angle() -> f32 {
+    env::var("WATERMARK_ANGLE")
+        .ok()
+        .and_then(|v| v.trim().parse::<f32>().ok())
+        .filter(|angle| WATERMARK_ANGLE_RANGE.contains(angle))
+        .unwrap_or(-30.0)
+}
+
+/// `text` rendered once onto a transparent square tile and rotated by `angle_degrees`
+/// about its center, ready to be stamped with `draw_logo`. The tile's side is the text
+/// block's diagonal, so no rotation clips the glyphs.
+fn rotated_text_tile(text: &str, scale: Scale, font: &Font, color: Rgba<u8>, outline: &TextOutline, angle_degrees: f32) -> RgbaImage {
+    let (text_width, text_height) = text_block_size(scale, font, text);
+    let padding = outline.width.max(0) + 1;
+    let (block_width, block_height) = (text_width + 2 * padding, text_height + 2 * padding);
+    let side = ((block_width as f32).hypot(block_height as f32).ceil() as u32).max(1);
+
+    // Transparent pixels carry the text color so antialiased edges don't darken when blended
+    let [r, g, b, _] = color.0;
+    let mut tile = RgbaImage::from_pixel(side, side, Rgba([r, g, b, 0]));
+    let origin = ((side as i32 - text_width) / 2, (side as i32 - text_height) / 2);
+    draw_outlined_lines(&mut tile, color, origin, scale, font, text, outline);
+
+    if angle_degrees == 0.0 {
+        return tile;
+    }
+    rotate_about_center(&tile, angle_degrees.to_radians(), Interpolation::Bilinear, Rgba([r, g, b, 0]))
+}
+
+fn watermark_image_text_only(img: DynamicImage, branding: &Branding) -> DynamicImage {
     let (width, height) = img.dimensions();
-    let font_data = include_bytes!("../fonts/DejaVuSans-Bold.ttf") as &[u8];
-    let font = Font::try_from_bytes(font_data).unwrap();
+    let font = watermark_font();
     let mut rgba: RgbaImage = img.to_rgba8();
 
     let center_x = width as i32 / 2;
     let center_y = height as i32 / 2;
 
     // Diagonal repeated watermarks only
+    let settings = &branding.settings;
+    let text_color = settings.rgba(branding.scale_opacity(80.0 / 255.0));
     let diagonal_font_size = (width.min(height) as f32 * 0.05).max(16.0);
     let diagonal_scale = Scale::uniform(diagonal_font_size);
+    let outline = &settings.outline;
+
+    let x_step = ((width as f32 / 2.5) as i32).max(1);
+    let y_step = ((height as f32 / 3.0) as i32).max(1);
+    let mut texts_drawn = 0;
 
-    let x_step = (width as f32 / 2.5) as i32;
-    let y_step = (height as f32 / 3.0) as i32;
+    // The grid and the glyphs are rotated by the same angle
+    let angle = settings.angle;
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let tile = rotated_text_tile(&branding.text, diagonal_scale, font, text_color, outline, angle);
+    let (text_width, text_height) = text_block_size(diagonal_scale, font, &branding.text);
 
-    // Apply rotation effect by drawing at diagonal positions
     for y in (-(height as i32)..(height as i32) * 2).step_by(y_step as usize) {
         for x in (-(width as i32)..(width as i32) * 2).step_by(x_step as usize) {
-            // Calculate rotated position (simulate -30 degree rotation)
-            let cos_30 = 0.866f32; // cos(-π/6)
-            let sin_30 = -0.5f32;  // sin(-π/6)
-
-            let rotated_x = ((x as f32 * cos_30 - y as f32 * sin_30) as i32) + center_x;
-            let rotated_y = ((x as f32 * sin_30 + y as f32 * cos_30) as i32) + center_y;
+            let rotated_x = ((x as f32 * cos - y as f32 * sin) as i32) + center_x;
+            let rotated_y = ((x as f32 * sin + y as f32 * cos) as i32) + center_y;
 
             // Only draw if within image bounds
             if rotated_x > 0 && rotated_x < width as i32 - 100 &&
                rotated_y > 0 && rotated_y < height as i32 - 30 {
-                draw_text_mut(
-                    &mut rgba,
-                    Rgba([255, 255, 255, 80]), // Semi-transparent white
-                    rotated_x,
-                    rotated_y,
-                    diagonal_scale,
-                    &font,
-                    text
-                );
+                // Centered where the unrotated text block would have been
+                let tile_x = rotated_x + text_width / 2 - tile.width() as i32 / 2;
+                let tile_y = rotated_y + text_height / 2 - tile.height() as i32 / 2;
+                draw_logo(&mut rgba, &tile, tile_x, tile_y, 1.0);
+                texts_drawn += 1;
             }
         }
     }
 
+    // Too small for the diagonal grid: one line, shrunk to fit, in the center
+    if texts_drawn == 0 {
+        log!("⚠️  Image {}x{} too small for diagonal watermark, using a single centered line", width, height);
+        let text = branding.text.as_str();
+        let longest_line = text.lines().map(|line| line.chars().count()).max().unwrap_or(0).max(1);
+        let line_count = text.lines().count().max(1);
+        let font_size = (width as f32 / (longest_line as f32 * 0.6))
+            .min(height as f32 * 0.5 / line_count as f32)
+            .max(6.0);
+        let scale = Scale::uniform(font_size);
+        let (text_width, text_height) = text_block_size(scale, font, text);
+        let position = (center_x - text_width / 2, center_y - text_height / 2);
+        let line_color = settings.rgba(branding.scale_opacity(150.0 / 255.0));
+        draw_outlined_lines(&mut rgba, line_color, position, scale, font, text, outline);
+    }
+
+    draw_copyright(&mut rgba, branding, font, outline);
+
     DynamicImage::ImageRgba8(rgba)
 }
 
-async fn watermark_video(input_bytes: &[u8], _watermark_text: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let input_file = NamedTempFile::with_suffix(".mp4")?.into_temp_path();
-    let output_file = NamedTempFile::with_suffix(".mp4")?.into_temp_path();
+/// Waits for an FFmpeg slot and then watermarks the video under `VIDEO_TIMEOUT_SECONDS`, so
+/// time spent queued behind other videos doesn't count against the timeout
+#[cfg(feature = "video")]
+async fn watermark_video_with_timeout(config: &Config, input_bytes: &[u8], branding: &Branding) -> Result<Result<NamedTempFile, Box<dyn std::error::Error>>, tokio::time::error::Elapsed> {
+    let _permit = acquire_ffmpeg_permit(config).await;
+    tokio::time::timeout(config.video.timeout, watermark_video(config, input_bytes, branding)).await
+}
+
+/// Runs FFmpeg on the video with the watermark overlay. The caller holds the FFmpeg permit.
+/// The result stays in its temp file, removed when the returned handle is dropped, so a
+/// large video is uploaded from disk rather than read into memory.
+#[cfg(feature = "video")]
+async fn watermark_video(config: &Config, input_bytes: &[u8], branding: &Branding) -> Result<NamedTempFile, Box<dyn std::error::Error>> {
+    let branding = &*branding.for_videos();
+    // Make sure the input and FFmpeg's output both fit before writing anything, so a full
+    // disk skips the video instead of failing mid-encode with a truncated temp file
+    let required_bytes = (input_bytes.len() as f64 * config.video.disk_headroom) as u64;
+    let tmp = &config.temp_dir;
+    match available_disk_space(tmp) {
+        Ok(available) if available < required_bytes => {
+            return Err(format!(
+                "Not enough disk space in {} ({:.1}MB free, {:.1}MB required)",
+                tmp.display(),
+                available as f64 / 1024.0 / 1024.0,
+                required_bytes as f64 / 1024.0 / 1024.0
+            ).into());
+        }
+        Ok(_) => {}
+        Err(e) => log_error!("⚠️  Could not check free disk space in {}: {}", tmp.display(), e),
+    }
+
+    // Both temp files are removed on drop, which also covers the caller's timeout aborting
+    // this future mid-encode
+    let format = config.video_output_format;
+    let input_file = new_temp_file(config, ".mp4")?;
+    let output_file = new_temp_file(config, &format!(".{}", format.extension()))?;
 
-    println!("📁 Input file: {}", input_file.display());
-    println!("📁 Output file: {}", output_file.display());
-    println!("📊 Input size: {} bytes", input_bytes.len());
+    log!("📁 Input file: {}", input_file.path().display());
+    log!("📁 Output file: {}", output_file.path().display());
+    log!("📊 Input size: {} bytes", input_bytes.len());
 
-    fs::write(&input_file, input_bytes).await?;
-    println!("✅ Wrote input file successfully");
+    fs::write(input_file.path(), input_bytes).await?;
+    log!("✅ Wrote input file successfully");
 
-    println!("🎬 Starting ffmpeg process...");
+    log!("🎬 Starting ffmpeg process...");
 
     // Create highly visible watermarks that actually show up in video
     // 5 lines with high opacity and large font size
     let mut watermark_filters = Vec::new();
 
-    // Create 5 lines with pattern similar to images but text-based for FFmpeg
-    for line in 0..5 {
-        let y_position = format!("h/2 + (h*0.12)*({} - 2)", line); // Match image spacing
-
-        // Left "REFLEXU" text - much more visible with stroke for thickness
+    // Text style shared by every element, with a fainter stroke for thickness
+    let [r, g, b] = branding.settings.color;
+    let color = format!("0x{:02X}{:02X}{:02X}", r, g, b);
+    let style = format!(
+        "fontcolor={color}@{:.2}:fontsize=h/40:borderw=2:bordercolor={color}@{:.2}",
+        branding.opacity,
+        branding.opacity / 2.0
+    );
+
+    if branding.settings.style == WatermarkStyle::Corner {
+        // Single discreet label in one corner; the brand name stands in for the logo
+        let corner = &branding.settings.corner;
+        let label = if corner.with_text {
+            format!("{}  {}", branding.brand, branding.text.replace('\n', " "))
+        } else {
+            branding.brand.clone()
+        };
+        let (x, y) = corner.ffmpeg_position();
+        let opacity = corner.opacity(branding);
         watermark_filters.push(format!(
-            "drawtext=text='REFLEXU':fontcolor=white@0.6:fontsize=h/40:borderw=2:bordercolor=white@0.3:x=w*0.2:y={}",
-            y_position
-        ));
-
-        // Left dash
-        watermark_filters.push(format!(
-            "drawtext=text='-':fontcolor=white@0.6:fontsize=h/40:borderw=2:bordercolor=white@0.3:x=w*0.32:y={}",
-            y_position
+            "drawtext=text={}:fontcolor={}@{:.2}:fontsize=h/30:borderw=1:bordercolor=black@{:.2}:x={}:y={}",
+            drawtext_escape(&label), color, opacity, opacity / 2.0, x, y
         ));
+    } else {
+        // Create 5 lines with pattern similar to images but text-based for FFmpeg
+        let brand = drawtext_escape(&branding.brand);
+        for line in 0..5 {
+            let y_position = format!("h/2 + (h*0.12)*({} - 2)", line); // Match image spacing
+
+            // Left brand text
+            watermark_filters.push(format!("drawtext=text={}:{}:x=w*0.2:y={}", brand, style, y_position));
+
+            // Left dash
+            watermark_filters.push(format!("drawtext=text='-':{}:x=w*0.32:y={}", style, y_position));
+
+            // Center text (e.g. the site URL), one drawtext per line stacked around the row
+            let text_lines: Vec<&str> = branding.text.lines().collect();
+            for (index, text_line) in text_lines.iter().enumerate() {
+                let offset = index as f32 - (text_lines.len() as f32 - 1.0) / 2.0;
+                watermark_filters.push(format!(
+                    "drawtext=text={}:{}:x=w/2-tw/2:y={} + (h/40*1.2)*({:.1})",
+                    drawtext_escape(text_line), style, y_position, offset
+                ));
+            }
 
-        // Center "www.reflexu.com" text - much bigger and more opaque with stroke for thickness
-        watermark_filters.push(format!(
-            "drawtext=text='www.reflexu.com':fontcolor=white@0.6:fontsize=h/40:borderw=2:bordercolor=white@0.3:x=w/2-tw/2:y={}",
-            y_position
-        ));
+            // Right dash
+            watermark_filters.push(format!("drawtext=text='-':{}:x=w*0.68:y={}", style, y_position));
 
-        // Right dash
-        watermark_filters.push(format!(
-            "drawtext=text='-':fontcolor=white@0.6:fontsize=h/40:borderw=2:bordercolor=white@0.3:x=w*0.68:y={}",
-            y_position
-        ));
+            // Right brand text
+            watermark_filters.push(format!("drawtext=text={}:{}:x=w*0.8-tw:y={}", brand, style, y_position));
+        }
+    }
 
-        // Right "REFLEXU" text
+    // Optional copyright line centered along the bottom edge
+    if let Some(line) = branding.copyright_line() {
         watermark_filters.push(format!(
-            "drawtext=text='REFLEXU':fontcolor=white@0.6:fontsize=h/40:borderw=2:bordercolor=white@0.3:x=w*0.8-tw:y={}",
-            y_position
+            "drawtext=text={}:fontcolor={}@{:.2}:fontsize=h/50:borderw=1:bordercolor=black@{:.2}:x=(w-tw)/2:y=h-th-h*0.04",
+            drawtext_escape(&line),
+            color,
+            branding.opacity,
+            branding.opacity / 2.0
         ));
     }
 
-    let watermark_filter = watermark_filters.join(",");
-    
-    let mut cmd = Command::new("ffmpeg");
-    cmd.args([
-        "-y",
-        "-i", input_file.to_str().unwrap(),
-        "-vf", &format!("scale=1280:-1,{}", watermark_filter), // Scale down to 1280px width (720p)
-        "-c:v", "libx264",
-        "-crf", "35", // Moderate quality reduction
-        "-preset", "ultrafast",
-        "-threads", "1", // Single thread to reduce resource usage
-        "-b:v", "1500k", // Limit bitrate to 1.5Mbps
-        "-movflags", "+faststart", // Optimize for streaming
-        "-an", // No audio
-        output_file.to_str().unwrap(),
-    ]);
-    
-    let ffmpeg_output = cmd.output()?;
-    
-    println!("🎬 FFmpeg process completed");
+    // Literal text only: no %{...} expansion of user-configured strings
+    let fontfile = ffmpeg_fontfile_option();
+    let watermark_filter = watermark_filters
+        .iter()
+        .map(|filter| filter.replacen("drawtext=", &format!("drawtext=expansion=none:{}", fontfile), 1))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    // Without drawtext (FFmpeg built without libfreetype) only the logo can be overlaid
+    let logo_file = if drawtext_available() { None } else { Some(write_overlay_logo(config, branding)?) };
+    let video_filter = match logo_file {
+        None => format!("scale=1280:-1,{}", watermark_filter),
+        Some(_) => format!(
+            "[0:v]scale=1280:-1[base];[1:v]scale=192:-1,format=rgba,colorchannelmixer=aa={:.2}[logo];[base][logo]overlay=(W-w)/2:(H-h)/2",
+            branding.opacity
+        ),
+    };
+    let filter_option = if logo_file.is_some() { "-filter_complex" } else { "-vf" };
+
+    // Built per attempt since a Command can't be reused after spawning
+    let build_command = || {
+        let mut cmd = Command::new("ffmpeg");
+        cmd.arg("-y");
+        match format {
+            VideoOutputFormat::Mp4 => {
+                let encoder = config.video.encoder;
+                cmd.args(encoder.input_args(&config.video.vaapi_device));
+                cmd.args(["-i", input_file.path().to_str().unwrap()]);
+                if let Some(logo) = &logo_file {
+                    cmd.args(["-i", logo.path().to_str().unwrap()]);
+                }
+                // Scale down to 1280px width (720p)
+                cmd.args([filter_option, &format!("{}{}", video_filter, encoder.filter_suffix())]);
+                cmd.args(encoder.encode_args());
+                cmd.args(["-movflags", "+faststart"]); // Optimize for streaming
+            }
+            VideoOutputFormat::Webm => {
+                cmd.args(["-i", input_file.path().to_str().unwrap()]);
+                if let Some(logo) = &logo_file {
+                    cmd.args(["-i", logo.path().to_str().unwrap()]);
+                }
+                cmd.args([
+                    filter_option, &video_filter, // Scale down to 1280px width (720p)
+                    "-c:v", "libvpx-vp9",
+                    "-crf", "35", // Constrained quality together with -b:v
+                    "-deadline", "realtime",
+                    "-cpu-used", "8", // Fastest VP9 preset
+                    "-threads", "1", // Single thread to reduce resource usage
+                ]);
+            }
+        }
+        cmd.args([
+            "-b:v", "1500k", // Limit bitrate to 1.5Mbps
+            "-an", // No audio
+            "-progress", "pipe:1", // Machine-readable progress on stdout
+            "-nostats",
+            output_file.path().to_str().unwrap(),
+        ]);
+        // Kill FFmpeg if this future is dropped (e.g. on timeout) so it stops writing to the temp file
+        cmd.kill_on_drop(true);
+        cmd
+    };
+
+    let duration = probe_duration_seconds(input_file.path()).await;
+    let max_retries = config.video.ffmpeg_retries;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let (status, stderr) = run_ffmpeg_with_progress(build_command(), duration).await?;
 
-    if !ffmpeg_output.status.success() {
-        let stderr = String::from_utf8_lossy(&ffmpeg_output.stderr);
-        let stdout = String::from_utf8_lossy(&ffmpeg_output.stdout);
-        eprintln!("❌ FFmpeg failed with exit code: {}", ffmpeg_output.status.code().unwrap_or(-1));
-        eprintln!("❌ FFmpeg stderr: {}", stderr);
-        eprintln!("❌ FFmpeg stdout: {}", stdout);
-        return Err(format!("FFmpeg command failed with exit code: {}", ffmpeg_output.status.code().unwrap_or(-1)).into());
+        log!("🎬 FFmpeg process completed (attempt {}, exit code {})", attempt, status.code().unwrap_or(-1));
+
+        if status.success() {
+            break;
+        }
+        log_error!("❌ FFmpeg failed with exit code: {}", status.code().unwrap_or(-1));
+        log_error!("❌ FFmpeg stderr: {}", stderr);
+        if attempt > max_retries || !ffmpeg_failure_is_retryable(status.code(), &stderr) {
+            return Err(format!("FFmpeg command failed with exit code: {}", status.code().unwrap_or(-1)).into());
+        }
+        let delay = Duration::from_secs(2u64 << (attempt - 1).min(5));
+        log!("🔁 Transient FFmpeg failure, retrying in {}s ({}/{})", delay.as_secs(), attempt, max_retries);
+        sleep(delay).await;
     }
 
     // Check if output file exists and has content
-    if !output_file.exists() {
+    if !output_file.path().exists() {
         return Err("Output file was not created by ffmpeg".into());
     }
 
-    let result_bytes = fs::read(&output_file).await?;
-    println!("📊 Output size: {} bytes", result_bytes.len());
+    let output_size = fs::metadata(output_file.path()).await?.len();
+    input_file.close()?;
+    log!("📊 Output size: {} bytes", output_size);
     
-    if result_bytes.is_empty() {
+    if output_size == 0 {
         return Err("Output file is empty".into());
     }
 
-    Ok(result_bytes)
+    Ok(output_file)
+}
+
+/// Writes the branding logo (or the bundled one) to a temp PNG for FFmpeg's `overlay`
+#[cfg(feature = "video")]
+fn write_overlay_logo(config: &Config, branding: &Branding) -> Result<NamedTempFile, Box<dyn std::error::Error>> {
+    let bundled_logo;
+    let logo = match &branding.logo {
+        Some(logo) => logo.as_ref(),
+        None => {
+            bundled_logo = image::open(BUNDLED_LOGO_PATH)
+                .map_err(|e| format!("FFmpeg has no drawtext filter and {} could not be loaded for a logo-only watermark: {}", BUNDLED_LOGO_PATH, e))?;
+            &bundled_logo
+        }
+    };
+    let file = new_temp_file(config, ".png")?;
+    logo.save_with_format(file.path(), image::ImageFormat::Png)?;
+    Ok(file)
+}
+
+/// Whether this FFmpeg build has the `drawtext` filter, probed once at startup
+#[cfg(feature = "video")]
+static FFMPEG_DRAWTEXT: OnceLock<bool> = OnceLock::new();
+
+#[cfg(feature = "video")]
+fn drawtext_available() -> bool {
+    *FFMPEG_DRAWTEXT.get_or_init(|| true)
+}
+
+/// True if `ffmpeg -filters` output lists `drawtext`
+#[cfg(feature = "video")]
+fn filters_include_drawtext(filters: &str) -> bool {
+    filters.lines().any(|line| line.split_whitespace().nth(1) == Some("drawtext"))
+}
+
+/// Checks for `drawtext` once at startup. Minimal FFmpeg builds without libfreetype lack
+/// it, and every video would otherwise fail with an obscure filter error; they get a
+/// logo-only `overlay` watermark instead.
+#[cfg(feature = "video")]
+async fn init_drawtext_probe() {
+    let available = match Command::new("ffmpeg").args(["-hide_banner", "-filters"]).output().await {
+        Ok(output) => filters_include_drawtext(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            // Without FFmpeg every video fails anyway, with a clearer error than this probe's
+            log_error!("⚠️  Could not run ffmpeg to probe filters: {}", e);
+            true
+        }
+    };
+    if !available {
+        log_error!("❌ This FFmpeg build has no drawtext filter (it needs libfreetype); videos get a logo-only watermark. Install an FFmpeg built with --enable-libfreetype for text watermarks.");
+    }
+    let _ = FFMPEG_DRAWTEXT.set(available);
+}
+
+/// stderr fragments of failures worth retrying: resource contention and transient I/O
+#[cfg(feature = "video")]
+const FFMPEG_TRANSIENT_ERRORS: &[&str] = &[
+    "Resource temporarily unavailable",
+    "Cannot allocate memory",
+    "Device or resource busy",
+    "Input/output error",
+    "Broken pipe",
+    "Connection reset",
+];
+
+/// stderr fragments of failures that will fail the same way every time
+#[cfg(feature = "video")]
+const FFMPEG_DETERMINISTIC_ERRORS: &[&str] = &[
+    "Invalid data found when processing input",
+    "moov atom not found",
+    "Unknown encoder",
+    "Unknown decoder",
+    "not supported",
+    "No such filter",
+    "Invalid argument",
+    "does not contain any stream",
+];
+
+/// Whether a failed FFmpeg run is worth retrying. A kill by signal (no exit code, e.g.
+/// the OOM killer) counts as transient; unknown errors are not retried.
+#[cfg(feature = "video")]
+fn ffmpeg_failure_is_retryable(exit_code: Option<i32>, stderr: &str) -> bool {
+    if FFMPEG_DETERMINISTIC_ERRORS.iter().any(|marker| stderr.contains(marker)) {
+        return false;
+    }
+    exit_code.is_none() || FFMPEG_TRANSIENT_ERRORS.iter().any(|marker| stderr.contains(marker))
+}
+
+/// Media duration in seconds according to ffprobe, if it can be determined
+#[cfg(feature = "video")]
+async fn probe_duration_seconds(path: &Path) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v", "error",
+            "-show_entries", "format=duration",
+            "-of", "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(path)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+
+    String::from_utf8_lossy(&output.stdout).trim().parse::<f64>().ok()
+}
+
+/// Runs an FFmpeg command started with `-progress pipe:1` and logs its progress every
+/// 10 seconds, as a percentage when the input duration is known. Returns the exit
+/// status and the captured stderr.
+#[cfg(feature = "video")]
+async fn run_ffmpeg_with_progress(
+    mut cmd: Command,
+    duration_seconds: Option<f64>,
+) -> Result<(std::process::ExitStatus, String), Box<dyn std::error::Error>> {
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    let stdout = child.stdout.take().ok_or("FFmpeg stdout was not captured")?;
+    let mut stderr = child.stderr.take().ok_or("FFmpeg stderr was not captured")?;
+
+    let read_progress = async {
+        let started = Instant::now();
+        let mut last_log = Instant::now();
+        let mut frame = 0u64;
+        let mut lines = BufReader::new(stdout).lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let Some((key, value)) = line.split_once('=') else { continue };
+            match key {
+                "frame" => frame = value.trim().parse().unwrap_or(frame),
+                "out_time_us" | "out_time_ms" if last_log.elapsed() >= Duration::from_secs(10) => {
+                    // Despite its name, out_time_ms is also reported in microseconds
+                    let out_seconds = value.trim().parse::<f64>().unwrap_or(0.0) / 1_000_000.0;
+                    match duration_seconds {
+                        Some(total) if total > 0.0 => log!(
+                            "⏳ FFmpeg progress: {:.0}% ({:.1}s / {:.1}s, frame {}, {:.0}s elapsed)",
+                            (out_seconds / total * 100.0).min(100.0), out_seconds, total, frame, started.elapsed().as_secs_f64()
+                        ),
+                        _ => log!(
+                            "⏳ FFmpeg progress: {:.1}s encoded (frame {}, {:.0}s elapsed)",
+                            out_seconds, frame, started.elapsed().as_secs_f64()
+                        ),
+                    }
+                    last_log = Instant::now();
+                }
+                _ => {}
+            }
+        }
+    };
+    let read_stderr = async {
+        let mut buf = Vec::new();
+        let _ = stderr.read_to_end(&mut buf).await;
+        buf
+    };
+
+    let ((), stderr_bytes) = tokio::join!(read_progress, read_stderr);
+    let status = child.wait().await?;
+
+    Ok((status, String::from_utf8_lossy(&stderr_bytes).into_owned()))
+}
+
+/// Container/codec of video previews, selected via `VIDEO_OUTPUT_FORMAT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoOutputFormat {
+    /// H.264 in MP4 using the configured `VIDEO_ENCODER`
+    Mp4,
+    /// VP9 in WebM
+    Webm,
+}
+
+impl VideoOutputFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "mp4" => Some(Self::Mp4),
+            "webm" => Some(Self::Webm),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Webm => "webm",
+        }
+    }
+}
+
+/// H.264 encoder used for video previews, selected via `VIDEO_ENCODER`
+#[cfg(feature = "video")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VideoEncoder {
+    Libx264,
+    Nvenc,
+    Vaapi,
+}
+
+#[cfg(feature = "video")]
+impl VideoEncoder {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "libx264" => Some(Self::Libx264),
+            "h264_nvenc" => Some(Self::Nvenc),
+            "h264_vaapi" => Some(Self::Vaapi),
+            _ => None,
+        }
+    }
+
+    fn ffmpeg_name(self) -> &'static str {
+        match self {
+            Self::Libx264 => "libx264",
+            Self::Nvenc => "h264_nvenc",
+            Self::Vaapi => "h264_vaapi",
+        }
+    }
+
+    /// Args that must come before `-i`
+    fn input_args(self, vaapi_device: &str) -> Vec<String> {
+        match self {
+            Self::Vaapi => vec!["-vaapi_device".to_string(), vaapi_device.to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Appended to the filter graph to move frames onto the GPU where needed
+    fn filter_suffix(self) -> &'static str {
+        match self {
+            Self::Vaapi => ",format=nv12,hwupload",
+            _ => "",
+        }
+    }
+
+    /// Codec and rate-control args, all tuned to roughly match CRF 35 on libx264
+    fn encode_args(self) -> Vec<&'static str> {
+        match self {
+            Self::Libx264 => vec![
+                "-c:v", "libx264",
+                "-crf", "35", // Moderate quality reduction
+                "-preset", "ultrafast",
+                "-threads", "1", // Single thread to reduce resource usage
+            ],
+            Self::Nvenc => vec!["-c:v", "h264_nvenc", "-preset", "p1", "-rc", "vbr", "-cq", "35"],
+            Self::Vaapi => vec!["-c:v", "h264_vaapi", "-rc_mode", "CQP", "-qp", "35"],
+        }
+    }
+}
+
+/// Checks once at startup that this FFmpeg build provides the requested encoder, falling
+/// back to `libx264` when it doesn't
+#[cfg(feature = "video")]
+async fn resolve_video_encoder(requested: VideoEncoder, output_format: VideoOutputFormat) -> VideoEncoder {
+    let encoder = match requested {
+        VideoEncoder::Libx264 => VideoEncoder::Libx264,
+        encoder => {
+            let available = match Command::new("ffmpeg").args(["-hide_banner", "-encoders"]).output().await {
+                Ok(output) => String::from_utf8_lossy(&output.stdout)
+                    .split_whitespace()
+                    .any(|word| word == encoder.ffmpeg_name()),
+                Err(e) => {
+                    log_error!("⚠️  Could not run ffmpeg to probe encoders: {}", e);
+                    false
+                }
+            };
+            if available {
+                encoder
+            } else {
+                log_error!("⚠️  Encoder {} is not available in this FFmpeg build, falling back to libx264", encoder.ffmpeg_name());
+                VideoEncoder::Libx264
+            }
+        }
+    };
+
+    match output_format {
+        VideoOutputFormat::Mp4 => log!("🎬 Video output: mp4 ({})", encoder.ffmpeg_name()),
+        VideoOutputFormat::Webm => log!("🎬 Video output: webm (libvpx-vp9, VIDEO_ENCODER ignored)"),
+    }
+    encoder
+}
+
+/// Video pipeline settings, read by `Config::from_env`
+#[cfg(feature = "video")]
+#[derive(Debug, Clone)]
+struct VideoSettings {
+    /// Max time a single video may spend in FFmpeg (`VIDEO_TIMEOUT_SECONDS`, default 300)
+    timeout: Duration,
+    /// Videos larger than this are skipped (`VIDEO_MAX_MB`, default 300)
+    max_mb: f64,
+    /// H.264 encoder (`VIDEO_ENCODER`, default `libx264`); replaced at startup by
+    /// `resolve_video_encoder` when FFmpeg lacks it
+    encoder: VideoEncoder,
+    /// Render node used by `h264_vaapi` (`VAAPI_DEVICE`, default `/dev/dri/renderD128`)
+    vaapi_device: String,
+    /// Extra FFmpeg attempts after a transient failure (`FFMPEG_RETRIES`, default 2)
+    ffmpeg_retries: u32,
+    /// FFmpeg processes running at once (`MAX_CONCURRENT_FFMPEG`, default 2)
+    max_concurrent_ffmpeg: usize,
+    /// Free temp space required before encoding, as a multiple of the input size
+    /// (`VIDEO_DISK_HEADROOM`, default 2)
+    disk_headroom: f64,
+    /// Seconds into a video the poster frame is taken from (`VIDEO_POSTER_TIME`, default 1)
+    poster_time: f64,
+    webp_preview: Option<WebpPreview>,
+}
+
+#[cfg(feature = "video")]
+impl VideoSettings {
+    fn from_env(cli: &CliSettings) -> Self {
+        let number = |var: &str, default: f64| env_any(&[var]).and_then(|v| v.trim().parse::<f64>().ok()).unwrap_or(default);
+        VideoSettings {
+            timeout: Duration::from_secs(cli_value(&cli.video_timeout_seconds).and_then(|v| v.trim().parse().ok()).unwrap_or(300)),
+            max_mb: cli_value(&cli.video_max_mb).and_then(|v| v.trim().parse().ok()).unwrap_or(300.0),
+            encoder: cli_value(&cli.video_encoder).and_then(VideoEncoder::parse).unwrap_or(VideoEncoder::Libx264),
+            vaapi_device: env_any(&["VAAPI_DEVICE"]).unwrap_or_else(|| "/dev/dri/renderD128".to_string()),
+            ffmpeg_retries: env_any(&["FFMPEG_RETRIES"]).and_then(|v| v.trim().parse().ok()).unwrap_or(2),
+            max_concurrent_ffmpeg: env_any(&["MAX_CONCURRENT_FFMPEG"]).and_then(|v| v.trim().parse().ok()).filter(|max| *max > 0).unwrap_or(2),
+            disk_headroom: number("VIDEO_DISK_HEADROOM", 2.0),
+            poster_time: number("VIDEO_POSTER_TIME", 1.0),
+            webp_preview: WebpPreview::from_env(),
+        }
+    }
+}
+
+/// Caps how many FFmpeg processes run at once across all concurrent work
+#[cfg(feature = "video")]
+static FFMPEG_SEMAPHORE: OnceLock<Semaphore> = OnceLock::new();
+
+/// Waits for an FFmpeg slot (`MAX_CONCURRENT_FFMPEG`, default 2). Hold the permit for as
+/// long as the FFmpeg process runs.
+#[cfg(feature = "video")]
+async fn acquire_ffmpeg_permit(config: &Config) -> SemaphorePermit<'static> {
+    let semaphore = FFMPEG_SEMAPHORE.get_or_init(|| Semaphore::new(config.video.max_concurrent_ffmpeg));
+
+    match semaphore.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            log!("⏳ Waiting for a free FFmpeg slot...");
+            semaphore.acquire().await.expect("FFmpeg semaphore is never closed")
+        }
+    }
+}
+
+/// Most frames an animated WebP preview may have, whatever the seconds and fps
+#[cfg(feature = "video")]
+const WEBP_MAX_FRAMES: u32 = 150;
+
+/// Animated WebP hover preview of videos (`VIDEO_WEBP_PREVIEW=true`): the first
+/// `VIDEO_WEBP_SECONDS` (default 3) at `VIDEO_WEBP_FPS` (default 10), `VIDEO_WEBP_WIDTH`
+/// px wide (default 480), rejected above `VIDEO_WEBP_MAX_KB` (default 1024)
+#[cfg(feature = "video")]
+#[derive(Debug, Clone)]
+struct WebpPreview {
+    seconds: f64,
+    fps: u32,
+    width: u32,
+    max_bytes: usize,
+}
+
+#[cfg(feature = "video")]
+impl WebpPreview {
+    fn from_env() -> Option<Self> {
+        if env::var("VIDEO_WEBP_PREVIEW").unwrap_or_default() != "true" {
+            return None;
+        }
+        let number = |var: &str, default: f64| env::var(var).ok().and_then(|v| v.parse::<f64>().ok()).filter(|v| *v > 0.0).unwrap_or(default);
+        Some(WebpPreview {
+            seconds: number("VIDEO_WEBP_SECONDS", 3.0),
+            fps: number("VIDEO_WEBP_FPS", 10.0).min(30.0) as u32,
+            width: (number("VIDEO_WEBP_WIDTH", 480.0) as u32).clamp(16, 1280),
+            max_bytes: number("VIDEO_WEBP_MAX_KB", 1024.0) as usize * 1024,
+        })
+    }
+
+    fn max_frames(&self) -> u32 {
+        ((self.seconds * self.fps as f64).ceil() as u32).clamp(1, WEBP_MAX_FRAMES)
+    }
+}
+
+/// Builds a looping animated WebP from the start of a video. FFmpeg extracts the frames,
+/// each is watermarked with the image pipeline, and FFmpeg encodes them with
+/// `libwebp_anim` since the `image` crate has no WebP encoder.
+#[cfg(feature = "video")]
+async fn video_webp_preview(config: &Config, input_bytes: &[u8], preview: &WebpPreview, branding: &Branding) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let input_file = new_temp_file(config, ".mp4")?;
+    let output_file = new_temp_file(config, ".webp")?;
+    let frames_dir = tempfile::Builder::new().prefix("reflexu-frames").tempdir_in(&config.temp_dir)?;
+    let frame_pattern = frames_dir.path().join("frame_%04d.png");
+    fs::write(input_file.path(), input_bytes).await?;
+
+    let permit = acquire_ffmpeg_permit(config).await;
+    let extract = Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-t", &format!("{:.3}", preview.seconds),
+            "-i", input_file.path().to_str().unwrap(),
+            "-vf", &format!("fps={},scale={}:-2", preview.fps, preview.width),
+            "-frames:v", &preview.max_frames().to_string(),
+            frame_pattern.to_str().unwrap(),
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    drop(permit);
+    if !extract.status.success() {
+        return Err(format!("FFmpeg frame extraction failed: {}", String::from_utf8_lossy(&extract.stderr).trim()).into());
+    }
+
+    let mut frames = std::fs::read_dir(frames_dir.path())?.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect::<Vec<_>>();
+    frames.sort();
+    if frames.is_empty() {
+        return Err("Video has no frames to extract".into());
+    }
+    for frame in &frames {
+        let watermarked = watermark_image(image::open(frame)?, branding);
+        watermarked.save_with_format(frame, image::ImageFormat::Png)?;
+    }
+
+    let permit = acquire_ffmpeg_permit(config).await;
+    let encode = Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-framerate", &preview.fps.to_string(),
+            "-i", frame_pattern.to_str().unwrap(),
+            "-c:v", "libwebp_anim",
+            "-quality", "50",
+            "-loop", "0",
+            "-an",
+            output_file.path().to_str().unwrap(),
+        ])
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    drop(permit);
+    if !encode.status.success() {
+        return Err(format!("FFmpeg WebP encoding failed: {}", String::from_utf8_lossy(&encode.stderr).trim()).into());
+    }
+
+    let webp = fs::read(output_file.path()).await?;
+    if webp.is_empty() {
+        return Err("FFmpeg wrote an empty WebP preview".into());
+    }
+    if webp.len() > preview.max_bytes {
+        return Err(format!("WebP preview is {}KB, over VIDEO_WEBP_MAX_KB ({}KB)", webp.len() / 1024, preview.max_bytes / 1024).into());
+    }
+    log!("🎞️  WebP preview: {} frames, {}KB", frames.len(), webp.len() / 1024);
+    Ok(webp)
+}
+
+/// Grabs a single frame at `at_seconds` using FFmpeg. Falls back to the first frame
+/// when the clip is shorter than the requested timestamp.
+#[cfg(feature = "video")]
+async fn extract_video_frame(config: &Config, input_bytes: &[u8], at_seconds: f64) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let input_file = new_temp_file(config, ".mp4")?;
+    let output_file = new_temp_file(config, ".png")?;
+
+    fs::write(input_file.path(), input_bytes).await?;
+
+    let _permit = acquire_ffmpeg_permit(config).await;
+    for seek in [at_seconds, 0.0] {
+        let ffmpeg_output = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-ss", &format!("{:.3}", seek.max(0.0)),
+                "-i", input_file.path().to_str().unwrap(),
+                "-frames:v", "1",
+                "-update", "1",
+                output_file.path().to_str().unwrap(),
+            ])
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !ffmpeg_output.status.success() {
+            let stderr = String::from_utf8_lossy(&ffmpeg_output.stderr);
+            return Err(format!("FFmpeg frame extraction failed: {}", stderr.trim()).into());
+        }
+
+        // Seeking past the end succeeds but writes nothing
+        let frame_bytes = fs::read(output_file.path()).await.unwrap_or_default();
+        if !frame_bytes.is_empty() {
+            return Ok(image::load_from_memory(&frame_bytes)?);
+        }
+    }
+
+    Err("Video has no frames to extract".into())
+}
+
+type HttpClient = hyper_util::client::legacy::Client<
+    hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>,
+    Full<Bytes>,
+>;
+
+/// Bound on connecting and on each webhook attempt, so a stuck endpoint can't stall a cycle
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shared HTTP(S) client for outgoing webhooks
+fn http_client() -> Result<&'static HttpClient, Box<dyn std::error::Error>> {
+    static CLIENT: OnceLock<HttpClient> = OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+
+    let mut http = hyper_util::client::legacy::connect::HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(WEBHOOK_TIMEOUT));
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_provider_and_native_roots(rustls::crypto::aws_lc_rs::default_provider())?
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http);
+    let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(https);
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+/// POSTs a JSON body, retrying up to 3 attempts with a growing delay. Each attempt is
+/// cut off after `WEBHOOK_TIMEOUT`.
+async fn post_json(url: &str, body: String) -> Result<(), Box<dyn std::error::Error>> {
+    let client = http_client()?;
+    let mut attempt = 1;
+    loop {
+        let request = Request::post(url)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(body.clone())))?;
+        let error = match tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => return Ok(()),
+            Ok(Ok(response)) => format!("HTTP {}", response.status()),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("timed out after {}s", WEBHOOK_TIMEOUT.as_secs()),
+        };
+
+        if attempt >= 3 {
+            return Err(format!("giving up after {} attempts: {}", attempt, error).into());
+        }
+        log_error!("⚠️  POST to {} failed (attempt {}): {}, retrying...", url, attempt, error);
+        sleep(Duration::from_secs(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Most failures listed individually in a notification
+const MAX_NOTIFIED_FAILURES: usize = 20;
+
+/// Slack-compatible `{"text": ...}` summary of a cycle's failures
+fn failure_summary_payload(config: &Config, failures: &[(String, String)]) -> String {
+    let mut text = format!("⚠️ Reflexu worker: {} failure(s) this cycle", failures.len());
+    for (subject, error) in failures.iter().take(MAX_NOTIFIED_FAILURES) {
+        match user_id_from_key(config, subject) {
+            Some(user_id) => text.push_str(&format!("\n• [{}] {}: {}", user_id, subject, error)),
+            None => text.push_str(&format!("\n• {}: {}", subject, error)),
+        }
+    }
+    if failures.len() > MAX_NOTIFIED_FAILURES {
+        text.push_str(&format!("\n…and {} more", failures.len() - MAX_NOTIFIED_FAILURES));
+    }
+
+    let mut out = String::new();
+    let mut object = JsonObjectWriter::new(&mut out);
+    object.key("text").string(&text);
+    object.finish();
+    out
+}
+
+/// Posts a single summary of the cycle's failures to `NOTIFY_WEBHOOK_URL` (if set).
+/// Failures to notify are logged, never fatal.
+async fn notify_failures(config: &Config, failures: &[(String, String)]) {
+    if failures.is_empty() {
+        return;
+    }
+    let Some(url) = &config.notify_webhook_url else { return };
+
+    match post_json(url, failure_summary_payload(config, failures)).await {
+        Ok(()) => log!("🔔 Sent failure summary ({} failures)", failures.len()),
+        Err(e) => log_error!("⚠️  Failure notification failed: {}", e),
+    }
+}
+
+/// JSON body sent to `WEBHOOK_URL` when a preview is uploaded
+fn preview_ready_payload(config: &Config, original_key: &str, preview_key: &str, bytes: usize, media_type: &str) -> String {
+    let mut out = String::new();
+    let mut object = JsonObjectWriter::new(&mut out);
+    match user_id_from_key(config, original_key) {
+        Some(user_id) => object.key("uuid").string(&user_id),
+        None => object.key("uuid").null(),
+    }
+    object.key("original_key").string(original_key);
+    object.key("preview_key").string(preview_key);
+    object.key("bytes").number(Number::PosInt(bytes as u64));
+    object.key("media_type").string(media_type);
+    object.finish();
+    out
+}
+
+/// Key of the sidecar of a preview: the preview's key with a `.json` extension, i.e.
+/// `{base}-watermark{size}.json` with the default `DEST_KEY_PATTERN`
+fn sidecar_key(config: &Config, watermarks_prefix: &str, base: &str, size: Option<u32>) -> String {
+    dest_key(config, watermarks_prefix, base, "json", size)
+}
+
+/// Sidecar describing an image preview, so galleries can show resolution/format
+/// badges without re-probing the file
+fn sidecar_payload(original: (u32, u32), preview: (u32, u32), format: &str, bytes: usize, branding: &Branding, processed_at: u64) -> String {
+    let mut out = String::new();
+    let mut object = JsonObjectWriter::new(&mut out);
+    object.key("original_width").number(Number::PosInt(original.0 as u64));
+    object.key("original_height").number(Number::PosInt(original.1 as u64));
+    object.key("width").number(Number::PosInt(preview.0 as u64));
+    object.key("height").number(Number::PosInt(preview.1 as u64));
+    object.key("format").string(format);
+    object.key("bytes").number(Number::PosInt(bytes as u64));
+    let mut watermark = object.key("watermark").start_object();
+    watermark.key("text").string(&branding.text);
+    watermark.key("brand").string(&branding.brand);
+    watermark.key("opacity").number(Number::Float(branding.opacity as f64));
+    match &branding.copyright {
+        Some(copyright) => watermark.key("copyright").string(copyright),
+        None => watermark.key("copyright").null(),
+    }
+    watermark.key("custom_logo").boolean(branding.logo.is_some());
+    watermark.finish();
+    match DateTime::from_secs(processed_at as i64).fmt(DateTimeFormat::DateTime) {
+        Ok(timestamp) => object.key("processed_at").string(&timestamp),
+        Err(_) => object.key("processed_at").null(),
+    }
+    object.finish();
+    out
+}
+
+/// Tells `WEBHOOK_URL` (if set) that a preview is ready. Failures are logged, never fatal.
+async fn notify_preview_ready(config: &Config, original_key: &str, preview_key: &str, bytes: usize, media_type: &str) {
+    let Some(url) = &config.webhook_url else { return };
+
+    let payload = preview_ready_payload(config, original_key, preview_key, bytes, media_type);
+    match post_json(url, payload).await {
+        Ok(()) => log!("🔔 Webhook notified for {}", preview_key),
+        Err(e) => log_error!("⚠️  Webhook for {} failed: {}", preview_key, e),
+    }
+}
+
+/// Crate version plus the git commit and build time embedded by `build.rs`
+fn version_json() -> String {
+    let built_at = env!("REFLEXU_BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| DateTime::from_secs(secs).fmt(DateTimeFormat::DateTime).ok());
+
+    let mut out = String::new();
+    let mut object = JsonObjectWriter::new(&mut out);
+    object.key("version").string(env!("CARGO_PKG_VERSION"));
+    object.key("git_commit").string(env!("REFLEXU_GIT_COMMIT"));
+    match built_at {
+        Some(built_at) => object.key("built_at").string(&built_at),
+        None => object.key("built_at").null(),
+    }
+    object.finish();
+    out
 }
 
-async fn start_health_server() {
-    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
-    println!("🔧 Health check server listening on port 8080");
+/// Address of the health check server in continuous mode
+const HEALTH_ADDR: &str = "0.0.0.0:8080";
+
+/// Binds the health check listener, logging a clear error if the port is unavailable
+async fn bind_health_server(addr: &str) -> Result<TcpListener, Box<dyn std::error::Error>> {
+    match TcpListener::bind(addr).await {
+        Ok(listener) => {
+            log!("🔧 Health check server listening on {}", addr);
+            Ok(listener)
+        }
+        Err(e) => {
+            log_error!("❌ Health check server could not bind {}: {}", addr, e);
+            Err(format!("health check server could not bind {} (is another process using the port?): {}", addr, e).into())
+        }
+    }
+}
 
+/// Serves health checks forever. Accept errors (e.g. running out of file descriptors)
+/// are logged and retried after a short pause instead of ending the task.
+async fn serve_health(listener: TcpListener, status: SharedStatus) {
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log_error!("⚠️  Health check server failed to accept a connection: {}", e);
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
         let io = TokioIo::new(stream);
+        let status = status.clone();
 
         tokio::task::spawn(async move {
             if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(health_handler))
+                .serve_connection(io, service_fn(move |req| health_handler(req, status.clone())))
                 .await
             {
-                println!("Error serving connection: {:?}", err);
+                log!("Error serving connection: {:?}", err);
             }
         });
     }
 }
 
-async fn health_handler(_req: Request<IncomingBody>) -> Result<Response<String>, hyper::Error> {
-    Ok(Response::new("OK".to_string()))
+async fn health_handler(req: Request<IncomingBody>, status: SharedStatus) -> Result<Response<String>, hyper::Error> {
+    match req.uri().path() {
+        "/status" => {
+            let body = status.read().unwrap().to_json();
+            Ok(Response::builder()
+                .header("Content-Type", "application/json")
+                .body(body)
+                .expect("static response parts are valid"))
+        }
+        "/version" => Ok(Response::builder()
+            .header("Content-Type", "application/json")
+            .body(version_json())
+            .expect("static response parts are valid")),
+        _ => Ok(Response::new("OK".to_string())),
+    }
+}
+
+/// All files below `dir` (recursively, sorted), leaving out `output_dir`
+async fn collect_local_files(dir: &Path, output_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    let mut pending_dirs = vec![dir.to_path_buf()];
+
+    while let Some(current) = pending_dirs.pop() {
+        let mut entries = fs::read_dir(&current).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                if path != output_dir {
+                    pending_dirs.push(path);
+                }
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Makes sure the local output directory exists, returning whether it had to be created.
+/// A stray file at that path is reported instead of surfacing later as a write error.
+async fn ensure_output_dir(output_dir: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    match fs::metadata(output_dir).await {
+        Ok(metadata) if metadata.is_dir() => Ok(false),
+        Ok(_) => Err(format!(
+            "Local output path '{}' exists but is not a directory; remove it or set LOCAL_OUTPUT_DIR",
+            output_dir.display()
+        )
+        .into()),
+        Err(_) => {
+            fs::create_dir_all(output_dir).await?;
+            Ok(true)
+        }
+    }
+}
+
+/// `{output_dir}/{relative dir}/{stem}-watermarked.{ext}`, creating the directory
+async fn local_output_path(output_dir: &Path, relative: &Path, ext: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let dir = match relative.parent() {
+        Some(parent) => output_dir.join(parent),
+        None => output_dir.to_path_buf(),
+    };
+    fs::create_dir_all(&dir).await?;
+    let stem = relative.file_stem().and_then(|stem| stem.to_str()).unwrap_or("output");
+    Ok(dir.join(format!("{}-watermarked.{}", stem, ext)))
 }
 
-async fn test_local_files() -> Result<(), Box<dyn std::error::Error>> {
-    println!("🧪 Starting local test mode...");
+async fn test_local_files(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    log!("🧪 Starting local test mode...");
     let total_start = Instant::now();
 
+    let assets_dir = config.local_input_dir.clone();
+    if !assets_dir.is_dir() {
+        return Err(format!("Local input directory '{}' does not exist (set LOCAL_INPUT_DIR)", assets_dir.display()).into());
+    }
+
     // Create output directory for watermarked files
-    let output_dir = PathBuf::from("assets/watermarked");
-    if !output_dir.exists() {
-        fs::create_dir(&output_dir).await?;
-        println!("📁 Created output directory: {}", output_dir.display());
+    let output_dir = config.local_output_dir.clone();
+    if ensure_output_dir(&output_dir).await? {
+        log!("📁 Created output directory: {}", output_dir.display());
     }
 
-    // Read all files from assets directory
-    let assets_dir = PathBuf::from("assets");
-    let mut entries = fs::read_dir(&assets_dir).await?;
+    // Walk the input directory, nested folders included
+    let files = collect_local_files(&assets_dir, &output_dir).await?;
 
+    let branding = &config.branding;
     let mut processed_count = 0;
     let mut total_processing_time = 0.0;
 
-    while let Some(entry) = entries.next_entry().await? {
-        let path = entry.path();
-
-        // Skip directories and the watermarked output directory
-        if path.is_dir() || path.file_name().unwrap() == "watermarked" {
-            continue;
-        }
-
-        let filename = path.file_name().unwrap().to_str().unwrap();
+    for path in files {
+        // Relative path, mirrored under the output directory
+        let relative = path.strip_prefix(&assets_dir).unwrap_or(&path).to_path_buf();
+        let filename = relative.to_string_lossy().to_string();
         let ext = path.extension()
             .unwrap_or_default()
             .to_string_lossy()
             .to_lowercase();
 
         // Skip the logo file since it's used for watermarking
-        if filename == "logo.png" {
-            println!("⏭️  Skipping logo file (used for watermarking): {}", filename);
+        if path == Path::new(BUNDLED_LOGO_PATH) {
+            log!("⏭️  Skipping logo file (used for watermarking): {}", filename);
             continue;
         }
 
-        println!("\n📂 Processing: {}", filename);
+        log!("\n📂 Processing: {}", filename);
         let file_start = Instant::now();
 
         match ext.as_str() {
-            "jpg" | "jpeg" | "png" => {
-                println!("🖼️  Processing image: {}", filename);
+            "jpg" | "jpeg" | "png" | "avif" | "tif" | "tiff" | "bmp" => {
+                log!("🖼️  Processing image: {}", filename);
                 let read_start = Instant::now();
                 let body = fs::read(&path).await?;
                 let file_size_mb = body.len() as f64 / 1024.0 / 1024.0;
-                println!("   Read time: {:.2}ms (Size: {:.1}MB)", read_start.elapsed().as_secs_f64() * 1000.0, file_size_mb);
+                log!("   Read time: {:.2}ms (Size: {:.1}MB)", read_start.elapsed().as_secs_f64() * 1000.0, file_size_mb);
+
+                if body.len() as u64 > config.max_image_bytes {
+                    log!("⚠️  Skipping large image ({:.1}MB): {}", file_size_mb, filename);
+                    continue;
+                }
 
                 let decode_start = Instant::now();
                 // Use same logic as production code for large images
                 let img = if file_size_mb > 20.0 {
-                    println!("📁 Large image detected, using temp file approach");
-                    let temp_file = NamedTempFile::with_suffix(&format!(".{}", ext))?;
+                    log!("📁 Large image detected, using temp file approach");
+                    let temp_file = new_temp_file(config, &format!(".{}", ext))?;
                     let temp_path = temp_file.path().to_path_buf();
                     fs::write(&temp_path, &body).await?;
 
                     match image::open(&temp_path) {
                         Ok(img) => img,
                         Err(e) => {
-                            eprintln!("❌ Failed to load large image {}: {}", filename, e);
+                            log_error!("❌ Failed to load large image {}: {}", filename, e);
                             continue;
                         }
                     }
@@ -787,116 +5614,1366 @@ async fn test_local_files() -> Result<(), Box<dyn std::error::Error>> {
                     match image::load_from_memory(&body) {
                         Ok(img) => img,
                         Err(e) => {
-                            eprintln!("❌ Failed to decode image {}: {}", filename, e);
+                            log_error!("❌ Failed to decode image {}: {}", filename, e);
                             continue;
                         }
                     }
                 };
                 let (orig_width, orig_height) = img.dimensions();
-                println!("   Decode time: {:.2}ms ({}x{})", decode_start.elapsed().as_secs_f64() * 1000.0, orig_width, orig_height);
+                log!("   Decode time: {:.2}ms ({}x{})", decode_start.elapsed().as_secs_f64() * 1000.0, orig_width, orig_height);
 
                 // Resize image to max 800px for preview (lower quality for protection)
                 let resize_start = Instant::now();
-                let max_dimension = 800u32;
+                let max_dimension = config.resize_limit(config.max_dimension);
                 let resized_img = if orig_width > max_dimension || orig_height > max_dimension {
-                    let ratio = if orig_width > orig_height {
-                        max_dimension as f32 / orig_width as f32
-                    } else {
-                        max_dimension as f32 / orig_height as f32
-                    };
-                    let new_width = (orig_width as f32 * ratio) as u32;
-                    let new_height = (orig_height as f32 * ratio) as u32;
-                    println!("📐 Resizing from {}x{} to {}x{}", orig_width, orig_height, new_width, new_height);
+                    let (new_width, new_height) = preview_dimensions(orig_width, orig_height, max_dimension);
+                    log!("📐 Resizing from {}x{} to {}x{}", orig_width, orig_height, new_width, new_height);
                     // Use Nearest filter for fastest possible resizing
                     let resized = img.resize_exact(new_width, new_height, imageops::FilterType::Nearest);
-                    println!("   Resize time: {:.2}ms", resize_start.elapsed().as_secs_f64() * 1000.0);
+                    log!("   Resize time: {:.2}ms", resize_start.elapsed().as_secs_f64() * 1000.0);
                     resized
                 } else {
-                    println!("📐 Image size {}x{} is already optimal", orig_width, orig_height);
+                    log!("📐 Image size {}x{} is already optimal", orig_width, orig_height);
                     img
                 };
 
-                println!("🖋️  Applying watermark...");
+                log!("🖋️  Applying watermark...");
                 let watermark_start = Instant::now();
-                let watermarked = watermark_image(resized_img, "REFLEXU PREVIEW");
-                println!("   Watermark time: {:.2}ms", watermark_start.elapsed().as_secs_f64() * 1000.0);
-
-                let output_path = output_dir.join(format!("{}-watermarked.jpg",
-                    path.file_stem().unwrap().to_str().unwrap()));
+                let watermarked = watermark_image(apply_protection(resized_img, branding.settings.protection), branding);
+                log!("   Watermark time: {:.2}ms", watermark_start.elapsed().as_secs_f64() * 1000.0);
 
                 let encode_start = Instant::now();
-                let mut buf = Cursor::new(Vec::new());
-                watermarked.write_to(&mut buf, image::ImageOutputFormat::Jpeg(85))?;
-                println!("   Encode time: {:.2}ms", encode_start.elapsed().as_secs_f64() * 1000.0);
+                let (output_bytes, output_ext) = match config.preview_format {
+                    PreviewFormat::Avif => (encode_preview(&watermarked, PreviewFormat::Avif, config.jpeg_quality, config.jpeg_subsampling, false).await?, "avif"),
+                    PreviewFormat::Jpeg => (encode_preview(&watermarked, PreviewFormat::Jpeg, 85, config.jpeg_subsampling, config.jpeg_progressive).await?, "jpg"),
+                };
+                log!("   Encode time: {:.2}ms", encode_start.elapsed().as_secs_f64() * 1000.0);
+                let output_path = local_output_path(&output_dir, &relative, output_ext).await?;
 
                 let write_start = Instant::now();
-                fs::write(&output_path, buf.into_inner()).await?;
-                println!("   Write time: {:.2}ms", write_start.elapsed().as_secs_f64() * 1000.0);
+                fs::write(&output_path, output_bytes).await?;
+                log!("   Write time: {:.2}ms", write_start.elapsed().as_secs_f64() * 1000.0);
+
+                let file_time = file_start.elapsed().as_secs_f64();
+                log!("✅ Saved watermarked image: {} (Total: {:.2}s)", output_path.display(), file_time);
+                processed_count += 1;
+                total_processing_time += file_time;
+            }
+            "gif" => {
+                log!("🎞️  Processing animated GIF: {}", filename);
+                let body = fs::read(&path).await?;
+                if body.len() as u64 > config.max_image_bytes {
+                    log!("⚠️  Skipping large GIF: {}", filename);
+                    continue;
+                }
+
+                let watermark_start = Instant::now();
+                let gif = match watermark_gif(config, &body, config.resize_limit(config.max_dimension), branding) {
+                    Ok(gif) => gif,
+                    Err(e) => {
+                        log_error!("❌ Failed to watermark GIF {}: {}", filename, e);
+                        continue;
+                    }
+                };
+                log!("   Watermark time: {:.2}s", watermark_start.elapsed().as_secs_f64());
+
+                let output_path = local_output_path(&output_dir, &relative, "gif").await?;
+                fs::write(&output_path, gif).await?;
 
                 let file_time = file_start.elapsed().as_secs_f64();
-                println!("✅ Saved watermarked image: {} (Total: {:.2}s)", output_path.display(), file_time);
+                log!("✅ Saved watermarked GIF: {} (Total: {:.2}s)", output_path.display(), file_time);
                 processed_count += 1;
                 total_processing_time += file_time;
             }
+            #[cfg(feature = "video")]
             "mp4" | "mov" | "webm" => {
-                println!("🎥 Processing video: {}", filename);
+                log!("🎥 Processing video: {}", filename);
                 let read_start = Instant::now();
                 let body = fs::read(&path).await?;
                 let file_size_mb = body.len() as f64 / 1024.0 / 1024.0;
-                println!("   Read time: {:.2}s", read_start.elapsed().as_secs_f64());
+                log!("   Read time: {:.2}s", read_start.elapsed().as_secs_f64());
 
-                if file_size_mb > 300.0 {
-                    println!("⚠️  Skipping large video ({}MB): {}", file_size_mb as u32, filename);
+                if file_size_mb > config.video.max_mb {
+                    log!("⚠️  Skipping large video ({}MB): {}", file_size_mb as u32, filename);
                     continue;
                 }
 
-                println!("🎬 Watermarking video ({:.1}MB)...", file_size_mb);
+                log!("🎬 Watermarking video ({:.1}MB)...", file_size_mb);
 
                 let watermark_start = Instant::now();
-                let timeout_duration = Duration::from_secs(300);
-                let watermarked = match tokio::time::timeout(timeout_duration, watermark_video(&body, "REFLEXU PREVIEW")).await {
-                    Ok(Ok(v)) => {
-                        println!("   Watermark time: {:.2}s", watermark_start.elapsed().as_secs_f64());
-                        println!("✅ Video watermarking completed");
-                        v
+                let timeout_duration = config.video.timeout;
+                let watermarked = match watermark_video_with_timeout(config, &body, branding).await {
+                    Ok(Ok(output)) => {
+                        log!("   Watermark time: {:.2}s", watermark_start.elapsed().as_secs_f64());
+                        log!("✅ Video watermarking completed");
+                        output
                     },
                     Ok(Err(e)) => {
-                        eprintln!("❌ Failed to watermark video {}: {}", filename, e);
+                        log_error!("❌ Failed to watermark video {}: {}", filename, e);
                         continue;
                     },
                     Err(_) => {
-                        eprintln!("❌ Video watermarking timed out: {}", filename);
+                        log_error!("❌ Video watermarking timed out after {}s: {}", timeout_duration.as_secs(), filename);
                         continue;
                     }
                 };
 
                 let write_start = Instant::now();
-                let output_path = output_dir.join(format!("{}-watermarked.{}",
-                    path.file_stem().unwrap().to_str().unwrap(), ext));
-                fs::write(&output_path, watermarked).await?;
-                println!("   Write time: {:.2}s", write_start.elapsed().as_secs_f64());
+                // Like the S3 path, the extension follows the muxed container, not the input
+                let output_path = local_output_path(&output_dir, &relative, config.video_output_format.extension()).await?;
+                fs::copy(watermarked.path(), &output_path).await?;
+                log!("   Write time: {:.2}s", write_start.elapsed().as_secs_f64());
 
                 let file_time = file_start.elapsed().as_secs_f64();
-                println!("✅ Saved watermarked video: {} (Total: {:.2}s)", output_path.display(), file_time);
+                log!("✅ Saved watermarked video: {} (Total: {:.2}s)", output_path.display(), file_time);
                 processed_count += 1;
                 total_processing_time += file_time;
             }
+            #[cfg(not(feature = "video"))]
+            "mp4" | "mov" | "webm" => {
+                log!("⏭️  Skipping video (video support not compiled in, build with --features video): {}", filename);
+            }
+            raw if is_raw_extension(raw) => {
+                log!("📷 RAW format (.{}) not supported, please upload JPEG/PNG: {}", raw, filename);
+            }
             _ => {
-                println!("⏭️  Skipping unsupported file: {}", filename);
+                log!("⏭️  Skipping unsupported file: {}", filename);
             }
         }
     }
 
     let total_time = total_start.elapsed().as_secs_f64();
-    println!("\n{}", "=".repeat(60));
-    println!("📊 PERFORMANCE SUMMARY");
-    println!("{}", "=".repeat(60));
-    println!("📁 Files processed: {}", processed_count);
-    println!("⏱️  Total execution time: {:.2}s", total_time);
-    println!("⚡ Average time per file: {:.2}s", if processed_count > 0 { total_processing_time / processed_count as f64 } else { 0.0 });
-    println!("🔄 Processing time only: {:.2}s", total_processing_time);
-    println!("🔧 Overhead time: {:.2}s", total_time - total_processing_time);
-    println!("{}", "=".repeat(60));
-    println!("🎉 Local test completed! Check assets/watermarked/ for results");
+    log!("\n{}", "=".repeat(60));
+    log!("📊 PERFORMANCE SUMMARY");
+    log!("{}", "=".repeat(60));
+    log!("📁 Files processed: {}", processed_count);
+    log!("⏱️  Total execution time: {:.2}s", total_time);
+    log!("⚡ Average time per file: {:.2}s", if processed_count > 0 { total_processing_time / processed_count as f64 } else { 0.0 });
+    log!("🔄 Processing time only: {:.2}s", total_processing_time);
+    log!("🔧 Overhead time: {:.2}s", total_time - total_processing_time);
+    log!("{}", "=".repeat(60));
+    log!("🎉 Local test completed! Check {}/ for results", output_dir.display());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_filename_keeps_inner_dots_in_base() {
+        assert_eq!(split_filename("my.photo.final.jpg"), Some(("my.photo.final", "jpg".to_string())));
+        assert_eq!(split_filename("photo.jpg.jpg"), Some(("photo.jpg", "jpg".to_string())));
+    }
+
+    #[test]
+    fn split_filename_lowercases_extension() {
+        assert_eq!(split_filename("IMG_0001.JPG"), Some(("IMG_0001", "jpg".to_string())));
+        assert_eq!(split_filename("clip.MoV"), Some(("clip", "mov".to_string())));
+    }
+
+    #[test]
+    fn split_filename_without_extension() {
+        assert_eq!(split_filename("README"), None);
+    }
+
+    fn parse_cli(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("reflexu_worker_rust").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn cli_definition_is_valid() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn cli_without_command_defers_to_env() {
+        let cli = parse_cli(&[]).unwrap();
+        assert_eq!(cli.command, None);
+        assert_eq!(cli.settings.interval_minutes, None);
+    }
+
+    #[test]
+    fn cli_parses_subcommands_and_overrides() {
+        let cli = parse_cli(&["run", "--interval-minutes", "5", "--video-output-format=WEBM"]).unwrap();
+        assert_eq!(cli.command, Some(CliCommand::Run));
+        assert_eq!(cli.settings.interval_minutes.as_deref(), Some("5"));
+        let config = Config::from_env(false, &cli.settings).unwrap();
+        assert_eq!(config.interval_minutes, 5);
+        assert_eq!(config.video_output_format, VideoOutputFormat::Webm);
+
+        let cli = parse_cli(&["single", "--key", "users/u/events/e/originals/a.jpg"]).unwrap();
+        assert_eq!(cli.command, Some(CliCommand::Single { key: "users/u/events/e/originals/a.jpg".to_string() }));
+    }
+
+    #[test]
+    fn cli_rejects_invalid_arguments() {
+        assert!(parse_cli(&["single"]).is_err());
+        assert!(parse_cli(&["once", "--key", "a.jpg"]).is_err());
+        assert!(parse_cli(&["--bogus"]).is_err());
+        assert!(parse_cli(&["--interval-minutes"]).is_err());
+
+        // Malformed values parse but are reported by the config check
+        let cli = parse_cli(&["--interval-minutes", "soon", "--video-output-format", "avi"]).unwrap();
+        let problems = Config::from_env(false, &cli.settings).unwrap_err().to_string();
+        assert!(problems.contains("INTERVAL_MINUTES must be a whole number"), "{}", problems);
+        assert!(problems.contains("VIDEO_OUTPUT_FORMAT must be one of mp4, webm"), "{}", problems);
+    }
+
+    #[test]
+    fn status_json_reports_cycle_state() {
+        let mut status = WorkerStatus::default();
+        assert_eq!(status.to_json(), r#"{"last_success":null,"cycle_in_progress":false,"files_processed":0,"last_cycle":null}"#);
+
+        status.start_cycle();
+        status.files_processed = 3;
+        assert_eq!(status.to_json(), r#"{"last_success":null,"cycle_in_progress":true,"files_processed":3,"last_cycle":null}"#);
+
+        status.last_success = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        status.finish_cycle(false);
+        assert_eq!(
+            status.to_json(),
+            r#"{"last_success":"2023-11-14T22:13:20Z","cycle_in_progress":false,"files_processed":3,"last_cycle":null}"#
+        );
+
+        status.last_cycle = Some(CycleSummary {
+            files_processed: 3,
+            total_seconds: 10.0,
+            processing_seconds: 6.0,
+            bytes_downloaded: 3_000_000,
+            bytes_uploaded: 300_000,
+        });
+        assert!(status.to_json().ends_with(
+            r#""last_cycle":{"files_processed":3,"total_seconds":10.0,"average_seconds_per_file":2.0,"processing_seconds":6.0,"overhead_seconds":4.0,"bytes_downloaded":3000000,"bytes_uploaded":300000}}"#
+        ));
+    }
+
+    #[test]
+    fn branding_overrides_apply_known_fields() {
+        let mut branding = Branding::from_env();
+        let logo_key = branding
+            .apply_overrides(br#"{"text": "studio.example", "brand": "STUDIO", "logo_key": "brands/logo.png", "opacity": 0.3}"#)
+            .unwrap();
+        assert_eq!(branding.text, "studio.example");
+        assert_eq!(branding.brand, "STUDIO");
+        assert_eq!(branding.opacity, 0.3);
+        assert_eq!(logo_key.as_deref(), Some("brands/logo.png"));
+    }
+
+    #[test]
+    fn copyright_line_fills_in_current_year() {
+        let mut branding = Branding::from_env();
+        branding.apply_overrides(r#"{"copyright": "© {year} Studio"}"#.as_bytes()).unwrap();
+        let line = branding.copyright_line().unwrap();
+        let year = current_year();
+        assert_eq!(year.len(), 4);
+        assert!(year.as_str() >= "2025");
+        assert_eq!(line, format!("© {} Studio", year));
+    }
+
+    #[test]
+    fn branding_overrides_reject_invalid_json() {
+        let mut branding = Branding::from_env();
+        assert!(branding.apply_overrides(b"not json").is_err());
+        assert!(branding.apply_overrides(b"[1, 2]").is_err());
+    }
+
+    #[test]
+    fn preview_ready_payload_is_json() {
+        let config = Config::from_env(false, &CliSettings::default()).unwrap();
+        assert_eq!(
+            preview_ready_payload(&config, "users/abc/events/e/originals/a \"1\".jpg", "users/abc/events/e/watermarks/a-watermark.jpg", 1234, "image"),
+            r#"{"uuid":"abc","original_key":"users/abc/events/e/originals/a \"1\".jpg","preview_key":"users/abc/events/e/watermarks/a-watermark.jpg","bytes":1234,"media_type":"image"}"#
+        );
+    }
+
+    #[test]
+    fn failure_summary_lists_and_caps_failures() {
+        let failures: Vec<(String, String)> = (0..MAX_NOTIFIED_FAILURES + 2)
+            .map(|i| (format!("users/abc/events/e/originals/{}.jpg", i), "boom".to_string()))
+            .collect();
+        let payload = failure_summary_payload(&Config::from_env(false, &CliSettings::default()).unwrap(), &failures);
+        assert!(payload.starts_with(r#"{"text":"⚠️ Reflexu worker: 22 failure(s) this cycle\n• [abc] users/abc/events/e/originals/0.jpg: boom"#));
+        assert!(payload.ends_with(r#"…and 2 more"}"#));
+    }
+
+    /// End-to-end run against a disposable S3-compatible endpoint (MinIO, LocalStack).
+    /// Needs the usual storage variables (e.g. `S3_PROVIDER=minio`, `S3_ENDPOINT`,
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`) plus `S3_TEST_BUCKET`:
+    /// `cargo test -- --ignored s3_round_trip`
+    #[tokio::test]
+    #[ignore]
+    async fn s3_round_trip_watermarks_seeded_original() {
+        let Ok(bucket) = env::var("S3_TEST_BUCKET") else {
+            log_error!("S3_TEST_BUCKET not set, skipping");
+            return;
+        };
+        let mut config = Config::from_env(true, &Cli::parse_from(["reflexu_worker_rust"]).settings).unwrap();
+        config.bucket = bucket.clone();
+        let client = build_s3_client(&config).await.unwrap();
+        let _ = client.create_bucket().bucket(&bucket).send().await;
+
+        let user_id = format!("it-{}", fastrand::u64(..));
+        let event_prefix = format!("{}{}/events/e1/", config.user_prefix, user_id);
+        let originals_prefix = format!("{}{}", event_prefix, config.originals_folder);
+        let watermarks_prefix = format!("{}{}", event_prefix, config.watermarks_folder);
+        let original_key = format!("{}photo.jpg", originals_prefix);
+
+        let mut jpeg = Cursor::new(Vec::new());
+        synthetic_photo().write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(80)).unwrap();
+        client.put_object().bucket(&bucket).key(&original_key).body(jpeg.into_inner().into()).send().await.unwrap();
+        // Junk files must be ignored rather than failing the event
+        client.put_object().bucket(&bucket).key(format!("{}.DS_Store", originals_prefix)).body(Vec::new().into()).send().await.unwrap();
+
+        assert!(discover_user_ids(&config, &client).await.unwrap().contains(&user_id));
+        assert_eq!(discover_event_ids(&config, &client, &user_id).await.unwrap(), vec!["e1".to_string()]);
+
+        let status = SharedStatus::default();
+        process_files_in_paths(&config, &client, &originals_prefix, &watermarks_prefix, &config.branding, &status).await.unwrap();
+
+        let preview_key = dest_key(&config, &watermarks_prefix, "photo", "jpg", None);
+        let head = client.head_object().bucket(&bucket).key(&preview_key).send().await.unwrap();
+        assert_eq!(head.content_type(), Some("image/jpeg"));
+        assert_eq!(status.read().unwrap().files_processed, 1);
+
+        // A second pass finds the preview and skips the original
+        process_files_in_paths(&config, &client, &originals_prefix, &watermarks_prefix, &config.branding, &status).await.unwrap();
+        assert_eq!(status.read().unwrap().files_processed, 1);
+
+        for key in list_all_keys(&config, &client, &event_prefix).await.unwrap() {
+            let _ = client.delete_object().bucket(&bucket).key(key).send().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn post_json_sends_body_to_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("{\"ok\":true}") {
+                let n = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            tokio::io::AsyncWriteExt::write_all(&mut stream, b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        post_json(&url, r#"{"ok":true}"#.to_string()).await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1"));
+        assert!(request.to_lowercase().contains("content-type: application/json"));
+    }
+
+    #[test]
+    fn junk_files_are_detected() {
+        assert!(is_junk_file("users/u/events/e/originals/.DS_Store"));
+        assert!(is_junk_file("users/u/events/e/originals/Thumbs.db"));
+        assert!(is_junk_file("users/u/events/e/originals/__MACOSX/photo.jpg"));
+        assert!(is_junk_file("users/u/events/e/originals/.sync/photo.jpg"));
+        assert!(!is_junk_file("users/u/events/e/originals/photo.jpg"));
+        assert!(!is_junk_file("users/u/events/e/originals/my.photo.jpg"));
+    }
+
+    #[test]
+    fn dest_key_uses_stem_and_lowercase_extension() {
+        let config = Config::from_env(false, &CliSettings::default()).unwrap();
+        let (base, ext) = split_filename("my.photo.final.JPG").unwrap();
+        assert_eq!(
+            dest_key(&config, "users/u/events/e/watermarks/", base, &ext, None),
+            "users/u/events/e/watermarks/my.photo.final-watermark.jpg"
+        );
+        assert_eq!(
+            dest_key(&config, "users/u/events/e/watermarks/", base, &ext, Some(400)),
+            "users/u/events/e/watermarks/my.photo.final-watermark-400.jpg"
+        );
+    }
+
+    #[test]
+    fn expected_preview_keys_cover_sizes_formats_and_poster() {
+        let keys = expected_preview_keys(&Config::from_env(false, &CliSettings::default()).unwrap(), "users/u/events/e/originals/clip.MOV", "users/u/events/e/watermarks/", Some(&[400]));
+        for expected in [
+            "users/u/events/e/watermarks/clip-watermark.mov",
+            "users/u/events/e/watermarks/clip-watermark.mp4",
+            "users/u/events/e/watermarks/clip-watermark.webm",
+            "users/u/events/e/watermarks/clip-watermark-400.mov",
+            "users/u/events/e/watermarks/clip-poster.jpg",
+        ] {
+            assert!(keys.iter().any(|key| key == expected), "missing {}", expected);
+        }
+    }
+
+    fn synthetic_photo() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(640, 480, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, 90])))
+    }
+
+    fn assert_watermarked(original: &DynamicImage, watermarked: &DynamicImage) {
+        assert_eq!(watermarked.dimensions(), original.dimensions());
+        assert!(matches!(watermarked, DynamicImage::ImageRgba8(_)));
+        let before = original.to_rgba8();
+        let after = watermarked.to_rgba8();
+        assert!(before.pixels().zip(after.pixels()).any(|(a, b)| a != b), "no pixel changed");
+    }
+
+    #[test]
+    fn watermark_image_keeps_dimensions_and_draws() {
+        let img = synthetic_photo();
+        let watermarked = watermark_image(img.clone(), &Branding::from_env());
+        assert_watermarked(&img, &watermarked);
+    }
+
+    #[test]
+    fn watermark_image_falls_back_to_text_only_without_logo() {
+        let img = synthetic_photo();
+        let watermarked = watermark_image_with_logo_file(img.clone(), &Branding::from_env(), "assets/does-not-exist.png", WatermarkRegion::Full);
+        assert_watermarked(&img, &watermarked);
+    }
+
+    fn assert_rgb_close(actual: &Rgba<u8>, expected: [f32; 3]) {
+        for (channel, want) in expected.iter().enumerate() {
+            let got = actual[channel] as f32;
+            assert!((got - want).abs() <= 1.0, "channel {}: got {}, expected {}", channel, got, want);
+        }
+    }
+
+    #[test]
+    fn draw_logo_blends_rgb_with_logo_alpha_and_opacity() {
+        let mut canvas = RgbaImage::from_pixel(4, 4, Rgba([100, 100, 100, 255]));
+        let logo = RgbaImage::from_pixel(1, 1, Rgba([200, 0, 50, 128]));
+        draw_logo(&mut canvas, &logo, 1, 2, 0.5);
+
+        let alpha = 128.0 / 255.0 * 0.5;
+        let blend = |bg: f32, fg: f32| bg * (1.0 - alpha) + fg * alpha;
+        assert_rgb_close(canvas.get_pixel(1, 2), [blend(100.0, 200.0), blend(100.0, 0.0), blend(100.0, 50.0)]);
+        assert_eq!(canvas.get_pixel(0, 0), &Rgba([100, 100, 100, 255]));
+    }
+
+    #[test]
+    fn draw_logo_composites_over_transparent_canvas() {
+        let mut canvas = RgbaImage::from_pixel(2, 1, Rgba([0, 0, 0, 0]));
+        canvas.put_pixel(1, 0, Rgba([0, 0, 255, 128]));
+        let logo = RgbaImage::from_pixel(1, 1, Rgba([200, 0, 50, 128]));
+
+        // Fully transparent destination takes the logo color at the logo's coverage
+        draw_logo(&mut canvas, &logo, 0, 0, 1.0);
+        assert_rgb_close(canvas.get_pixel(0, 0), [200.0, 0.0, 50.0]);
+        assert_eq!(canvas.get_pixel(0, 0)[3], 128);
+
+        // Half-transparent destination: alpha accumulates, color is the weighted mix
+        draw_logo(&mut canvas, &logo, 1, 0, 1.0);
+        let (sa, da) = (128.0 / 255.0, 128.0 / 255.0);
+        let out_a: f32 = sa + da * (1.0 - sa);
+        let mix = |fg: f32, bg: f32| (fg * sa + bg * da * (1.0 - sa)) / out_a;
+        assert_rgb_close(canvas.get_pixel(1, 0), [mix(200.0, 0.0), mix(0.0, 0.0), mix(50.0, 255.0)]);
+        assert!((canvas.get_pixel(1, 0)[3] as f32 - out_a * 255.0).abs() <= 1.0);
+    }
+
+    #[test]
+    fn draw_logo_clips_to_canvas() {
+        let background = Rgba([10, 20, 30, 255]);
+        let mut canvas = RgbaImage::from_pixel(4, 4, background);
+        let logo = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 255]));
+
+        draw_logo(&mut canvas, &logo, -1, -1, 1.0);
+        draw_logo(&mut canvas, &logo, 3, 3, 1.0);
+        draw_logo(&mut canvas, &logo, 100, -100, 1.0);
+
+        for (x, y, pixel) in canvas.enumerate_pixels() {
+            let covered = (x, y) == (0, 0) || (x, y) == (3, 3);
+            if covered {
+                assert_rgb_close(pixel, [255.0, 255.0, 255.0]);
+            } else {
+                assert_eq!(pixel, &background, "pixel ({}, {}) was modified", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn watermark_image_marks_tiny_images() {
+        for (w, h) in [(60, 40), (8, 8), (1000, 12)] {
+            let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(w, h, image::Rgb([40, 40, 40])));
+            let watermarked = watermark_image(img.clone(), &Branding::from_env());
+            assert_watermarked(&img, &watermarked);
+        }
+    }
+
+    #[test]
+    fn watermark_gif_keeps_frames_and_delays() {
+        let delays = [image::Delay::from_numer_denom_ms(100, 1), image::Delay::from_numer_denom_ms(250, 1)];
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for (i, delay) in delays.iter().enumerate() {
+                let frame = RgbaImage::from_pixel(1200, 600, Rgba([40 * i as u8, 80, 120, 255]));
+                encoder.encode_frame(Frame::from_parts(frame, 0, 0, *delay)).unwrap();
+            }
+        }
+
+        let out = watermark_gif(&Config::from_env(false, &CliSettings::default()).unwrap(), &gif, 400, &Branding::from_env()).unwrap();
+        let frames = GifDecoder::new(Cursor::new(out)).unwrap().into_frames().collect_frames().unwrap();
+        assert_eq!(frames.len(), 2);
+        for (frame, delay) in frames.iter().zip(delays) {
+            assert_eq!(frame.buffer().dimensions(), (400, 200));
+            assert_eq!(frame.delay(), delay);
+        }
+    }
+
+    #[test]
+    fn text_block_size_stacks_lines() {
+        let font = watermark_font();
+        let scale = Scale::uniform(20.0);
+        let (single_width, single_height) = text_block_size(scale, font, "www.reflexu.com");
+        let (width, height) = text_block_size(scale, font, "STUDIO\nwww.reflexu.com");
+        assert_eq!(width, single_width);
+        assert_eq!(height, 2 * single_height);
+
+        let img = synthetic_photo();
+        let mut branding = Branding::from_env();
+        branding.text = "STUDIO\nwww.reflexu.com".to_string();
+        assert_watermarked(&img, &watermark_image(img.clone(), &branding));
+    }
+
+    #[test]
+    fn corner_watermark_stays_in_its_corner() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(400, 300, image::Rgb([30, 30, 30])));
+        let style = CornerStyle { corner: Corner::BottomRight, size: 0.12, opacity: Some(1.0), with_text: true };
+        let out = watermark_image_corner(img.clone(), &Branding::from_env(), BUNDLED_LOGO_PATH, &style);
+        assert_watermarked(&img, &out);
+
+        let before = img.to_rgba8();
+        let after = out.to_rgba8();
+        for (x, y, pixel) in after.enumerate_pixels() {
+            if x < 200 || y < 150 {
+                assert_eq!(pixel, before.get_pixel(x, y), "pixel ({}, {}) outside the corner changed", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn font_runs_keep_supported_text_in_one_run() {
+        let runs = font_runs(watermark_font(), "Café Zoë");
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].1, "Café Zoë");
+        assert!(text_width(Scale::uniform(20.0), watermark_font(), "Café Zoë") > 0);
+    }
+
+    #[test]
+    fn pixelate_keeps_dimensions_and_flattens_blocks() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(20, 10, |x, y| image::Rgba([(x * 12) as u8, (y * 25) as u8, 0, 255])));
+        let out = pixelate(&img, 5).to_rgba8();
+        assert_eq!(out.dimensions(), (20, 10));
+        assert_eq!(out.get_pixel(0, 0), out.get_pixel(4, 4));
+    }
+
+    #[test]
+    fn parse_hex_color_accepts_optional_hash() {
+        assert_eq!(parse_hex_color("#1a2B3c"), Some([0x1a, 0x2b, 0x3c]));
+        assert_eq!(parse_hex_color("000000"), Some([0, 0, 0]));
+        assert_eq!(parse_hex_color("fff"), None);
+        assert_eq!(parse_hex_color("zzzzzz"), None);
+    }
+
+    #[cfg(feature = "video")]
+    #[tokio::test]
+    async fn upload_body_streams_ranges_from_memory_and_files() {
+        let content: Vec<u8> = (0..=255).collect();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &content).unwrap();
+
+        for body in [UploadBody::from(content.clone()), UploadBody::file(file.path()).await.unwrap()] {
+            assert_eq!(body.len(), 256);
+            let part = body.stream(100, 50).await.unwrap().collect().await.unwrap().into_bytes();
+            assert_eq!(part.as_ref(), &content[100..150]);
+            let last = body.stream(250, 6).await.unwrap().collect().await.unwrap().into_bytes();
+            assert_eq!(last.as_ref(), &content[250..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn local_video_output_uses_muxed_extension() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let path = local_output_path(output_dir.path(), Path::new("clips/sample.mov"), VideoOutputFormat::Mp4.extension())
+            .await
+            .unwrap();
+        assert_eq!(path, output_dir.path().join("clips/sample-watermarked.mp4"));
+        assert!(output_dir.path().join("clips").is_dir());
+    }
+
+    #[tokio::test]
+    async fn local_output_dir_is_created_reused_or_rejected() {
+        let assets = tempfile::tempdir().unwrap();
+        let output_dir = assets.path().join("watermarked");
+        assert!(ensure_output_dir(&output_dir).await.unwrap());
+        assert!(!ensure_output_dir(&output_dir).await.unwrap());
+        assert!(output_dir.is_dir());
+
+        let stray_file = assets.path().join("stray");
+        std::fs::write(&stray_file, b"").unwrap();
+        let error = ensure_output_dir(&stray_file).await.unwrap_err();
+        assert!(error.to_string().contains("is not a directory"));
+    }
+
+    #[test]
+    fn image_preview_extension_maps_impractical_formats_to_jpg() {
+        assert_eq!(image_preview_extension("tiff", PreviewFormat::Jpeg), "jpg");
+        assert_eq!(image_preview_extension("bmp", PreviewFormat::Jpeg), "jpg");
+        assert_eq!(image_preview_extension("png", PreviewFormat::Jpeg), "png");
+        assert_eq!(image_preview_extension("tif", PreviewFormat::Avif), "avif");
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_cap() {
+        assert_eq!(retry_delay_seconds(1, 30, 600), 30);
+        assert_eq!(retry_delay_seconds(2, 30, 600), 60);
+        assert_eq!(retry_delay_seconds(5, 30, 600), 480);
+        assert_eq!(retry_delay_seconds(6, 30, 600), 600);
+        assert_eq!(retry_delay_seconds(100, 30, 600), 600);
+    }
+
+    #[test]
+    fn dest_key_fills_placeholders() {
+        let mut config = Config::from_env(false, &CliSettings::default()).unwrap();
+        let prefix = "users/abc/events/e/watermarks/";
+        config.dest_key_pattern = "{uuid}/{base}_preview{size}.{ext}".to_string();
+        assert_eq!(
+            dest_key(&config, prefix, "img", "jpg", Some(800)),
+            "users/abc/events/e/watermarks/abc/img_preview-800.jpg"
+        );
+        config.dest_key_pattern = "{base}_preview{size}.{ext}".to_string();
+        assert_eq!(
+            dest_key(&config, prefix, "img", "mp4", None),
+            "users/abc/events/e/watermarks/img_preview.mp4"
+        );
+    }
+    #[test]
+    #[cfg(feature = "video")]
+    fn drawtext_escape_survives_both_ffmpeg_unescape_passes() {
+        assert_eq!(drawtext_escape("reflexu.com"), "reflexu.com");
+        assert_eq!(drawtext_escape("It's 50%"), r"It\\\'s 50%");
+        assert_eq!(drawtext_escape("a:b,c"), r"a\\:b\,c");
+        assert_eq!(drawtext_escape(r"C:\fonts"), r"C\\:\\\\fonts");
+    }
+
+    #[test]
+    fn config_debug_redacts_credentials() {
+        let mut config = Config::from_env(false, &CliSettings::default()).unwrap();
+        config.access_key = Some("AKIDEXAMPLE".to_string());
+        config.secret_key = Some("super-secret-value".to_string());
+        config.session_token = Some("session-token-value".to_string());
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("AKIDEXAMPLE"));
+        assert!(!debug.contains("super-secret-value"));
+        assert!(!debug.contains("session-token-value"));
+    }
+
+    #[test]
+    fn cycle_limit_counts_attempted_files() {
+        let mut config = Config::from_env(false, &CliSettings::default()).unwrap();
+        let status = SharedStatus::default();
+        config.max_files_per_cycle = None;
+        status.write().unwrap().files_attempted = 1_000;
+        assert!(!cycle_limit_reached(&config, &status));
+
+        config.max_files_per_cycle = Some(2);
+        status.write().unwrap().start_cycle();
+        status.write().unwrap().files_attempted += 1;
+        assert!(!cycle_limit_reached(&config, &status));
+        status.write().unwrap().files_attempted += 1;
+        assert!(cycle_limit_reached(&config, &status));
+    }
+
+    #[test]
+    fn sort_for_processing_orders_by_last_modified() {
+        let object = |key: &str, secs: Option<i64>| {
+            Object::builder().key(key).set_last_modified(secs.map(aws_smithy_types::DateTime::from_secs)).build()
+        };
+        let keys = |objects: &[Object]| objects.iter().map(|o| o.key().unwrap().to_string()).collect::<Vec<_>>();
+        let listed = vec![object("a.jpg", Some(300)), object("b.jpg", Some(100)), object("c.jpg", Some(200))];
+
+        let mut objects = listed.clone();
+        sort_for_processing(&mut objects, ProcessOrder::Key);
+        assert_eq!(keys(&objects), ["a.jpg", "b.jpg", "c.jpg"]);
+
+        sort_for_processing(&mut objects, ProcessOrder::Oldest);
+        assert_eq!(keys(&objects), ["b.jpg", "c.jpg", "a.jpg"]);
+
+        let mut objects = listed;
+        sort_for_processing(&mut objects, ProcessOrder::Newest);
+        assert_eq!(keys(&objects), ["a.jpg", "c.jpg", "b.jpg"]);
+    }
+
+    #[test]
+    fn lock_payload_round_trips() {
+        let payload = lock_payload("pod-1-42-deadbeef", 1_700_000_000);
+        assert_eq!(parse_lock(payload.as_bytes()), Some(("pod-1-42-deadbeef".to_string(), 1_700_000_000)));
+        assert_eq!(parse_lock(b"{\"owner\":\"x\"}"), None);
+        assert_eq!(parse_lock(b"not json"), None);
+    }
+
+    #[test]
+    fn draw_band_blends_and_clips() {
+        let mut canvas = RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255]));
+        let band = TextBand { color: [0, 0, 0], opacity: 0.5 };
+        draw_band(&mut canvas, &band, (-5, 2), 15, 4);
+
+        let inside = canvas.get_pixel(3, 3);
+        assert!((120..=135).contains(&inside[0]), "band should half-darken white, got {:?}", inside);
+        assert!(inside[3] >= 254, "opaque canvas should stay opaque, got {:?}", inside);
+        assert_eq!(*canvas.get_pixel(3, 0), Rgba([255, 255, 255, 255]));
+        assert_eq!(*canvas.get_pixel(12, 3), Rgba([255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn filter_user_ids_applies_allow_then_deny() {
+        let ids = || parse_list("a, b,c,,d");
+        assert_eq!(ids(), ["a", "b", "c", "d"]);
+        assert_eq!(filter_user_ids(ids(), None, &[]), ["a", "b", "c", "d"]);
+        assert_eq!(filter_user_ids(ids(), Some(&parse_list("b,c,x")), &[]), ["b", "c"]);
+        assert_eq!(filter_user_ids(ids(), Some(&parse_list("b,c")), &parse_list("c")), ["b"]);
+        assert_eq!(filter_user_ids(ids(), None, &parse_list("a,d")), ["b", "c"]);
+    }
+
+    #[test]
+    fn uuid_flag_selects_the_user() {
+        let cli = parse_cli(&["once", "--uuid", "abc-123"]).unwrap();
+        assert_eq!(cli.command, Some(CliCommand::Once));
+        assert_eq!(cli.uuid.as_deref(), Some("abc-123"));
+    }
+
+    #[test]
+    fn download_quarter_tracks_progress() {
+        assert_eq!(download_quarter(0, 100), 0);
+        assert_eq!(download_quarter(24, 100), 0);
+        assert_eq!(download_quarter(25, 100), 1);
+        assert_eq!(download_quarter(99, 100), 3);
+        assert_eq!(download_quarter(150, 100), 4);
+        assert_eq!(download_quarter(10, 0), 0);
+    }
+
+    #[test]
+    fn preview_dimensions_keeps_small_images() {
+        assert_eq!(preview_dimensions(640, 480, 800), (640, 480));
+        assert_eq!(preview_dimensions(800, 800, 800), (800, 800));
+        assert_eq!(preview_dimensions(1600, 1200, 800), (800, 600));
+    }
+
+    #[test]
+    fn preview_dimensions_never_collapse_to_zero() {
+        assert_eq!(preview_dimensions(4000, 100, 800), (800, 20));
+        assert_eq!(preview_dimensions(100_000, 10, 800), (800, 1));
+        assert_eq!(preview_dimensions(3, 50_000, 800), (1, 800));
+
+        let panorama = DynamicImage::ImageRgba8(RgbaImage::new(4000, 3));
+        let resized = resize_for_preview(&panorama, 800);
+        assert_eq!(resized.dimensions(), (800, 1));
+    }
+
+    #[test]
+    fn disable_resize_lifts_the_preview_limit() {
+        let mut config = Config::from_env(false, &CliSettings::default()).unwrap();
+        config.disable_resize = false;
+        assert_eq!(config.resize_limit(800), 800);
+        config.disable_resize = true;
+        assert_eq!(config.resize_limit(800), FULL_RESOLUTION);
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(2000, 1000));
+        assert_eq!(resize_for_preview(&img, config.resize_limit(800)).dimensions(), (2000, 1000));
+    }
+
+    #[test]
+    fn raw_extensions_are_detected() {
+        assert_eq!(split_filename("IMG_0001.CR2").map(|(_, ext)| is_raw_extension(&ext)), Some(true));
+        assert!(is_raw_extension("nef"));
+        assert!(is_raw_extension("arw"));
+        assert!(!is_raw_extension("jpg"));
+        assert!(!is_raw_extension("tiff"));
+    }
+
+    #[test]
+    #[cfg(feature = "video")]
+    fn ffmpeg_retry_classification() {
+        assert!(ffmpeg_failure_is_retryable(Some(1), "av_interleaved_write_frame(): Input/output error"));
+        assert!(ffmpeg_failure_is_retryable(None, ""));
+        assert!(!ffmpeg_failure_is_retryable(Some(1), "clip.mov: Invalid data found when processing input"));
+        assert!(!ffmpeg_failure_is_retryable(None, "Unknown encoder 'h264_nvenc'"));
+        assert!(!ffmpeg_failure_is_retryable(Some(1), "some unrecognised failure"));
+    }
+
+    #[test]
+    fn sidecar_describes_the_preview() {
+        let (base, ext) = split_filename("photo.JPG").unwrap();
+        let prefix = "users/u/events/e/watermarks/";
+        let config = Config::from_env(false, &CliSettings::default()).unwrap();
+        assert_eq!(dest_key(&config, prefix, base, &ext, None), "users/u/events/e/watermarks/photo-watermark.jpg");
+        assert_eq!(sidecar_key(&config, prefix, base, None), "users/u/events/e/watermarks/photo-watermark.json");
+        assert_eq!(sidecar_key(&config, prefix, base, Some(800)), "users/u/events/e/watermarks/photo-watermark-800.json");
+        // CLEANUP_ORPHANS must not treat sidecars as orphans
+        let expected = expected_preview_keys(&config, "users/u/events/e/originals/photo.JPG", prefix, Some(&[800]));
+        assert!(expected.contains(&sidecar_key(&config, prefix, base, None)));
+        assert!(expected.contains(&sidecar_key(&config, prefix, base, Some(800))));
+
+        let branding = Branding::from_env();
+        let payload = sidecar_payload((4000, 3000), (800, 600), "jpg", 12345, &branding, 0);
+        let mut tokens = aws_smithy_json::deserialize::json_token_iter(payload.as_bytes()).peekable();
+        let Document::Object(fields) = aws_smithy_json::deserialize::token::expect_document(&mut tokens).unwrap() else {
+            panic!("sidecar is not an object: {}", payload);
+        };
+        assert_eq!(fields.get("original_width"), Some(&Document::Number(Number::PosInt(4000))));
+        assert_eq!(fields.get("height"), Some(&Document::Number(Number::PosInt(600))));
+        assert_eq!(fields.get("format"), Some(&Document::String("jpg".to_string())));
+        assert_eq!(fields.get("processed_at"), Some(&Document::String("1970-01-01T00:00:00Z".to_string())));
+        assert!(matches!(fields.get("watermark"), Some(Document::Object(watermark)) if watermark.contains_key("text")));
+    }
+
+    #[test]
+    #[cfg(feature = "video")]
+    fn drawtext_is_found_in_filter_list() {
+        let full = " ... datascope         V->V       Video data analysis.\n T.C drawtext          V->V       Draw text on top of video frames using libfreetype library.\n";
+        let minimal = " ... datascope         V->V       Video data analysis.\n TSC overlay           VV->V      Overlay a video source on top of the input.\n";
+        assert!(filters_include_drawtext(full));
+        assert!(!filters_include_drawtext(minimal));
+    }
+
+    #[tokio::test]
+    async fn health_server_bind_conflict_is_an_error() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        assert!(bind_health_server(&addr).await.is_err());
+    }
+
+    #[test]
+    fn version_json_reports_build_info() {
+        let json = version_json();
+        let mut tokens = aws_smithy_json::deserialize::json_token_iter(json.as_bytes()).peekable();
+        let Document::Object(fields) = aws_smithy_json::deserialize::token::expect_document(&mut tokens).unwrap() else {
+            panic!("version is not an object: {}", json);
+        };
+        assert_eq!(fields.get("version"), Some(&Document::String(env!("CARGO_PKG_VERSION").to_string())));
+        assert!(matches!(fields.get("git_commit"), Some(Document::String(commit)) if !commit.is_empty()));
+        assert!(matches!(fields.get("built_at"), Some(Document::String(_))));
+    }
+
+    #[test]
+    fn user_list_skips_comments_invalid_ids_and_duplicates() {
+        let contents = "# backfill 2025-06\n\n  a1b2  \nusers/evil\nc3d4\na1b2\n";
+        assert_eq!(parse_user_list(&Config::from_env(false, &CliSettings::default()).unwrap(), contents), vec!["a1b2".to_string(), "c3d4".to_string()]);
+    }
+
+    #[test]
+    fn media_text_overrides_fall_back_to_the_general_text() {
+        let mut branding = Branding::from_env();
+        branding.text = "www.reflexu.com".to_string();
+        assert_eq!(branding.for_images().text, "www.reflexu.com");
+        assert_eq!(branding.for_videos().text, "www.reflexu.com");
+
+        branding.video_text = Some("PREVIEW - VIDEO".to_string());
+        assert_eq!(branding.for_images().text, "www.reflexu.com");
+        let video = branding.for_videos();
+        assert_eq!(video.text, "PREVIEW - VIDEO");
+        // A poster watermarked via the image pipeline keeps the video text
+        assert_eq!(video.for_images().text, "PREVIEW - VIDEO");
+
+        branding.apply_overrides(br#"{"text": "studio.example"}"#).unwrap();
+        assert_eq!(branding.for_videos().text, "studio.example");
+    }
+
+    #[cfg(feature = "jpegtran")]
+    #[tokio::test]
+    async fn progressive_jpeg_stays_decodable() {
+        let img = synthetic_photo();
+        let baseline = encode_jpeg(&img, 25, JpegSubsampling::Ratio420).unwrap();
+        let progressive = make_progressive(baseline.clone()).await;
+        let decoded = image::load_from_memory(&progressive).unwrap();
+        assert_eq!(decoded.dimensions(), img.dimensions());
+        // SOF2 marks a progressive frame; without jpegtran the baseline is kept as-is
+        if progressive != baseline {
+            assert!(progressive.windows(2).any(|marker| marker == [0xFF, 0xC2]));
+        }
+    }
+
+    #[test]
+    fn tint_logo_recolors_visible_pixels_and_keeps_alpha() {
+        let mut logo = RgbaImage::from_pixel(2, 1, Rgba([255, 255, 255, 0]));
+        logo.put_pixel(1, 0, Rgba([255, 255, 255, 128]));
+        tint_logo(&mut logo, [0x20, 0x20, 0x20]);
+        assert_eq!(*logo.get_pixel(0, 0), Rgba([255, 255, 255, 0]));
+        assert_eq!(*logo.get_pixel(1, 0), Rgba([0x20, 0x20, 0x20, 128]));
+    }
+
+    #[test]
+    #[cfg(feature = "video")]
+    fn webp_preview_frame_count_is_bounded() {
+        let preview = |seconds, fps| WebpPreview { seconds, fps, width: 480, max_bytes: 1024 * 1024 };
+        assert_eq!(preview(3.0, 10).max_frames(), 30);
+        assert_eq!(preview(0.05, 10).max_frames(), 1);
+        assert_eq!(preview(60.0, 30).max_frames(), WEBP_MAX_FRAMES);
+        assert_eq!(webp_preview_key("u/e/watermarks/", "clip"), "u/e/watermarks/clip-preview.webp");
+    }
+
+    #[test]
+    fn strip_leading_emoji_keeps_indent_and_text() {
+        assert_eq!(strip_leading_emoji("✅ Uploaded: a.jpg"), "Uploaded: a.jpg");
+        assert_eq!(strip_leading_emoji("⚠️  Skipping large GIF"), "Skipping large GIF");
+        assert_eq!(strip_leading_emoji("   📅 Found 2 events"), "   Found 2 events");
+        assert_eq!(strip_leading_emoji("\n📊 PERFORMANCE SUMMARY"), "\nPERFORMANCE SUMMARY");
+        assert_eq!(strip_leading_emoji("Error serving connection"), "Error serving connection");
+        assert_eq!(strip_leading_emoji("==== ✅ done"), "==== ✅ done");
+    }
+
+    #[test]
+    fn preview_is_stale_compares_stored_etag() {
+        let stored = HashMap::from([("original-etag".to_string(), "abc123".to_string())]);
+        assert!(!preview_is_stale(Some(&stored), Some("\"abc123\"")));
+        assert!(preview_is_stale(Some(&stored), Some("\"def456\"")));
+        // Previews from before ETags were stored are redone once
+        assert!(preview_is_stale(Some(&HashMap::new()), Some("\"abc123\"")));
+        assert!(preview_is_stale(None, Some("abc123")));
+        assert!(!preview_is_stale(None, None));
+        assert_eq!(preview_metadata("k", Some("abc123")).unwrap().get("original-etag").map(String::as_str), Some("abc123"));
+    }
+
+    /// Sampling factors byte (horizontal << 4 | vertical) of each component in the SOF0 frame
+    fn jpeg_sampling_factors(jpeg: &[u8]) -> Vec<u8> {
+        let sof = jpeg.windows(2).position(|marker| marker == [0xFF, 0xC0]).expect("baseline SOF0 marker");
+        let components = jpeg[sof + 9] as usize;
+        (0..components).map(|component| jpeg[sof + 11 + component * 3]).collect()
+    }
+
+    #[test]
+    fn jpeg_subsampling_sets_the_chroma_resolution() {
+        // Odd dimensions leave partial 2x2 chroma blocks at the edges
+        let photo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(641, 479, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, 90])));
+        let full = encode_jpeg(&photo, PREVIEW_JPEG_QUALITY, JpegSubsampling::Ratio444).unwrap();
+        assert_eq!(jpeg_sampling_factors(&full), [0x11, 0x11, 0x11]);
+
+        let halved = encode_jpeg(&photo, PREVIEW_JPEG_QUALITY, JpegSubsampling::Ratio420).unwrap();
+        assert_eq!(jpeg_sampling_factors(&halved), [0x22, 0x11, 0x11]);
+        assert!(halved.len() < full.len());
+        let decoded = image::load_from_memory(&halved).unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), (641, 479));
+        let original = photo.to_rgb8();
+        let error: u64 = original.as_raw().iter().zip(decoded.as_raw()).map(|(a, b)| a.abs_diff(*b) as u64).sum();
+        assert!(error / (original.as_raw().len() as u64) < 12, "mean error {}", error / original.as_raw().len() as u64);
+
+        // Grayscale has no chroma to subsample
+        let gray = DynamicImage::ImageLuma8(photo.to_luma8());
+        assert_eq!(jpeg_sampling_factors(&encode_jpeg(&gray, PREVIEW_JPEG_QUALITY, JpegSubsampling::Ratio420).unwrap()), [0x11]);
+    }
+
+    #[tokio::test]
+    async fn download_original_retries_a_truncated_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            // The first response promises 10 bytes but the connection closes after 5
+            for body in ["hello", "helloworld"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let n = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 10\r\nETag: \"abc\"\r\nConnection: close\r\n\r\n{}", body);
+                tokio::io::AsyncWriteExt::write_all(&mut stream, response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(endpoint)
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .force_path_style(true)
+            .build();
+        let client = Client::from_conf(s3_config);
+        let mut config = Config::from_env(false, &CliSettings::default()).unwrap();
+        config.bucket = "bucket".to_string();
+
+        let (body, etag) = download_original(&config, &client, "u/e/originals/a.jpg", "a.jpg").await.unwrap();
+        assert_eq!(&body[..], b"helloworld");
+        assert_eq!(etag.as_deref(), Some("abc"));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_original_is_skipped_not_failed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        // No previews exist yet (HEAD 404) and the original downloads as 0 bytes
+        let server = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let n = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let response = if request.starts_with(b"HEAD") {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                } else {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nETag: \"abc\"\r\nConnection: close\r\n\r\n"
+                };
+                tokio::io::AsyncWriteExt::write_all(&mut stream, response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(endpoint)
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .force_path_style(true)
+            .build();
+        let client = Client::from_conf(s3_config);
+        let mut config = Config::from_env(false, &CliSettings::default()).unwrap();
+        config.bucket = "bucket".to_string();
+        let status = SharedStatus::default();
+
+        let key = "users/u/events/e/originals/a.jpg";
+        process_one_object(&config, &client, key, None, "users/u/events/e/watermarks/", &Branding::from_env(), &status).await.unwrap();
+        server.abort();
+
+        let status = status.read().unwrap();
+        assert_eq!(status.empty_skipped, [key]);
+        assert!(status.failures.is_empty(), "{:?}", status.failures);
+    }
+
+    #[test]
+    fn bottom_region_leaves_the_rest_untouched() {
+        let img = synthetic_photo();
+        let (width, height) = img.dimensions();
+        let watermarked = watermark_image_with_logo_file(img.clone(), &Branding::from_env(), BUNDLED_LOGO_PATH, WatermarkRegion::Bottom);
+        let (top, _) = WatermarkRegion::Bottom.bounds(height);
+        let original = img.to_rgba8();
+        let watermarked = watermarked.to_rgba8();
+        let changed_rows = |rows: std::ops::Range<u32>| rows.filter(|&y| (0..width).any(|x| original.get_pixel(x, y) != watermarked.get_pixel(x, y))).count();
+        assert_eq!(changed_rows(0..top as u32), 0);
+        assert!(changed_rows(top as u32..height) > 0);
+    }
+
+    #[test]
+    fn endpoint_scheme_and_port_are_checked() {
+        assert_eq!(endpoint_problem("https://minio.lan:9443"), None);
+        assert_eq!(endpoint_problem("http://10.0.0.5:9000/"), None);
+        assert_eq!(endpoint_problem("https://[::1]:9000"), None);
+        assert_eq!(endpoint_problem("https://fra1.digitaloceanspaces.com"), None);
+        assert!(endpoint_problem("minio.lan:9000").unwrap().contains("http:// or https://"));
+        assert!(endpoint_problem("ftp://minio.lan").unwrap().contains("http:// or https://"));
+        assert!(endpoint_problem("http://minio.lan:0").unwrap().contains("invalid port"));
+        assert!(endpoint_problem("http://minio.lan:").unwrap().contains("invalid port"));
+        assert!(endpoint_problem("http://minio.lan:99999").is_some());
+    }
+
+    #[tokio::test]
+    async fn insecure_http_client_reaches_a_custom_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                let n = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+            tokio::io::AsyncWriteExt::write_all(&mut stream, response.as_bytes()).await.unwrap();
+        });
+
+        let s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(endpoint)
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .force_path_style(true)
+            .http_client(InsecureS3Http::new().unwrap())
+            .build();
+        let client = Client::from_conf(s3_config);
+
+        let object = client.get_object().bucket("bucket").key("a.jpg").send().await.unwrap();
+        assert_eq!(&object.body.collect().await.unwrap().into_bytes()[..], b"ok");
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn process_since_window_includes_the_cutoff() {
+        let since = DateTime::from_str("2024-05-01T00:00:00Z", DateTimeFormat::DateTime).unwrap();
+        let at = |secs: i64| DateTime::from_secs(since.secs() + secs);
+        assert!(modified_since(Some(&at(0)), &since));
+        assert!(modified_since(Some(&at(3600)), &since));
+        assert!(!modified_since(Some(&at(-1)), &since));
+        assert!(modified_since(None, &since));
+    }
+
+    #[test]
+    fn text_tile_is_rotated_by_the_watermark_angle() {
+        let font = watermark_font();
+        let scale = Scale::uniform(24.0);
+        let outline = TextOutline { width: 0, color: [0, 0, 0] };
+        let color = Rgba([255, 255, 255, 200]);
+        // Row of the leftmost and rightmost drawn pixel
+        let ends = |tile: &RgbaImage| {
+            let drawn: Vec<(u32, u32)> = tile.enumerate_pixels().filter(|(_, _, p)| p[3] > 100).map(|(x, y, _)| (x, y)).collect();
+            let left = drawn.iter().min_by_key(|(x, _)| *x).unwrap().1 as i32;
+            let right = drawn.iter().max_by_key(|(x, _)| *x).unwrap().1 as i32;
+            (left, right)
+        };
+
+        let (left, right) = ends(&rotated_text_tile("REFLEXU REFLEXU", scale, font, color, &outline, -30.0));
+        assert!(right < left - 20, "text should rise to the right: {} -> {}", left, right);
+        let (left, right) = ends(&rotated_text_tile("REFLEXU REFLEXU", scale, font, color, &outline, 30.0));
+        assert!(right > left + 20, "text should fall to the right: {} -> {}", left, right);
+    }
+
+    #[test]
+    fn throttling_is_recognized_and_backed_off() {
+        assert_eq!(throttle_delay(Some("NoSuchKey"), Some(404), None, 1), None);
+        assert_eq!(throttle_delay(Some("InternalError"), Some(503), None, 1), None);
+        assert_eq!(throttle_delay(Some("SlowDown"), Some(503), Some("12"), 1), Some(Duration::from_secs(12)));
+        assert_eq!(throttle_delay(None, Some(429), Some("86400"), 1), Some(MAX_THROTTLE_DELAY));
+
+        let first = throttle_delay(Some("SlowDown"), Some(503), None, 1).unwrap();
+        assert!(first >= Duration::from_secs(5) && first < Duration::from_secs(6));
+        let third = throttle_delay(None, Some(429), Some("soon"), 3).unwrap();
+        assert!(third >= Duration::from_secs(20) && third < Duration::from_secs(21));
+    }
+
+    #[test]
+    fn precomputed_logo_matches_per_image_resize() {
+        let img = synthetic_photo();
+        let uncached = Branding::from_env();
+        let cached = Branding::from_env().with_logo_cache(BUNDLED_LOGO_PATH, &[640, 1600]);
+        let cache = cached.logo_cache.as_ref().unwrap();
+        assert!(cache.get(pattern_logo_width(640)).is_some());
+        assert!(cache.get(pattern_logo_width(1000)).is_none());
+
+        let region = WatermarkRegion::Full;
+        let expected = watermark_image_with_logo_file(img.clone(), &uncached, BUNDLED_LOGO_PATH, region);
+        let actual = watermark_image_with_logo_file(img, &cached, "missing-logo.png", region);
+        assert!(expected.to_rgba8() == actual.to_rgba8());
+    }
+
+    #[test]
+    fn element_gap_accepts_pixels_or_a_fraction() {
+        assert_eq!(ElementGap::parse("24"), Some(ElementGap::Pixels(24)));
+        assert_eq!(ElementGap::parse(" 24px "), Some(ElementGap::Pixels(24)));
+        assert_eq!(ElementGap::parse("0.05"), Some(ElementGap::Fraction(0.05)));
+        assert_eq!(ElementGap::parse("1.5"), None);
+        assert_eq!(ElementGap::parse("-3"), None);
+        assert_eq!(ElementGap::parse("wide"), None);
+        assert_eq!(ElementGap::Pixels(24).pixels(800), 24);
+        assert_eq!(ElementGap::Fraction(0.05).pixels(800), 40);
+    }
+
+    #[test]
+    fn tiled_logo_covers_every_part_of_the_image() {
+        let img = synthetic_photo();
+        let style = TileStyle { size: 0.08, spacing: 0.2, opacity: Some(0.5) };
+        let watermarked = watermark_image_tiled(img.clone(), &Branding::from_env(), BUNDLED_LOGO_PATH, &style);
+        assert_watermarked(&img, &watermarked);
+
+        // Each cell of a 3x3 grid over the image has some logo in it
+        let (width, height) = img.dimensions();
+        let (original, watermarked) = (img.to_rgba8(), watermarked.to_rgba8());
+        for cell_y in 0..3 {
+            for cell_x in 0..3 {
+                let changed = (cell_y * height / 3..(cell_y + 1) * height / 3)
+                    .flat_map(|y| (cell_x * width / 3..(cell_x + 1) * width / 3).map(move |x| (x, y)))
+                    .any(|(x, y)| original.get_pixel(x, y) != watermarked.get_pixel(x, y));
+                assert!(changed, "cell ({}, {}) has no logo", cell_x, cell_y);
+            }
+        }
+    }
+
+    /// A ZIP archive of `(name, data, deflate)` entries
+    fn zip_archive(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        use std::io::Write;
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+        for (name, data, deflate) in entries {
+            let stored = if *deflate {
+                let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                data.to_vec()
+            };
+            let fields = |out: &mut Vec<u8>| {
+                out.extend_from_slice(&(if *deflate { 8u16 } else { 0 }).to_le_bytes());
+                out.extend_from_slice(&[0; 4]);
+                out.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+                out.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+                out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                out.extend_from_slice(&0u16.to_le_bytes());
+            };
+            let local_offset = archive.len() as u32;
+            archive.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            archive.extend_from_slice(&[20, 0, 0, 0]);
+            fields(&mut archive);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&stored);
+
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            fields(&mut directory);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&local_offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&directory_offset.to_le_bytes());
+        archive.extend_from_slice(&[0; 2]);
+        archive
+    }
+
+    fn zip_limits(max_entries: usize, max_total_bytes: u64) -> ZipLimits {
+        ZipLimits { max_entries, max_total_bytes, max_entry_bytes: 50 * 1024 * 1024 }
+    }
+
+    #[test]
+    fn zip_entries_are_listed_and_extracted() {
+        let photo = encode_jpeg(&synthetic_photo(), 80, JpegSubsampling::Ratio444).unwrap();
+        let archive = zip_archive(&[
+            ("shoot/a.jpg", &photo, true),
+            ("b.JPG", &photo, false),
+            ("__MACOSX/shoot/._a.jpg", b"resource fork", false),
+            ("notes.txt", b"hello", true),
+        ]);
+
+        let entries = zip_entries(&archive, &zip_limits(10, 1 << 30)).unwrap();
+        let names: Vec<&str> = entries.iter().filter(|entry| zip_entry_is_image(&entry.name)).map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["shoot/a.jpg", "b.JPG"]);
+        for entry in &entries {
+            assert_eq!(zip_entry_data(&archive, entry).unwrap().len() as u64, entry.uncompressed_size);
+        }
+        assert_eq!(zip_entry_data(&archive, &entries[0]).unwrap(), photo);
+    }
+
+    #[test]
+    fn zip_bombs_are_rejected() {
+        let zeros = vec![0u8; 100_000];
+        let archive = zip_archive(&[("a.jpg", &zeros, true), ("b.jpg", &zeros, true)]);
+        assert!(zip_entries(&archive, &zip_limits(1, 1 << 30)).unwrap_err().to_string().contains("ZIP_MAX_ENTRIES"));
+        assert!(zip_entries(&archive, &zip_limits(10, 150_000)).unwrap_err().to_string().contains("ZIP_MAX_TOTAL_MB"));
+
+        // An entry inflating past its declared size is cut off, not trusted
+        let mut entry = zip_entries(&archive, &zip_limits(10, 1 << 30)).unwrap().remove(0);
+        entry.uncompressed_size = 1000;
+        assert!(zip_entry_data(&archive, &entry).unwrap_err().to_string().contains("declared size"));
+        assert!(zip_entries(b"not a zip", &zip_limits(10, 1 << 30)).is_err());
+    }
+
+    #[test]
+    fn zip_preview_keys_stay_inside_the_archive_folder() {
+        let config = Config::from_env(false, &CliSettings::default()).unwrap();
+        let prefix = "users/u/events/e/watermarks/";
+        assert_eq!(
+            zip_preview_key(&config, prefix, "batch", "day1/IMG_1.JPG", PreviewFormat::Jpeg).as_deref(),
+            Some("users/u/events/e/watermarks/batch/day1/IMG_1-watermark.jpg")
+        );
+        assert_eq!(
+            zip_preview_key(&config, prefix, "batch", "../../../x.jpg", PreviewFormat::Jpeg).as_deref(),
+            Some("users/u/events/e/watermarks/batch/x-watermark.jpg")
+        );
+        assert_eq!(zip_preview_key(&config, prefix, "batch", "../", PreviewFormat::Jpeg), None);
+    }
+
+    #[test]
+    fn archived_originals_keep_their_path_under_the_user() {
+        let config = Config::from_env(false, &CliSettings::default()).unwrap();
+        assert_eq!(archive_key(&config, "users/u1/events/e1/originals/a b.jpg"), "users/u1/archive/events/e1/originals/a b.jpg");
+        assert_eq!(archive_key(&config, "originals/a.jpg"), "archive/originals/a.jpg");
+        assert_eq!(copy_source("reflexu", "users/u1/events/e1/originals/a b+ü.jpg"), "reflexu/users/u1/events/e1/originals/a%20b%2B%C3%BC.jpg");
+    }
+
+    #[test]
+    fn fullsize_copy_has_its_own_jpeg_quality() {
+        let mut config = Config::from_env(false, &CliSettings::default()).unwrap();
+        config.fullsize_jpeg_quality = 85;
+        assert_eq!(config.jpeg_quality_for(800), PREVIEW_JPEG_QUALITY);
+        assert_eq!(config.jpeg_quality_for(FULL_RESOLUTION), 85);
+
+        let photo = synthetic_photo();
+        let preview = encode_jpeg(&photo, config.jpeg_quality_for(800), config.jpeg_subsampling).unwrap();
+        let fullsize = encode_jpeg(&photo, config.jpeg_quality_for(FULL_RESOLUTION), config.jpeg_subsampling).unwrap();
+        assert!(fullsize.len() > preview.len());
+    }
+
+    #[test]
+    fn watermark_follows_the_settings_on_the_branding() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(400, 300, image::Rgb([30, 30, 30])));
+        let mut branding = Branding::from_env();
+        branding.settings.style = WatermarkStyle::Corner;
+        branding.settings.corner = CornerStyle { corner: Corner::BottomRight, size: 0.12, opacity: Some(1.0), with_text: false };
+        let expected = watermark_image_corner(img.clone(), &branding, BUNDLED_LOGO_PATH, &branding.settings.corner);
+        assert_eq!(watermark_image(img.clone(), &branding).to_rgba8(), expected.to_rgba8());
+
+        branding.settings.protection = ProtectionMode::Pixelate { block: 5 };
+        let protected = apply_protection(img.clone(), branding.settings.protection);
+        assert_eq!(protected.to_rgba8(), pixelate(&img, 5).to_rgba8());
+        assert_eq!(apply_protection(img.clone(), ProtectionMode::Watermark).to_rgba8(), img.to_rgba8());
+    }
+
+    #[tokio::test]
+    async fn user_branding_falls_back_only_when_the_brand_file_is_missing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else { break };
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                    let n = tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let (status, body) = if request.contains("/users/studio/.reflexu-brand.json") {
+                    ("200 OK", r#"{"text": "studio.example", "logo_key": "users/other/events/e/originals/a.jpg"}"#.to_string())
+                } else if request.contains("/users/plain/") {
+                    ("404 Not Found", "<Error><Code>NoSuchKey</Code></Error>".to_string())
+                } else if request.contains("/users/locked/") {
+                    ("403 Forbidden", "<Error><Code>AccessDenied</Code></Error>".to_string())
+                } else {
+                    panic!("unexpected request: {}", request.lines().next().unwrap_or_default());
+                };
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                tokio::io::AsyncWriteExt::write_all(&mut stream, response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(endpoint)
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .force_path_style(true)
+            .build();
+        let client = Client::from_conf(s3_config);
+        let mut config = Config::from_env(false, &CliSettings::default()).unwrap();
+        config.bucket = "bucket".to_string();
+        let defaults = Branding::from_env();
+
+        // A logo_key outside the user's own directory is never fetched
+        let studio = load_user_branding(&config, &client, "studio", &defaults).await.unwrap();
+        assert_eq!(studio.text, "studio.example");
+        assert!(studio.logo.is_none());
+
+        let plain = load_user_branding(&config, &client, "plain", &defaults).await.unwrap();
+        assert_eq!(plain.text, defaults.text);
+
+        assert!(load_user_branding(&config, &client, "locked", &defaults).await.is_err());
+        server.abort();
+    }
+}